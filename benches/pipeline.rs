@@ -0,0 +1,125 @@
+//! Compares flash write throughput of page-by-page, sector-by-sector and
+//! pipelined writes.
+//!
+//! Needs a device in BOOTSEL mode, or an emulator exposing its PICOBOOT
+//! interface as a USB device (e.g. over USB/IP). The benchmark erases and
+//! overwrites flash, so it only runs with `PICOBOOT_BENCH` set:
+//!
+//! ```text
+//! PICOBOOT_BENCH=1 cargo bench --bench pipeline
+//! ```
+//!
+//! `PICOBOOT_BENCH_ADDR` sets the flash address to write at (default
+//! `0x10100000`), and `PICOBOOT_BENCH_SIZE` the number of bytes to write
+//! (default 256 KiB). Both must be multiples of the sector size.
+
+use picoboot_rs::{
+    AsyncPicobootConnection, PicobootConnection, PipelineOptions, PICO_PAGE_SIZE, PICO_SECTOR_SIZE,
+};
+
+use rusb::Context;
+use std::{
+    future::Future,
+    sync::Arc,
+    task::{Poll, Wake, Waker},
+    thread::Thread,
+    time::{Duration, Instant},
+};
+
+// runs a future to completion on the current thread
+fn block_on<F: Future>(future: F) -> F::Output {
+    struct ThreadWaker(Thread);
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = std::task::Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+fn env_u32(name: &str, default: u32) -> u32 {
+    match std::env::var(name) {
+        Ok(v) => match v.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => v.parse(),
+        }
+        .unwrap_or_else(|_| panic!("{} is not a number", name)),
+        Err(_) => default,
+    }
+}
+
+fn report(name: &str, size: u32, elapsed: Duration) {
+    let rate = size as f64 / 1024.0 / elapsed.as_secs_f64();
+    println!(
+        "{:<10} {:>8.1} ms {:>8.1} KiB/s",
+        name,
+        elapsed.as_secs_f64() * 1000.0,
+        rate
+    );
+}
+
+fn main() {
+    if std::env::var_os("PICOBOOT_BENCH").is_none() {
+        println!("skipping, set PICOBOOT_BENCH to run against a device or emulator");
+        return;
+    }
+
+    let addr = env_u32("PICOBOOT_BENCH_ADDR", 0x10100000);
+    let size = env_u32("PICOBOOT_BENCH_SIZE", 64 * PICO_SECTOR_SIZE);
+    assert!(
+        addr % PICO_SECTOR_SIZE == 0 && size % PICO_SECTOR_SIZE == 0,
+        "address and size must be multiples of the sector size"
+    );
+
+    let data: Vec<u8> = (0..size).map(|i| (i * 7 + i / 251) as u8).collect();
+    let pages: Vec<(u32, Vec<u8>)> = data
+        .chunks(PICO_PAGE_SIZE as usize)
+        .enumerate()
+        .map(|(i, page)| (addr + i as u32 * PICO_PAGE_SIZE, page.to_vec()))
+        .collect();
+
+    let ctx = Context::new().expect("failed to initialize libusb");
+    let mut conn = PicobootConnection::new(ctx, None).expect("failed to connect to device");
+    conn.reset_interface().expect("failed to reset interface");
+    conn.access_exclusive_eject()
+        .expect("failed to claim access");
+    conn.exit_xip().expect("failed to exit xip mode");
+
+    println!("writing {} KiB at {:#010x}", size / 1024, addr);
+
+    conn.flash_erase(addr, size).expect("failed to erase");
+    let start = Instant::now();
+    for (page_addr, page) in &pages {
+        conn.flash_write(*page_addr, page).expect("failed to write");
+    }
+    report("per page", size, start.elapsed());
+
+    conn.flash_erase(addr, size).expect("failed to erase");
+    let start = Instant::now();
+    for (i, sector) in data.chunks(PICO_SECTOR_SIZE as usize).enumerate() {
+        let sector_addr = addr + i as u32 * PICO_SECTOR_SIZE;
+        conn.flash_write(sector_addr, sector)
+            .expect("failed to write");
+    }
+    report("per sector", size, start.elapsed());
+
+    conn.flash_erase(addr, size).expect("failed to erase");
+    let mut conn = AsyncPicobootConnection::new(conn);
+    let start = Instant::now();
+    block_on(conn.flash_write_pipelined(pages, &PipelineOptions::default()))
+        .expect("failed to write");
+    report("pipelined", size, start.elapsed());
+
+    let mut conn = conn.into_blocking();
+    let read = conn.flash_read(addr, size).expect("failed to read");
+    assert!(read == data, "flash does not match the written data");
+}
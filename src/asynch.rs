@@ -0,0 +1,877 @@
+use crate::{
+    cmd::{PicobootCmd, PicobootError, PicobootStatusCmd, TargetID},
+    loader::FlashReport,
+    usb::{bulk_timeout, DeviceIdentity, PicobootConnection, TRANSFER_CHUNK_SIZE},
+    PICO_PAGE_SIZE, PICO_SECTOR_SIZE,
+};
+
+use rusb::{constants::*, ffi, UsbContext};
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    future::Future,
+    iter::Peekable,
+    os::raw::{c_int, c_uint, c_void},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+// see https://libusb.sourceforge.io/api-1.0/libusb_asyncio.html
+// for details on asynchronous transfers
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// How long the event thread waits for events before checking whether it
+/// should stop.
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Options for [`AsyncPicobootConnection::flash_write_pipelined`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineOptions {
+    /// Largest number of bytes sent with a single WRITE command. Contiguous
+    /// pages are merged up to this size. Rounded down to a multiple of
+    /// [`PICO_PAGE_SIZE`].
+    pub max_write_size: u32,
+    /// Size of each bulk transfer of a command's data. Rounded down to a
+    /// multiple of [`PICO_PAGE_SIZE`].
+    pub transfer_size: u32,
+    /// Number of bulk transfers queued at once.
+    pub queue_depth: usize,
+}
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        PipelineOptions {
+            max_write_size: 8 * PICO_SECTOR_SIZE,
+            transfer_size: PICO_SECTOR_SIZE,
+            queue_depth: 4,
+        }
+    }
+}
+
+/// An asynchronous connection to a PICOBOOT device
+///
+/// Offers the same commands as [`PicobootConnection`], driven by libusb
+/// asynchronous transfers instead of blocking the calling thread. Transfers
+/// complete on a background thread and wake the task awaiting them through its
+/// [`Waker`], so the futures work with any executor.
+///
+/// Dropping a future cancels its transfer. A command cancelled part way leaves
+/// the device in the middle of it; call [`Self::reset_interface`] before
+/// sending the next one.
+#[derive(Debug)]
+pub struct AsyncPicobootConnection<T: UsbContext + Debug> {
+    conn: PicobootConnection<T>,
+    _events: EventThread<T>,
+}
+impl<T: UsbContext + Debug + 'static> AsyncPicobootConnection<T> {
+    /// Wraps a connection, and starts handling its USB events on a background
+    /// thread.
+    ///
+    /// - `conn` - Connection to the device, see [`PicobootConnection::new`].
+    pub fn new(conn: PicobootConnection<T>) -> Self {
+        let events = EventThread::start(conn.get_context().clone());
+        AsyncPicobootConnection {
+            conn,
+            _events: events,
+        }
+    }
+
+    /// Stops the background thread and returns the blocking connection.
+    pub fn into_blocking(self) -> PicobootConnection<T> {
+        self.conn
+    }
+
+    /// Returns PICOBOOT device type.
+    pub fn get_device_type(&self) -> TargetID {
+        self.conn.get_device_type()
+    }
+
+    /// Returns the identity of the connected device.
+    pub fn get_identity(&self) -> &DeviceIdentity {
+        self.conn.get_identity()
+    }
+
+    async fn bulk_read(&mut self, buf_size: usize, check: bool) -> Result<Vec<u8>> {
+        let handle = self.conn.get_usb_device().0.get_handle();
+        let (_, in_addr, _) = self.conn.get_endpoints();
+        let timeout = bulk_timeout(Duration::from_secs(3), buf_size);
+        let buf = Transfer::bulk(handle.as_raw(), in_addr, vec![0; buf_size], timeout)
+            .map_err(|e| Error::UsbReadBulkFailure(e.into()))?
+            .await
+            .map_err(|e| Error::UsbReadBulkFailure(e.into()))?;
+
+        if check && buf.len() != buf_size {
+            return Err(Error::UsbReadBulkMismatch);
+        }
+
+        Ok(buf)
+    }
+
+    async fn bulk_write(&mut self, buf: &[u8], check: bool) -> Result<()> {
+        let handle = self.conn.get_usb_device().0.get_handle();
+        let (_, _, out_addr) = self.conn.get_endpoints();
+        let timeout = bulk_timeout(Duration::from_secs(5), buf.len());
+        let written = Transfer::bulk(handle.as_raw(), out_addr, buf.to_vec(), timeout)
+            .map_err(|e| Error::UsbWriteBulkFailure(e.into()))?
+            .await
+            .map_err(|e| Error::UsbWriteBulkFailure(e.into()))?;
+
+        if check && written.len() != buf.len() {
+            return Err(Error::UsbWriteBulkMismatch);
+        }
+
+        Ok(())
+    }
+
+    /// Sends a command to the device
+    ///
+    /// See [`PicobootConnection::cmd`].
+    ///
+    /// # Errors
+    /// - [`Error::CmdSerializeFailure`]
+    /// - [`Error::UsbWriteBulkFailure`]
+    /// - [`Error::UsbWriteBulkMismatch`]
+    /// - [`Error::UsbReadBulkFailure`]
+    /// - [`Error::UsbReadBulkMismatch`]
+    pub async fn cmd(&mut self, cmd: PicobootCmd, buf: &[u8]) -> Result<Vec<u8>> {
+        let cmd = cmd.set_token(self.conn.next_cmd_token());
+
+        // write command
+        let cmdu8 = bincode::serialize(&cmd).map_err(Error::CmdSerializeFailure)?;
+        self.bulk_write(cmdu8.as_slice(), true).await?;
+        let _stat = self.get_command_status().await;
+
+        // if we're reading or writing a buffer
+        let l = cmd.get_transfer_len() as usize;
+        let mut res = vec![];
+        if l != 0 {
+            if ((cmd.get_cmd_id() as u8) & 0x80) != 0 {
+                res = self.bulk_read(l, true).await?;
+            } else {
+                self.bulk_write(buf, true).await?
+            }
+            let _stat = self.get_command_status().await;
+        }
+
+        // do ack
+        if ((cmd.get_cmd_id() as u8) & 0x80) != 0 {
+            self.bulk_write(&[0u8; 1], false).await?;
+        } else {
+            self.bulk_read(1, false).await?;
+        }
+
+        Ok(res)
+    }
+
+    /// Requests non-exclusive access with the device, and does not close the
+    /// USB Mass Storage interface.
+    ///
+    /// # Errors:
+    /// - Any produced by [`Self::cmd`]
+    pub async fn access_not_exclusive(&mut self) -> Result<()> {
+        self.set_exclusive_access(0).await
+    }
+
+    /// Requests exclusive access with the device, and disables the USB Mass
+    /// Storage interface. Any data writes through that interface will fail.
+    ///
+    /// # Errors:
+    /// - Any produced by [`Self::cmd`]
+    pub async fn access_exclusive(&mut self) -> Result<()> {
+        self.set_exclusive_access(1).await
+    }
+
+    /// Requests exclusive access with the device, and disables and ejects the
+    /// USB Mass Storage interface. Any data writes through that interface will
+    /// fail.
+    ///
+    /// # Errors:
+    /// - Any produced by [`Self::cmd`]
+    pub async fn access_exclusive_eject(&mut self) -> Result<()> {
+        self.set_exclusive_access(2).await
+    }
+
+    async fn set_exclusive_access(&mut self, exclusive: u8) -> Result<()> {
+        self.cmd(PicobootCmd::exclusive_access(exclusive), &[0u8; 0])
+            .await
+            .map(|_| ())
+    }
+
+    /// Reboots the device with a specified program counter, stack pointer, and
+    /// delay in milliseconds.
+    ///
+    /// See [`PicobootConnection::reboot`].
+    ///
+    /// # Errors:
+    /// - Any produced by [`Self::cmd`]
+    pub async fn reboot(&mut self, pc: u32, sp: u32, delay: u32) -> Result<()> {
+        self.cmd(PicobootCmd::reboot(pc, sp, delay), &[0u8; 0])
+            .await
+            .map(|_| ())
+    }
+
+    /// Reboots the device with a delay in milliseconds. (Only for RP2350)
+    ///
+    /// # Errors:
+    /// - [`Error::CmdNotAllowedForTarget`]
+    /// - Any produced by [`Self::cmd`]
+    pub async fn reboot2_normal(&mut self, delay: u32) -> Result<()> {
+        if let TargetID::Rp2040 = self.get_device_type() {
+            return Err(Error::CmdNotAllowedForTarget);
+        }
+
+        self.cmd(PicobootCmd::reboot2_normal(delay), &[0u8; 0])
+            .await
+            .map(|_| ())
+    }
+
+    /// Reboots the device into BOOTSEL mode with a delay in milliseconds. (Only
+    /// for RP2350)
+    ///
+    /// # Errors:
+    /// - [`Error::CmdNotAllowedForTarget`]
+    /// - Any produced by [`Self::cmd`]
+    pub async fn reboot2_bootsel(&mut self, delay: u32) -> Result<()> {
+        if let TargetID::Rp2040 = self.get_device_type() {
+            return Err(Error::CmdNotAllowedForTarget);
+        }
+
+        self.cmd(PicobootCmd::reboot2_bootsel(delay), &[0u8; 0])
+            .await
+            .map(|_| ())
+    }
+
+    /// Reboots the device in flash update mode with a delay in milliseconds.
+    /// (Only for RP2350)
+    ///
+    /// See [`PicobootConnection::reboot2_flash_update`].
+    ///
+    /// # Errors:
+    /// - [`Error::CmdNotAllowedForTarget`]
+    /// - Any produced by [`Self::cmd`]
+    pub async fn reboot2_flash_update(&mut self, addr: u32, delay: u32) -> Result<()> {
+        if let TargetID::Rp2040 = self.get_device_type() {
+            return Err(Error::CmdNotAllowedForTarget);
+        }
+
+        self.cmd(PicobootCmd::reboot2_flash_update(addr, delay), &[0u8; 0])
+            .await
+            .map(|_| ())
+    }
+
+    /// Erases the flash memory of the device.
+    ///
+    /// - `addr` - Address to start the erase. Must be on a multiple of [`PICO_SECTOR_SIZE`].
+    /// - `size` - Number of bytes to erase. Must be a multiple of [`PICO_SECTOR_SIZE`].
+    ///
+    /// # Errors:
+    /// - [`Error::EraseInvalidAddr`]
+    /// - [`Error::EraseInvalidSize`]
+    /// - Any produced by [`Self::cmd`]
+    pub async fn flash_erase(&mut self, addr: u32, size: u32) -> Result<()> {
+        if addr % PICO_SECTOR_SIZE != 0 {
+            return Err(Error::EraseInvalidAddr);
+        }
+        if size % PICO_SECTOR_SIZE != 0 {
+            return Err(Error::EraseInvalidSize);
+        }
+
+        self.cmd(PicobootCmd::flash_erase(addr, size), &[0u8; 0])
+            .await
+            .map(|_| ())
+    }
+
+    /// Writes a buffer to the flash memory of the device.
+    ///
+    /// Buffers larger than [`TRANSFER_CHUNK_SIZE`] are written with several
+    /// commands.
+    ///
+    /// - `addr` - Address to start the write. Must be on a multiple of [`PICO_PAGE_SIZE`].
+    /// - `buf` - Buffer of data to write to flash. Should be a multiple of [`PICO_PAGE_SIZE`]. If not, the remainder of the final page is zero-filled.
+    ///
+    /// # Errors:
+    /// - [`Error::WriteInvalidAddr`]
+    /// - [`Error::MemoryOutOfRange`]
+    /// - Any produced by [`Self::cmd`]
+    pub async fn flash_write(&mut self, addr: u32, buf: &[u8]) -> Result<()> {
+        if addr % PICO_PAGE_SIZE != 0 {
+            return Err(Error::WriteInvalidAddr);
+        }
+        u32::try_from(buf.len())
+            .ok()
+            .and_then(|len| addr.checked_add(len))
+            .ok_or(Error::MemoryOutOfRange)?;
+
+        for (i, chunk) in buf.chunks(TRANSFER_CHUNK_SIZE as usize).enumerate() {
+            let chunk_addr = addr
+                .checked_add(i as u32 * TRANSFER_CHUNK_SIZE)
+                .ok_or(Error::MemoryOutOfRange)?;
+            self.cmd(
+                PicobootCmd::flash_write(chunk_addr, chunk.len() as u32),
+                chunk,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads from the flash memory of the device.
+    ///
+    /// Reads larger than [`TRANSFER_CHUNK_SIZE`] are made with several
+    /// commands.
+    ///
+    /// - `addr` - Address to start the read.
+    /// - `size` - Number of bytes to read.
+    ///
+    /// # Errors:
+    /// - [`Error::MemoryOutOfRange`]
+    /// - Any produced by [`Self::cmd`]
+    pub async fn flash_read(&mut self, addr: u32, size: u32) -> Result<Vec<u8>> {
+        addr.checked_add(size).ok_or(Error::MemoryOutOfRange)?;
+
+        let mut buf = Vec::with_capacity(size as usize);
+        let mut offset = 0;
+        while offset < size {
+            let len = std::cmp::min(size - offset, TRANSFER_CHUNK_SIZE);
+            let chunk_addr = addr.checked_add(offset).ok_or(Error::MemoryOutOfRange)?;
+            let data = self
+                .cmd(PicobootCmd::flash_read(chunk_addr, len), &[0u8; 0])
+                .await?;
+            buf.extend(data);
+            offset += len;
+        }
+
+        Ok(buf)
+    }
+
+    /// Enter Flash XIP (execute-in-place) mode.
+    ///
+    /// # Errors:
+    /// - Any produced by [`Self::cmd`]
+    pub async fn enter_xip(&mut self) -> Result<()> {
+        self.cmd(PicobootCmd::enter_xip(), &[0u8; 0])
+            .await
+            .map(|_| ())
+    }
+
+    /// Exits Flash XIP (execute-in-place) mode.
+    ///
+    /// # Errors:
+    /// - Any produced by [`Self::cmd`]
+    pub async fn exit_xip(&mut self) -> Result<()> {
+        self.cmd(PicobootCmd::exit_xip(), &[0u8; 0])
+            .await
+            .map(|_| ())
+    }
+
+    /// Resets PICOBOOT USB interface.
+    ///
+    /// Clearing the endpoint halts is not available asynchronously in libusb,
+    /// and briefly blocks the calling thread.
+    ///
+    /// # Errors:
+    /// - [`Error::UsbClearInAddrHalt`]
+    /// - [`Error::UsbClearOutAddrHalt`]
+    /// - [`Error::UsbResetInterfaceFailure`]
+    pub async fn reset_interface(&mut self) -> Result<()> {
+        let handle = self.conn.get_usb_device().0.get_handle();
+        let (iface, in_addr, out_addr) = self.conn.get_endpoints();
+        handle
+            .clear_halt(in_addr)
+            .map_err(|e| Error::UsbClearInAddrHalt(e.into()))?;
+        handle
+            .clear_halt(out_addr)
+            .map_err(|e| Error::UsbClearOutAddrHalt(e.into()))?;
+
+        let timeout = Duration::from_secs(1);
+        let setup = (0b01000001, 0b01000001, 0, iface.into());
+        Transfer::control(handle.as_raw(), setup, &[0u8; 0], 0, timeout)
+            .map_err(|e| Error::UsbResetInterfaceFailure(e.into()))?
+            .await
+            .map_err(|e| Error::UsbResetInterfaceFailure(e.into()))?;
+
+        Ok(())
+    }
+
+    /// Writes pages to the flash memory of the device for throughput.
+    ///
+    /// Contiguous pages are merged into WRITE commands of up to
+    /// [`PipelineOptions::max_write_size`] bytes. The data of each command is
+    /// sent as several bulk transfers queued together, so the bus is not left
+    /// idle between them, and the next command is assembled while the device
+    /// is still writing the current one.
+    ///
+    /// The flash must already be erased, see [`Self::flash_erase`].
+    ///
+    /// - `pages` - Address and data of each write, in any size. Addresses must
+    ///   be on a multiple of [`PICO_PAGE_SIZE`].
+    /// - `options` - See [`PipelineOptions`].
+    ///
+    /// # Errors:
+    /// - [`Error::WriteInvalidAddr`]
+    /// - [`Error::CmdStatusFailure`]
+    /// - [`Error::UsbGetCommandStatusFailure`]
+    /// - Any produced by [`Self::cmd`]
+    pub async fn flash_write_pipelined<I>(
+        &mut self,
+        pages: I,
+        options: &PipelineOptions,
+    ) -> Result<FlashReport>
+    where
+        I: IntoIterator<Item = (u32, Vec<u8>)>,
+    {
+        let max_write_size = page_multiple(options.max_write_size);
+        let mut pages = pages
+            .into_iter()
+            .filter(|(_, data)| !data.is_empty())
+            .peekable();
+
+        let mut report = FlashReport::default();
+        let mut run = next_run(&mut pages, max_write_size)?;
+        while let Some((addr, data)) = run {
+            let cmd = PicobootCmd::flash_write(addr, data.len() as u32)
+                .set_token(self.conn.next_cmd_token());
+            let cmdu8 = bincode::serialize(&cmd).map_err(Error::CmdSerializeFailure)?;
+            self.bulk_write(cmdu8.as_slice(), true).await?;
+            self.get_command_status().await?.check()?;
+
+            self.bulk_write_queued(&data, options).await?;
+            self.get_command_status().await?.check()?;
+
+            // the device acks once it has written the data, so prepare the
+            // next run in the meantime
+            let handle = self.conn.get_usb_device().0.get_handle();
+            let (_, in_addr, _) = self.conn.get_endpoints();
+            let timeout = bulk_timeout(Duration::from_secs(3), data.len());
+            let ack = Transfer::bulk(handle.as_raw(), in_addr, vec![0; 1], timeout)
+                .map_err(|e| Error::UsbReadBulkFailure(e.into()))?;
+            report.pages_written += (data.len() as u32 + PICO_PAGE_SIZE - 1) / PICO_PAGE_SIZE;
+            let next = next_run(&mut pages, max_write_size);
+
+            // finish the command before giving up on an invalid next run
+            ack.await.map_err(|e| Error::UsbReadBulkFailure(e.into()))?;
+            run = next?;
+        }
+
+        Ok(report)
+    }
+
+    // sends a buffer as several bulk transfers, keeping up to the queue depth
+    // of them in flight
+    async fn bulk_write_queued(&mut self, buf: &[u8], options: &PipelineOptions) -> Result<()> {
+        let handle = self.conn.get_usb_device().0.get_handle();
+        let (_, _, out_addr) = self.conn.get_endpoints();
+        let handle = handle.as_raw();
+        let transfer_size = page_multiple(options.transfer_size) as usize;
+        let timeout = bulk_timeout(Duration::from_secs(5), transfer_size);
+
+        let mut chunks = buf.chunks(transfer_size);
+        let mut queue = VecDeque::new();
+        loop {
+            while queue.len() < options.queue_depth.max(1) {
+                let chunk = match chunks.next() {
+                    Some(c) => c,
+                    None => break,
+                };
+                let transfer = Transfer::bulk(handle, out_addr, chunk.to_vec(), timeout)
+                    .map_err(|e| Error::UsbWriteBulkFailure(e.into()))?;
+                queue.push_back((chunk.len(), transfer));
+            }
+
+            let (len, transfer) = match queue.pop_front() {
+                Some(t) => t,
+                None => break,
+            };
+            let written = transfer
+                .await
+                .map_err(|e| Error::UsbWriteBulkFailure(e.into()))?;
+            if written.len() != len {
+                return Err(Error::UsbWriteBulkMismatch);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn get_command_status(&mut self) -> Result<PicobootStatusCmd> {
+        let handle = self.conn.get_usb_device().0.get_handle();
+        let (iface, _, _) = self.conn.get_endpoints();
+        let timeout = Duration::from_secs(1);
+        let setup = (0b11000001, 0b01000010, 0, iface.into());
+        let mut buf = Transfer::control(handle.as_raw(), setup, &[0u8; 0], 16, timeout)
+            .map_err(|e| Error::UsbGetCommandStatusFailure(e.into()))?
+            .await
+            .map_err(|e| Error::UsbGetCommandStatusFailure(e.into()))?;
+        buf.resize(16, 0);
+        let buf: PicobootStatusCmd =
+            bincode::deserialize(&buf).map_err(Error::CmdDeserializeFailure)?;
+
+        Ok(buf)
+    }
+}
+
+// rounds a size down to a multiple of the page size, and at least one page
+fn page_multiple(size: u32) -> u32 {
+    std::cmp::max(size - size % PICO_PAGE_SIZE, PICO_PAGE_SIZE)
+}
+
+// takes the next write from `pages`, merged with the contiguous writes that
+// follow it while it ends on a page boundary and fits in `max_size`
+fn next_run<I>(pages: &mut Peekable<I>, max_size: u32) -> Result<Option<(u32, Vec<u8>)>>
+where
+    I: Iterator<Item = (u32, Vec<u8>)>,
+{
+    let (addr, mut data) = match pages.next() {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    if addr % PICO_PAGE_SIZE != 0 {
+        return Err(Error::WriteInvalidAddr);
+    }
+
+    while data.len() % PICO_PAGE_SIZE as usize == 0 {
+        match pages.peek() {
+            Some((next, next_data))
+                if *next as usize == addr as usize + data.len()
+                    && data.len() + next_data.len() <= max_size as usize =>
+            {
+                if let Some((_, next_data)) = pages.next() {
+                    data.extend(next_data);
+                }
+            }
+            _ => break,
+        }
+    }
+
+    Ok(Some((addr, data)))
+}
+
+// handles the events of a libusb context on a background thread, so that
+// asynchronous transfers complete without anyone blocking on them
+#[derive(Debug)]
+struct EventThread<T: UsbContext> {
+    ctx: T,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+impl<T: UsbContext + Debug + 'static> EventThread<T> {
+    fn start(ctx: T) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = {
+            let ctx = ctx.clone();
+            let running = running.clone();
+            std::thread::spawn(move || {
+                while running.load(Ordering::Acquire) {
+                    let _ = ctx.handle_events(Some(EVENT_POLL_INTERVAL));
+                }
+            })
+        };
+
+        EventThread {
+            ctx,
+            running,
+            thread: Some(thread),
+        }
+    }
+}
+impl<T: UsbContext> Drop for EventThread<T> {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        self.ctx.interrupt_handle_events();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// completion state shared between a transfer and its libusb callback
+#[derive(Default)]
+struct TransferState {
+    inner: Mutex<TransferStateInner>,
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct TransferStateInner {
+    complete: bool,
+    status: c_int,
+    actual_length: usize,
+    waker: Option<Waker>,
+}
+
+// a submitted libusb transfer, resolving to the transferred data
+struct Transfer {
+    transfer: *mut ffi::libusb_transfer,
+    buf: Vec<u8>,
+    // bytes at the start of the buffer that are not data (the control setup)
+    header_len: usize,
+    state: Arc<TransferState>,
+}
+
+// libusb transfers may be submitted, cancelled and freed from any thread
+unsafe impl Send for Transfer {}
+
+impl Transfer {
+    fn bulk(
+        handle: *mut ffi::libusb_device_handle,
+        endpoint: u8,
+        buf: Vec<u8>,
+        timeout: Duration,
+    ) -> rusb::Result<Self> {
+        let mut transfer = Self::alloc(buf, 0)?;
+        unsafe {
+            ffi::libusb_fill_bulk_transfer(
+                transfer.transfer,
+                handle,
+                endpoint,
+                transfer.buf.as_mut_ptr(),
+                transfer.buf.len() as c_int,
+                transfer_callback,
+                Arc::as_ptr(&transfer.state) as *mut c_void,
+                timeout.as_millis() as c_uint,
+            );
+        }
+        transfer.submit()?;
+        Ok(transfer)
+    }
+
+    // setup is (request_type, request, value, index); data is sent for
+    // host-to-device requests, and `read_len` bytes are read otherwise
+    fn control(
+        handle: *mut ffi::libusb_device_handle,
+        setup: (u8, u8, u16, u16),
+        data: &[u8],
+        read_len: u16,
+        timeout: Duration,
+    ) -> rusb::Result<Self> {
+        let (request_type, request, value, index) = setup;
+        let len = if request_type & LIBUSB_ENDPOINT_IN != 0 {
+            read_len
+        } else {
+            data.len() as u16
+        };
+
+        let mut buf = vec![0u8; LIBUSB_CONTROL_SETUP_SIZE + len as usize];
+        if request_type & LIBUSB_ENDPOINT_IN == 0 {
+            buf[LIBUSB_CONTROL_SETUP_SIZE..].copy_from_slice(data);
+        }
+
+        let mut transfer = Self::alloc(buf, LIBUSB_CONTROL_SETUP_SIZE)?;
+        unsafe {
+            ffi::libusb_fill_control_setup(
+                transfer.buf.as_mut_ptr(),
+                request_type,
+                request,
+                value,
+                index,
+                len,
+            );
+            ffi::libusb_fill_control_transfer(
+                transfer.transfer,
+                handle,
+                transfer.buf.as_mut_ptr(),
+                transfer_callback,
+                Arc::as_ptr(&transfer.state) as *mut c_void,
+                timeout.as_millis() as c_uint,
+            );
+        }
+        transfer.submit()?;
+        Ok(transfer)
+    }
+
+    fn alloc(buf: Vec<u8>, header_len: usize) -> rusb::Result<Self> {
+        let transfer = unsafe { ffi::libusb_alloc_transfer(0) };
+        if transfer.is_null() {
+            return Err(rusb::Error::NoMem);
+        }
+
+        Ok(Transfer {
+            transfer,
+            buf,
+            header_len,
+            state: Arc::new(TransferState::default()),
+        })
+    }
+
+    fn submit(&mut self) -> rusb::Result<()> {
+        match unsafe { ffi::libusb_submit_transfer(self.transfer) } {
+            0 => Ok(()),
+            err => {
+                // never submitted, so the callback will not run
+                self.state.inner.lock().unwrap().complete = true;
+                Err(error_from_libusb(err))
+            }
+        }
+    }
+}
+impl Future for Transfer {
+    type Output = rusb::Result<Vec<u8>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let (status, actual_length) = {
+            let mut inner = self.state.inner.lock().unwrap();
+            if !inner.complete {
+                inner.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            (inner.status, inner.actual_length)
+        };
+
+        let result = match status {
+            LIBUSB_TRANSFER_COMPLETED => {
+                let start = self.header_len;
+                let mut buf = std::mem::take(&mut self.buf);
+                buf.truncate(start + actual_length);
+                Ok(buf.split_off(start))
+            }
+            LIBUSB_TRANSFER_TIMED_OUT => Err(rusb::Error::Timeout),
+            LIBUSB_TRANSFER_STALL => Err(rusb::Error::Pipe),
+            LIBUSB_TRANSFER_NO_DEVICE => Err(rusb::Error::NoDevice),
+            LIBUSB_TRANSFER_OVERFLOW => Err(rusb::Error::Overflow),
+            LIBUSB_TRANSFER_CANCELLED => Err(rusb::Error::Interrupted),
+            _ => Err(rusb::Error::Io),
+        };
+        Poll::Ready(result)
+    }
+}
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        // the buffer belongs to libusb until the callback has run, so a
+        // transfer still in flight is cancelled and waited for
+        let mut inner = self.state.inner.lock().unwrap();
+        if !inner.complete {
+            unsafe { ffi::libusb_cancel_transfer(self.transfer) };
+            while !inner.complete {
+                inner = self.state.done.wait(inner).unwrap();
+            }
+        }
+        drop(inner);
+
+        unsafe { ffi::libusb_free_transfer(self.transfer) };
+    }
+}
+
+extern "system" fn transfer_callback(transfer: *mut ffi::libusb_transfer) {
+    let waker = unsafe {
+        let state = &*((*transfer).user_data as *const TransferState);
+        // the state may be freed as soon as the lock is released
+        let mut inner = state.inner.lock().unwrap();
+        inner.complete = true;
+        inner.status = (*transfer).status;
+        inner.actual_length = (*transfer).actual_length.max(0) as usize;
+        state.done.notify_all();
+        inner.waker.take()
+    };
+
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+fn error_from_libusb(err: c_int) -> rusb::Error {
+    match err {
+        LIBUSB_ERROR_IO => rusb::Error::Io,
+        LIBUSB_ERROR_INVALID_PARAM => rusb::Error::InvalidParam,
+        LIBUSB_ERROR_ACCESS => rusb::Error::Access,
+        LIBUSB_ERROR_NO_DEVICE => rusb::Error::NoDevice,
+        LIBUSB_ERROR_NOT_FOUND => rusb::Error::NotFound,
+        LIBUSB_ERROR_BUSY => rusb::Error::Busy,
+        LIBUSB_ERROR_TIMEOUT => rusb::Error::Timeout,
+        LIBUSB_ERROR_OVERFLOW => rusb::Error::Overflow,
+        LIBUSB_ERROR_PIPE => rusb::Error::Pipe,
+        LIBUSB_ERROR_INTERRUPTED => rusb::Error::Interrupted,
+        LIBUSB_ERROR_NO_MEM => rusb::Error::NoMem,
+        LIBUSB_ERROR_NOT_SUPPORTED => rusb::Error::NotSupported,
+        _ => rusb::Error::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(addr: u32, len: usize) -> (u32, Vec<u8>) {
+        (addr, vec![addr as u8; len])
+    }
+
+    fn runs(pages: Vec<(u32, Vec<u8>)>, max_size: u32) -> Result<Vec<(u32, usize)>> {
+        let mut pages = pages.into_iter().peekable();
+        let mut runs = Vec::new();
+        while let Some((addr, data)) = next_run(&mut pages, max_size)? {
+            runs.push((addr, data.len()));
+        }
+        Ok(runs)
+    }
+
+    #[test]
+    fn page_multiple_rounds_down() {
+        assert_eq!(page_multiple(0), PICO_PAGE_SIZE);
+        assert_eq!(page_multiple(1), PICO_PAGE_SIZE);
+        assert_eq!(page_multiple(PICO_PAGE_SIZE + 1), PICO_PAGE_SIZE);
+        assert_eq!(
+            page_multiple(PICO_SECTOR_SIZE - 1),
+            PICO_SECTOR_SIZE - PICO_PAGE_SIZE
+        );
+        assert_eq!(page_multiple(PICO_SECTOR_SIZE), PICO_SECTOR_SIZE);
+    }
+
+    #[test]
+    fn next_run_merges_contiguous() {
+        let pages = (0..4)
+            .map(|i| page(0x1000_0000 + i * 0x100, 0x100))
+            .collect();
+        assert_eq!(runs(pages, 0x1000).unwrap(), [(0x1000_0000, 0x400)]);
+
+        // a gap starts a new run
+        let pages = vec![page(0x1000_0000, 0x100), page(0x1000_0200, 0x100)];
+        assert_eq!(
+            runs(pages, 0x1000).unwrap(),
+            [(0x1000_0000, 0x100), (0x1000_0200, 0x100)]
+        );
+    }
+
+    #[test]
+    fn next_run_splits_at_max_size() {
+        let pages = (0..5)
+            .map(|i| page(0x1000_0000 + i * 0x100, 0x100))
+            .collect();
+        assert_eq!(
+            runs(pages, 0x200).unwrap(),
+            [
+                (0x1000_0000, 0x200),
+                (0x1000_0200, 0x200),
+                (0x1000_0400, 0x100)
+            ]
+        );
+    }
+
+    #[test]
+    fn next_run_keeps_partial_page() {
+        // a write that does not end on a page boundary is zero-filled by the
+        // device, so it ends the command
+        let pages = vec![page(0x1000_0000, 0x80), page(0x1000_0100, 0x100)];
+        assert_eq!(
+            runs(pages, 0x1000).unwrap(),
+            [(0x1000_0000, 0x80), (0x1000_0100, 0x100)]
+        );
+    }
+
+    #[test]
+    fn next_run_rejects_unaligned() {
+        let pages = vec![page(0x1000_0000, 0x100), page(0x1000_0180, 0x100)];
+        let mut pages = pages.into_iter().peekable();
+        assert!(next_run(&mut pages, 0x1000).unwrap().is_some());
+        assert!(matches!(
+            next_run(&mut pages, 0x1000),
+            Err(Error::WriteInvalidAddr)
+        ));
+    }
+}
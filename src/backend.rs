@@ -0,0 +1,712 @@
+use std::{fmt::Debug, time::Duration};
+use thiserror::Error;
+
+#[cfg(feature = "libusb")]
+use rusb::{Device, DeviceDescriptor, DeviceHandle, UsbContext};
+
+type UsbResult<T> = ::std::result::Result<T, UsbError>;
+
+/// Error from the USB library a connection is made through.
+#[derive(Error, Debug)]
+pub enum UsbError {
+    /// Error from libusb.
+    #[cfg(feature = "libusb")]
+    #[error(transparent)]
+    Libusb(#[from] rusb::Error),
+    /// Error from nusb.
+    #[cfg(feature = "nusb")]
+    #[error(transparent)]
+    Nusb(#[from] nusb::Error),
+    /// Transfer error from nusb.
+    #[cfg(feature = "nusb")]
+    #[error(transparent)]
+    NusbTransfer(#[from] nusb::transfer::TransferError),
+
+    /// Transfer did not complete in time.
+    #[error("usb transfer timed out")]
+    Timeout,
+    /// Transfer needs a claimed interface, and none is.
+    #[error("no usb interface claimed")]
+    NotClaimed,
+}
+impl UsbError {
+    // whether the device has left the bus
+    pub(crate) fn is_disconnected(&self) -> bool {
+        match self {
+            #[cfg(feature = "libusb")]
+            UsbError::Libusb(e) => *e == rusb::Error::NoDevice,
+            #[cfg(feature = "nusb")]
+            UsbError::Nusb(e) => e.kind() == nusb::ErrorKind::Disconnected,
+            #[cfg(feature = "nusb")]
+            UsbError::NusbTransfer(e) => *e == nusb::transfer::TransferError::Disconnected,
+            _ => false,
+        }
+    }
+}
+
+/// A USB library PICOBOOT connections can be made through
+///
+/// With the `libusb` feature (on by default), every [`rusb::UsbContext`] is a
+/// backend. With the `nusb` feature, [`Nusb`] is a backend that does not link
+/// against libusb. Other backends cannot be added outside of this crate.
+pub trait UsbBackend: sealed::Backend {}
+impl<T: sealed::Backend> UsbBackend for T {}
+
+pub(crate) mod sealed {
+    use super::*;
+
+    /// A device found on the bus, described the same way for every backend.
+    #[derive(Debug, Clone)]
+    pub struct DeviceEntry<D> {
+        pub device: D,
+        pub vendor_id: u16,
+        pub product_id: u16,
+        /// BCD encoded device release number.
+        pub device_version: u16,
+        pub bus: u8,
+        pub ports: Vec<u8>,
+        pub address: u8,
+        /// Number, class, subclass and protocol of each interface of the
+        /// active configuration.
+        pub interfaces: Vec<(u8, u8, u8, u8)>,
+    }
+    impl<D> DeviceEntry<D> {
+        /// Returns the number of the first interface with a class, subclass and
+        /// protocol.
+        pub fn find_interface(&self, class: u8, subclass: u8, protocol: u8) -> Option<u8> {
+            self.interfaces
+                .iter()
+                .find(|i| (i.1, i.2, i.3) == (class, subclass, protocol))
+                .map(|i| i.0)
+        }
+    }
+
+    pub trait Backend: Clone + Debug {
+        type Device: Debug;
+        type Handle: Handle;
+
+        /// Lists the devices on the bus.
+        fn list_devices(&self) -> UsbResult<Vec<DeviceEntry<Self::Device>>>;
+
+        /// Opens a device found by [`Self::list_devices`].
+        fn open(&self, device: &DeviceEntry<Self::Device>) -> UsbResult<Self::Handle>;
+    }
+
+    pub trait Handle: Debug {
+        fn read_manufacturer_string(&self) -> Option<String>;
+        fn read_product_string(&self) -> Option<String>;
+        fn read_serial_number_string(&self) -> Option<String>;
+
+        /// Finds a bulk endpoint of an interface with a class, subclass and
+        /// protocol, returning the configuration, interface, alternate setting
+        /// and endpoint address.
+        fn find_bulk_endpoint(
+            &self,
+            class: u8,
+            subclass: u8,
+            protocol: u8,
+            dir_in: bool,
+        ) -> UsbResult<Option<(u8, u8, u8, u8)>>;
+
+        fn kernel_driver_active(&self, iface: u8) -> bool;
+        fn detach_kernel_driver(&mut self, iface: u8) -> UsbResult<()>;
+        fn attach_kernel_driver(&mut self, iface: u8) -> UsbResult<()>;
+        fn set_active_configuration(&mut self, cfg: u8) -> UsbResult<()>;
+        fn claim_interface(&mut self, iface: u8) -> UsbResult<()>;
+        fn release_interface(&mut self, iface: u8) -> UsbResult<()>;
+        fn set_alternate_setting(&mut self, iface: u8, setting: u8) -> UsbResult<()>;
+        fn clear_halt(&mut self, endpoint: u8) -> UsbResult<()>;
+
+        fn read_bulk(
+            &mut self,
+            endpoint: u8,
+            buf: &mut [u8],
+            timeout: Duration,
+        ) -> UsbResult<usize>;
+        fn write_bulk(&mut self, endpoint: u8, buf: &[u8], timeout: Duration) -> UsbResult<usize>;
+
+        /// `setup` is the request type, request, value and index.
+        fn read_control(
+            &mut self,
+            setup: (u8, u8, u16, u16),
+            buf: &mut [u8],
+            timeout: Duration,
+        ) -> UsbResult<usize>;
+        fn write_control(
+            &mut self,
+            setup: (u8, u8, u16, u16),
+            buf: &[u8],
+            timeout: Duration,
+        ) -> UsbResult<usize>;
+    }
+
+    /// An open device of a libusb backend.
+    #[cfg(feature = "libusb")]
+    #[derive(Debug)]
+    pub struct LibusbHandle<T: UsbContext> {
+        pub(super) device: Device<T>,
+        pub(super) desc: DeviceDescriptor,
+        pub(super) handle: DeviceHandle<T>,
+    }
+
+    /// An open device of the nusb backend.
+    ///
+    /// nusb transfers through a claimed interface rather than the device, so one
+    /// interface at a time can be claimed, and its bulk endpoints are opened as
+    /// they are first used.
+    #[cfg(feature = "nusb")]
+    #[derive(Debug)]
+    pub struct NusbHandle {
+        pub(super) info: nusb::DeviceInfo,
+        pub(super) device: nusb::Device,
+        pub(super) interface: Option<nusb::Interface>,
+        pub(super) bulk_in: Option<nusb::Endpoint<nusb::transfer::Bulk, nusb::transfer::In>>,
+        pub(super) bulk_out: Option<nusb::Endpoint<nusb::transfer::Bulk, nusb::transfer::Out>>,
+    }
+}
+#[cfg(feature = "libusb")]
+use sealed::LibusbHandle;
+#[cfg(feature = "nusb")]
+use sealed::NusbHandle;
+#[cfg(any(feature = "libusb", feature = "nusb"))]
+use sealed::{Backend, DeviceEntry, Handle};
+
+#[cfg(feature = "libusb")]
+impl<T: UsbContext> LibusbHandle<T> {
+    pub(crate) fn get_handle(&self) -> &DeviceHandle<T> {
+        &self.handle
+    }
+}
+
+#[cfg(feature = "libusb")]
+impl<T: UsbContext + Debug> Backend for T {
+    type Device = Device<T>;
+    type Handle = LibusbHandle<T>;
+
+    fn list_devices(&self) -> UsbResult<Vec<DeviceEntry<Device<T>>>> {
+        let mut entries = vec![];
+        for device in UsbContext::devices(self)?.iter() {
+            let desc = match device.device_descriptor() {
+                Ok(d) => d,
+                Err(_) => continue,
+            };
+
+            let mut interfaces = vec![];
+            if let Ok(config_desc) = device.active_config_descriptor() {
+                for iface in config_desc.interfaces() {
+                    for iface_desc in iface.descriptors() {
+                        interfaces.push((
+                            iface_desc.interface_number(),
+                            iface_desc.class_code(),
+                            iface_desc.sub_class_code(),
+                            iface_desc.protocol_code(),
+                        ));
+                    }
+                }
+            }
+
+            let version = desc.device_version();
+            entries.push(DeviceEntry {
+                vendor_id: desc.vendor_id(),
+                product_id: desc.product_id(),
+                device_version: (version.major() as u16 / 10) << 12
+                    | (version.major() as u16 % 10) << 8
+                    | (version.minor() as u16) << 4
+                    | version.sub_minor() as u16,
+                bus: device.bus_number(),
+                ports: device.port_numbers().unwrap_or_default(),
+                address: device.address(),
+                interfaces,
+                device,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    fn open(&self, device: &DeviceEntry<Device<T>>) -> UsbResult<LibusbHandle<T>> {
+        let device = device.device.clone();
+        Ok(LibusbHandle {
+            desc: device.device_descriptor()?,
+            handle: device.open()?,
+            device,
+        })
+    }
+}
+
+#[cfg(feature = "libusb")]
+impl<T: UsbContext + Debug> Handle for LibusbHandle<T> {
+    fn read_manufacturer_string(&self) -> Option<String> {
+        self.handle.read_manufacturer_string_ascii(&self.desc).ok()
+    }
+
+    fn read_product_string(&self) -> Option<String> {
+        self.handle.read_product_string_ascii(&self.desc).ok()
+    }
+
+    fn read_serial_number_string(&self) -> Option<String> {
+        self.handle.read_serial_number_string_ascii(&self.desc).ok()
+    }
+
+    fn find_bulk_endpoint(
+        &self,
+        class: u8,
+        subclass: u8,
+        protocol: u8,
+        dir_in: bool,
+    ) -> UsbResult<Option<(u8, u8, u8, u8)>> {
+        let direction = if dir_in {
+            rusb::Direction::In
+        } else {
+            rusb::Direction::Out
+        };
+
+        let desc = self.device.device_descriptor()?;
+        for n in 0..desc.num_configurations() {
+            let config_desc = match self.device.config_descriptor(n) {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+
+            for iface in config_desc.interfaces() {
+                for iface_desc in iface.descriptors() {
+                    if (
+                        iface_desc.class_code(),
+                        iface_desc.sub_class_code(),
+                        iface_desc.protocol_code(),
+                    ) != (class, subclass, protocol)
+                    {
+                        continue;
+                    }
+
+                    for endpoint_desc in iface_desc.endpoint_descriptors() {
+                        if endpoint_desc.direction() == direction
+                            && endpoint_desc.transfer_type() == rusb::TransferType::Bulk
+                        {
+                            return Ok(Some((
+                                config_desc.number(),
+                                iface_desc.interface_number(),
+                                iface_desc.setting_number(),
+                                endpoint_desc.address(),
+                            )));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn kernel_driver_active(&self, iface: u8) -> bool {
+        self.handle.kernel_driver_active(iface).unwrap_or(false)
+    }
+
+    fn detach_kernel_driver(&mut self, iface: u8) -> UsbResult<()> {
+        Ok(self.handle.detach_kernel_driver(iface)?)
+    }
+
+    fn attach_kernel_driver(&mut self, iface: u8) -> UsbResult<()> {
+        Ok(self.handle.attach_kernel_driver(iface)?)
+    }
+
+    fn set_active_configuration(&mut self, cfg: u8) -> UsbResult<()> {
+        Ok(self.handle.set_active_configuration(cfg)?)
+    }
+
+    fn claim_interface(&mut self, iface: u8) -> UsbResult<()> {
+        Ok(self.handle.claim_interface(iface)?)
+    }
+
+    fn release_interface(&mut self, iface: u8) -> UsbResult<()> {
+        Ok(self.handle.release_interface(iface)?)
+    }
+
+    fn set_alternate_setting(&mut self, iface: u8, setting: u8) -> UsbResult<()> {
+        Ok(self.handle.set_alternate_setting(iface, setting)?)
+    }
+
+    fn clear_halt(&mut self, endpoint: u8) -> UsbResult<()> {
+        Ok(self.handle.clear_halt(endpoint)?)
+    }
+
+    fn read_bulk(&mut self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> UsbResult<usize> {
+        Ok(self.handle.read_bulk(endpoint, buf, timeout)?)
+    }
+
+    fn write_bulk(&mut self, endpoint: u8, buf: &[u8], timeout: Duration) -> UsbResult<usize> {
+        Ok(self.handle.write_bulk(endpoint, buf, timeout)?)
+    }
+
+    fn read_control(
+        &mut self,
+        setup: (u8, u8, u16, u16),
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> UsbResult<usize> {
+        let (request_type, request, value, index) = setup;
+        Ok(self
+            .handle
+            .read_control(request_type, request, value, index, buf, timeout)?)
+    }
+
+    fn write_control(
+        &mut self,
+        setup: (u8, u8, u16, u16),
+        buf: &[u8],
+        timeout: Duration,
+    ) -> UsbResult<usize> {
+        let (request_type, request, value, index) = setup;
+        Ok(self
+            .handle
+            .write_control(request_type, request, value, index, buf, timeout)?)
+    }
+}
+
+/// Pure Rust USB backend built on nusb
+///
+/// Pass it where a rusb context would go, e.g.
+/// `PicobootConnection::new(Nusb, None)`. There is no state to set up, so
+/// every value refers to the same system USB stack.
+#[cfg(feature = "nusb")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Nusb;
+
+// how long to wait for string descriptors the OS has not cached
+#[cfg(feature = "nusb")]
+const NUSB_STRING_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[cfg(feature = "nusb")]
+impl NusbHandle {
+    fn read_string(
+        &self,
+        cached: Option<&str>,
+        index: Option<std::num::NonZeroU8>,
+    ) -> Option<String> {
+        use nusb::{descriptors::language_id::US_ENGLISH, MaybeFuture};
+
+        if let Some(s) = cached {
+            return Some(s.to_owned());
+        }
+        self.device
+            .get_string_descriptor(index?, US_ENGLISH, NUSB_STRING_TIMEOUT)
+            .wait()
+            .ok()
+    }
+
+    fn get_interface(&self) -> UsbResult<&nusb::Interface> {
+        self.interface.as_ref().ok_or(UsbError::NotClaimed)
+    }
+
+    // splits a request type into the fields nusb wants
+    fn control_type(request_type: u8) -> (nusb::transfer::ControlType, nusb::transfer::Recipient) {
+        use nusb::transfer::{ControlType, Recipient};
+
+        let control_type = match (request_type >> 5) & 0b11 {
+            0 => ControlType::Standard,
+            1 => ControlType::Class,
+            _ => ControlType::Vendor,
+        };
+        let recipient = match request_type & 0b11111 {
+            0 => Recipient::Device,
+            1 => Recipient::Interface,
+            2 => Recipient::Endpoint,
+            _ => Recipient::Other,
+        };
+        (control_type, recipient)
+    }
+}
+
+#[cfg(feature = "nusb")]
+impl Backend for Nusb {
+    type Device = nusb::DeviceInfo;
+    type Handle = NusbHandle;
+
+    fn list_devices(&self) -> UsbResult<Vec<DeviceEntry<nusb::DeviceInfo>>> {
+        use nusb::MaybeFuture;
+
+        let devices = nusb::list_devices().wait()?;
+        Ok(devices
+            .map(|info| DeviceEntry {
+                vendor_id: info.vendor_id(),
+                product_id: info.product_id(),
+                device_version: info.device_version(),
+                bus: nusb_bus_number(&info),
+                ports: info.port_chain().to_vec(),
+                address: info.device_address(),
+                interfaces: info
+                    .interfaces()
+                    .map(|i| (i.interface_number(), i.class(), i.subclass(), i.protocol()))
+                    .collect(),
+                device: info,
+            })
+            .collect())
+    }
+
+    fn open(&self, device: &DeviceEntry<nusb::DeviceInfo>) -> UsbResult<NusbHandle> {
+        use nusb::MaybeFuture;
+
+        Ok(NusbHandle {
+            device: device.device.open().wait()?,
+            info: device.device.clone(),
+            interface: None,
+            bulk_in: None,
+            bulk_out: None,
+        })
+    }
+}
+
+// nusb names buses with strings, which are numbers on Linux and hexadecimal
+// on macOS
+#[cfg(feature = "nusb")]
+fn nusb_bus_number(info: &nusb::DeviceInfo) -> u8 {
+    let bus = info.bus_id();
+    bus.parse()
+        .ok()
+        .or_else(|| u8::from_str_radix(bus, 16).ok())
+        .unwrap_or(0)
+}
+
+#[cfg(feature = "nusb")]
+impl Handle for NusbHandle {
+    fn read_manufacturer_string(&self) -> Option<String> {
+        let index = self.device.device_descriptor().manufacturer_string_index();
+        self.read_string(self.info.manufacturer_string(), index)
+    }
+
+    fn read_product_string(&self) -> Option<String> {
+        let index = self.device.device_descriptor().product_string_index();
+        self.read_string(self.info.product_string(), index)
+    }
+
+    fn read_serial_number_string(&self) -> Option<String> {
+        let index = self.device.device_descriptor().serial_number_string_index();
+        self.read_string(self.info.serial_number(), index)
+    }
+
+    fn find_bulk_endpoint(
+        &self,
+        class: u8,
+        subclass: u8,
+        protocol: u8,
+        dir_in: bool,
+    ) -> UsbResult<Option<(u8, u8, u8, u8)>> {
+        use nusb::descriptors::TransferType;
+        use nusb::transfer::Direction;
+
+        let direction = if dir_in {
+            Direction::In
+        } else {
+            Direction::Out
+        };
+
+        for config_desc in self.device.configurations() {
+            for iface_desc in config_desc.interface_alt_settings() {
+                if (
+                    iface_desc.class(),
+                    iface_desc.subclass(),
+                    iface_desc.protocol(),
+                ) != (class, subclass, protocol)
+                {
+                    continue;
+                }
+
+                for endpoint_desc in iface_desc.endpoints() {
+                    if endpoint_desc.direction() == direction
+                        && endpoint_desc.transfer_type() == TransferType::Bulk
+                    {
+                        return Ok(Some((
+                            config_desc.configuration_value(),
+                            iface_desc.interface_number(),
+                            iface_desc.alternate_setting(),
+                            endpoint_desc.address(),
+                        )));
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    // nusb cannot tell whether a kernel driver is bound, so interfaces are
+    // claimed with detach_and_claim_interface instead
+    fn kernel_driver_active(&self, _iface: u8) -> bool {
+        false
+    }
+
+    fn detach_kernel_driver(&mut self, iface: u8) -> UsbResult<()> {
+        Ok(self.device.detach_kernel_driver(iface)?)
+    }
+
+    fn attach_kernel_driver(&mut self, iface: u8) -> UsbResult<()> {
+        Ok(self.device.attach_kernel_driver(iface)?)
+    }
+
+    fn set_active_configuration(&mut self, cfg: u8) -> UsbResult<()> {
+        use nusb::MaybeFuture;
+
+        if self
+            .device
+            .active_configuration()
+            .map(|c| c.configuration_value())
+            == Ok(cfg)
+        {
+            return Ok(());
+        }
+        Ok(self.device.set_configuration(cfg).wait()?)
+    }
+
+    fn claim_interface(&mut self, iface: u8) -> UsbResult<()> {
+        use nusb::MaybeFuture;
+
+        self.release_interface(iface)?;
+        self.interface = Some(self.device.detach_and_claim_interface(iface).wait()?);
+        Ok(())
+    }
+
+    fn release_interface(&mut self, _iface: u8) -> UsbResult<()> {
+        // dropping the last reference releases the interface
+        self.bulk_in = None;
+        self.bulk_out = None;
+        self.interface = None;
+        Ok(())
+    }
+
+    fn set_alternate_setting(&mut self, _iface: u8, setting: u8) -> UsbResult<()> {
+        use nusb::MaybeFuture;
+
+        // endpoints may not be open while the setting changes
+        self.bulk_in = None;
+        self.bulk_out = None;
+        Ok(self.get_interface()?.set_alt_setting(setting).wait()?)
+    }
+
+    fn clear_halt(&mut self, endpoint: u8) -> UsbResult<()> {
+        use nusb::MaybeFuture;
+
+        if endpoint & 0x80 != 0 {
+            self.get_bulk_in(endpoint)?.clear_halt().wait()?;
+        } else {
+            self.get_bulk_out(endpoint)?.clear_halt().wait()?;
+        }
+        Ok(())
+    }
+
+    fn read_bulk(&mut self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> UsbResult<usize> {
+        let ep = self.get_bulk_in(endpoint)?;
+
+        // IN transfers must ask for whole packets, the device still stops
+        // after the bytes it has
+        let packet = ep.max_packet_size();
+        let requested = (std::cmp::max(buf.len(), 1) + packet - 1) / packet * packet;
+        let completion = ep.transfer_blocking(nusb::transfer::Buffer::new(requested), timeout);
+        completion.status.map_err(nusb_error)?;
+
+        let len = std::cmp::min(completion.buffer.len(), buf.len());
+        buf[..len].copy_from_slice(&completion.buffer[..len]);
+        Ok(len)
+    }
+
+    fn write_bulk(&mut self, endpoint: u8, buf: &[u8], timeout: Duration) -> UsbResult<usize> {
+        let ep = self.get_bulk_out(endpoint)?;
+        let completion = ep.transfer_blocking(buf.to_vec().into(), timeout);
+        completion.status.map_err(nusb_error)?;
+
+        Ok(completion.actual_len)
+    }
+
+    fn read_control(
+        &mut self,
+        setup: (u8, u8, u16, u16),
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> UsbResult<usize> {
+        use nusb::{transfer::ControlIn, MaybeFuture};
+
+        let (request_type, request, value, index) = setup;
+        let (control_type, recipient) = Self::control_type(request_type);
+        let data = self
+            .get_interface()?
+            .control_in(
+                ControlIn {
+                    control_type,
+                    recipient,
+                    request,
+                    value,
+                    index,
+                    length: buf.len() as u16,
+                },
+                timeout,
+            )
+            .wait()
+            .map_err(nusb_error)?;
+
+        let len = std::cmp::min(data.len(), buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn write_control(
+        &mut self,
+        setup: (u8, u8, u16, u16),
+        buf: &[u8],
+        timeout: Duration,
+    ) -> UsbResult<usize> {
+        use nusb::{transfer::ControlOut, MaybeFuture};
+
+        let (request_type, request, value, index) = setup;
+        let (control_type, recipient) = Self::control_type(request_type);
+        self.get_interface()?
+            .control_out(
+                ControlOut {
+                    control_type,
+                    recipient,
+                    request,
+                    value,
+                    index,
+                    data: buf,
+                },
+                timeout,
+            )
+            .wait()
+            .map_err(nusb_error)?;
+
+        Ok(buf.len())
+    }
+}
+
+#[cfg(feature = "nusb")]
+impl NusbHandle {
+    fn get_bulk_in(
+        &mut self,
+        endpoint: u8,
+    ) -> UsbResult<&mut nusb::Endpoint<nusb::transfer::Bulk, nusb::transfer::In>> {
+        if self.bulk_in.as_ref().map(|ep| ep.endpoint_address()) != Some(endpoint) {
+            self.bulk_in = None;
+            self.bulk_in = Some(self.get_interface()?.endpoint(endpoint)?);
+        }
+        Ok(self.bulk_in.as_mut().unwrap())
+    }
+
+    fn get_bulk_out(
+        &mut self,
+        endpoint: u8,
+    ) -> UsbResult<&mut nusb::Endpoint<nusb::transfer::Bulk, nusb::transfer::Out>> {
+        if self.bulk_out.as_ref().map(|ep| ep.endpoint_address()) != Some(endpoint) {
+            self.bulk_out = None;
+            self.bulk_out = Some(self.get_interface()?.endpoint(endpoint)?);
+        }
+        Ok(self.bulk_out.as_mut().unwrap())
+    }
+}
+
+// nusb reports a transfer cancelled on its timeout the same as any other
+// cancellation, and only cancels transfers on timeouts here
+#[cfg(feature = "nusb")]
+fn nusb_error(err: nusb::transfer::TransferError) -> UsbError {
+    match err {
+        nusb::transfer::TransferError::Cancelled => UsbError::Timeout,
+        e => e.into(),
+    }
+}
@@ -0,0 +1,724 @@
+use crate::{
+    cmd::PicobootError,
+    memory::{Image, MemoryRead, PageCache},
+};
+
+// see https://github.com/raspberrypi/pico-sdk/tree/master/src/common/pico_binary_info
+// for the layout of binary info in an image
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// Marker word preceding the binary info header
+pub const BINARY_INFO_MARKER_START: u32 = 0x7188EBF2;
+/// Marker word following the binary info header
+pub const BINARY_INFO_MARKER_END: u32 = 0xE71AA390;
+
+/// Binary info tag for entries defined by Raspberry Pi
+pub const BINARY_INFO_TAG_RASPBERRY_PI: u16 = u16::from_le_bytes(*b"RP");
+
+/// Binary info ID for the program name
+pub const BINARY_INFO_ID_RP_PROGRAM_NAME: u32 = 0x02031C86;
+/// Binary info ID for the program version string
+pub const BINARY_INFO_ID_RP_PROGRAM_VERSION_STRING: u32 = 0x11A9BC3A;
+/// Binary info ID for the program build date string
+pub const BINARY_INFO_ID_RP_PROGRAM_BUILD_DATE_STRING: u32 = 0x9DA22254;
+/// Binary info ID for the address of the end of the binary
+pub const BINARY_INFO_ID_RP_BINARY_END: u32 = 0x68F465DE;
+/// Binary info ID for the program URL
+pub const BINARY_INFO_ID_RP_PROGRAM_URL: u32 = 0x1856239A;
+/// Binary info ID for the program description
+pub const BINARY_INFO_ID_RP_PROGRAM_DESCRIPTION: u32 = 0xB6A07C19;
+/// Binary info ID for a program feature
+pub const BINARY_INFO_ID_RP_PROGRAM_FEATURE: u32 = 0xA1F4B453;
+/// Binary info ID for a program build attribute
+pub const BINARY_INFO_ID_RP_PROGRAM_BUILD_ATTRIBUTE: u32 = 0x4275F0D3;
+/// Binary info ID for the Pico SDK version
+pub const BINARY_INFO_ID_RP_SDK_VERSION: u32 = 0x5360B3AB;
+/// Binary info ID for the board the program was built for
+pub const BINARY_INFO_ID_RP_PICO_BOARD: u32 = 0xB63CFFBB;
+/// Binary info ID for the second stage bootloader name
+pub const BINARY_INFO_ID_RP_BOOT2_NAME: u32 = 0x7F8882E1;
+
+const BINARY_INFO_TYPE_ID_AND_INT: u16 = 5;
+const BINARY_INFO_TYPE_ID_AND_STRING: u16 = 6;
+const BINARY_INFO_TYPE_BLOCK_DEVICE: u16 = 7;
+const BINARY_INFO_TYPE_PINS_WITH_FUNC: u16 = 8;
+const BINARY_INFO_TYPE_PINS_WITH_NAME: u16 = 9;
+const BINARY_INFO_TYPE_NAMED_GROUP: u16 = 10;
+const BINARY_INFO_TYPE_PTR_INT32_WITH_NAME: u16 = 11;
+const BINARY_INFO_TYPE_PTR_STRING_WITH_NAME: u16 = 12;
+const BINARY_INFO_TYPE_PINS64_WITH_FUNC: u16 = 13;
+const BINARY_INFO_TYPE_PINS64_WITH_NAME: u16 = 14;
+
+const BI_PINS_ENCODING_RANGE: u64 = 1;
+const BI_PINS_ENCODING_MULTI: u64 = 2;
+
+// the header sits right after the vector table on RP2350, or after the 256
+// byte boot2 on RP2040
+const BINARY_INFO_SEARCH_LEN: u32 = 0x200;
+// guards against walking off into garbage on a corrupt image
+const BINARY_INFO_MAX_ENTRIES: u32 = 1024;
+const BINARY_INFO_MAX_STRING_LEN: u32 = 1024;
+
+/// A single decoded binary info entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BinaryInfoEntry {
+    /// An integer value identified by a tag and ID.
+    IdAndInt { tag: u16, id: u32, value: i32 },
+    /// A string value identified by a tag and ID.
+    IdAndString { tag: u16, id: u32, value: String },
+    /// A block device (e.g. a filesystem) stored in flash.
+    BlockDevice {
+        tag: u16,
+        name: String,
+        address: u32,
+        size: u32,
+        flags: u16,
+    },
+    /// A set of pins configured for a GPIO function.
+    PinsWithFunc { tag: u16, pins: Vec<u8>, func: u8 },
+    /// A set of pins with a label. Labels for multiple pins are separated by
+    /// `|`, in ascending pin order.
+    PinsWithName {
+        tag: u16,
+        pin_mask: u64,
+        label: String,
+    },
+    /// A named group that other entries with a matching tag and ID belong to.
+    NamedGroup {
+        tag: u16,
+        parent_id: u32,
+        flags: u16,
+        group_tag: u16,
+        group_id: u32,
+        label: String,
+    },
+    /// A labelled integer, usually a configurable value in the binary.
+    PtrInt32WithName {
+        tag: u16,
+        id: u32,
+        label: String,
+        value: i32,
+    },
+    /// A labelled string, usually a configurable value in the binary.
+    PtrStringWithName {
+        tag: u16,
+        id: u32,
+        label: String,
+        value: String,
+    },
+    /// An entry of a type this crate does not decode.
+    Unknown { entry_type: u16, tag: u16 },
+}
+impl BinaryInfoEntry {
+    /// Returns the tag of the entry.
+    pub fn get_tag(&self) -> u16 {
+        match self {
+            BinaryInfoEntry::IdAndInt { tag, .. }
+            | BinaryInfoEntry::IdAndString { tag, .. }
+            | BinaryInfoEntry::BlockDevice { tag, .. }
+            | BinaryInfoEntry::PinsWithFunc { tag, .. }
+            | BinaryInfoEntry::PinsWithName { tag, .. }
+            | BinaryInfoEntry::NamedGroup { tag, .. }
+            | BinaryInfoEntry::PtrInt32WithName { tag, .. }
+            | BinaryInfoEntry::PtrStringWithName { tag, .. }
+            | BinaryInfoEntry::Unknown { tag, .. } => *tag,
+        }
+    }
+
+    /// Returns the ID of the entry, for entry types that have one.
+    pub fn get_id(&self) -> Option<u32> {
+        match self {
+            BinaryInfoEntry::IdAndInt { id, .. }
+            | BinaryInfoEntry::IdAndString { id, .. }
+            | BinaryInfoEntry::PtrInt32WithName { id, .. }
+            | BinaryInfoEntry::PtrStringWithName { id, .. } => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+/// Function of a single pin, as collected from all pin entries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PinUsage {
+    /// The pin is configured for the numbered GPIO function.
+    Func(u8),
+    /// The pin is labelled by the program.
+    Name(String),
+}
+
+/// Binary info embedded in a Pico SDK program
+///
+/// Pico SDK programs carry a table of tagged entries describing the program,
+/// which `picotool info` displays. The table is found through a header near
+/// the start of the image, for both RP2040 and RP2350 images.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BinaryInfo {
+    entries: Vec<BinaryInfoEntry>,
+}
+impl BinaryInfo {
+    /// Parses binary info from memory.
+    ///
+    /// - `mem` - Memory to read from, either an [`Image`] or a connected device.
+    /// - `start` - Address the program starts at, e.g. [`PICO_FLASH_START`](crate::PICO_FLASH_START).
+    ///
+    /// # Errors:
+    /// - [`Error::BinaryInfoNotFound`]
+    /// - [`Error::BinaryInfoMalformed`]
+    /// - Any produced by `mem`
+    pub fn parse<M: MemoryRead + ?Sized>(mem: &mut M, start: u32) -> Result<Self> {
+        let mut mem = PageCache::new(mem);
+        let header = Self::find_header(&mut mem, start)?;
+        let (entries_start, entries_end, mapping_table) = (header[1], header[2], header[3]);
+        let mapping = AddressMapping::read(&mut mem, mapping_table)?;
+
+        if entries_end < entries_start
+            || (entries_end - entries_start) / 4 > BINARY_INFO_MAX_ENTRIES
+        {
+            return Err(Error::BinaryInfoMalformed);
+        }
+
+        let mut parser = Parser {
+            mem: &mut mem,
+            mapping,
+        };
+        let mut entries = vec![];
+        for addr in (entries_start..entries_end).step_by(4) {
+            let ptr = parser.read_u32(addr)?;
+            entries.push(parser.read_entry(ptr)?);
+        }
+
+        Ok(BinaryInfo { entries })
+    }
+
+    /// Parses binary info from an image in host memory.
+    ///
+    /// - `image` - Image to parse, e.g. a binary converted from a UF2.
+    /// - `start` - Address the image is mapped at, e.g. [`PICO_FLASH_START`](crate::PICO_FLASH_START).
+    ///
+    /// # Errors:
+    /// - Any produced by [`Self::parse`]
+    pub fn from_image(image: &[u8], start: u32) -> Result<Self> {
+        Self::parse(&mut Image::new(start, image), start)
+    }
+
+    fn find_header<M: MemoryRead + ?Sized>(mem: &mut M, start: u32) -> Result<[u32; 5]> {
+        let buf = match mem.read_memory(start, BINARY_INFO_SEARCH_LEN + 16) {
+            Ok(b) => b,
+            Err(Error::MemoryOutOfRange) => return Err(Error::BinaryInfoNotFound),
+            Err(e) => return Err(e),
+        };
+        let words: Vec<u32> = buf
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+
+        words
+            .windows(5)
+            .find(|w| w[0] == BINARY_INFO_MARKER_START && w[4] == BINARY_INFO_MARKER_END)
+            .map(|w| [w[0], w[1], w[2], w[3], w[4]])
+            .ok_or(Error::BinaryInfoNotFound)
+    }
+
+    /// Returns all entries, in the order they appear in the binary.
+    pub fn get_entries(&self) -> &[BinaryInfoEntry] {
+        &self.entries
+    }
+
+    /// Returns the value of the first Raspberry Pi string entry with `id`.
+    pub fn get_string(&self, id: u32) -> Option<&str> {
+        self.get_strings(BINARY_INFO_TAG_RASPBERRY_PI, id).next()
+    }
+
+    /// Returns the values of all string entries with `tag` and `id`.
+    pub fn get_strings(&self, tag: u16, id: u32) -> impl Iterator<Item = &str> {
+        self.entries.iter().filter_map(move |e| match e {
+            BinaryInfoEntry::IdAndString {
+                tag: t,
+                id: i,
+                value,
+            } if *t == tag && *i == id => Some(value.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Returns the value of the first integer entry with `tag` and `id`.
+    pub fn get_int(&self, tag: u16, id: u32) -> Option<i32> {
+        self.entries.iter().find_map(|e| match e {
+            BinaryInfoEntry::IdAndInt {
+                tag: t,
+                id: i,
+                value,
+            } if *t == tag && *i == id => Some(*value),
+            _ => None,
+        })
+    }
+
+    /// Returns the program name.
+    pub fn get_program_name(&self) -> Option<&str> {
+        self.get_string(BINARY_INFO_ID_RP_PROGRAM_NAME)
+    }
+
+    /// Returns the program version string.
+    pub fn get_program_version(&self) -> Option<&str> {
+        self.get_string(BINARY_INFO_ID_RP_PROGRAM_VERSION_STRING)
+    }
+
+    /// Returns the program build date string.
+    pub fn get_build_date(&self) -> Option<&str> {
+        self.get_string(BINARY_INFO_ID_RP_PROGRAM_BUILD_DATE_STRING)
+    }
+
+    /// Returns the program URL.
+    pub fn get_program_url(&self) -> Option<&str> {
+        self.get_string(BINARY_INFO_ID_RP_PROGRAM_URL)
+    }
+
+    /// Returns the program description.
+    pub fn get_program_description(&self) -> Option<&str> {
+        self.get_string(BINARY_INFO_ID_RP_PROGRAM_DESCRIPTION)
+    }
+
+    /// Returns the Pico SDK version the program was built with.
+    pub fn get_sdk_version(&self) -> Option<&str> {
+        self.get_string(BINARY_INFO_ID_RP_SDK_VERSION)
+    }
+
+    /// Returns the board the program was built for.
+    pub fn get_pico_board(&self) -> Option<&str> {
+        self.get_string(BINARY_INFO_ID_RP_PICO_BOARD)
+    }
+
+    /// Returns the name of the second stage bootloader. (Only for RP2040)
+    pub fn get_boot2_name(&self) -> Option<&str> {
+        self.get_string(BINARY_INFO_ID_RP_BOOT2_NAME)
+    }
+
+    /// Returns the address of the end of the binary.
+    pub fn get_binary_end(&self) -> Option<u32> {
+        self.get_int(BINARY_INFO_TAG_RASPBERRY_PI, BINARY_INFO_ID_RP_BINARY_END)
+            .map(|v| v as u32)
+    }
+
+    /// Returns the program features that are not part of a named group.
+    pub fn get_features(&self) -> impl Iterator<Item = &str> {
+        self.get_strings(
+            BINARY_INFO_TAG_RASPBERRY_PI,
+            BINARY_INFO_ID_RP_PROGRAM_FEATURE,
+        )
+    }
+
+    /// Returns the program build attributes.
+    pub fn get_build_attributes(&self) -> impl Iterator<Item = &str> {
+        self.get_strings(
+            BINARY_INFO_TAG_RASPBERRY_PI,
+            BINARY_INFO_ID_RP_PROGRAM_BUILD_ATTRIBUTE,
+        )
+    }
+
+    /// Returns the named groups, as `(group_tag, group_id, label)`.
+    pub fn get_named_groups(&self) -> impl Iterator<Item = (u16, u32, &str)> {
+        self.entries.iter().filter_map(|e| match e {
+            BinaryInfoEntry::NamedGroup {
+                group_tag,
+                group_id,
+                label,
+                ..
+            } => Some((*group_tag, *group_id, label.as_str())),
+            _ => None,
+        })
+    }
+
+    /// Returns the entries belonging to the named group `group_tag`/`group_id`.
+    pub fn get_group_entries(
+        &self,
+        group_tag: u16,
+        group_id: u32,
+    ) -> impl Iterator<Item = &BinaryInfoEntry> {
+        self.entries
+            .iter()
+            .filter(move |e| e.get_tag() == group_tag && e.get_id() == Some(group_id))
+    }
+
+    /// Returns every pin used by the program, sorted by pin number.
+    pub fn get_pins(&self) -> Vec<(u8, PinUsage)> {
+        let mut pins = vec![];
+        for e in &self.entries {
+            match e {
+                BinaryInfoEntry::PinsWithFunc { pins: p, func, .. } => {
+                    pins.extend(p.iter().map(|&pin| (pin, PinUsage::Func(*func))));
+                }
+                BinaryInfoEntry::PinsWithName {
+                    pin_mask, label, ..
+                } => {
+                    let mut labels = label.split('|');
+                    let mut last = label.as_str();
+                    for pin in (0..64u8).filter(|p| pin_mask & (1 << p) != 0) {
+                        last = labels.next().unwrap_or(last);
+                        pins.push((pin, PinUsage::Name(last.to_string())));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        pins.sort_by_key(|&(pin, _)| pin);
+        pins
+    }
+}
+
+// maps addresses of data copied to RAM at startup back to where it is stored
+struct AddressMapping {
+    ranges: Vec<(u32, u32, u32)>,
+}
+impl AddressMapping {
+    fn read<M: MemoryRead + ?Sized>(mem: &mut M, table: u32) -> Result<Self> {
+        let mut ranges = vec![];
+        if table == 0 {
+            return Ok(AddressMapping { ranges });
+        }
+
+        for i in 0..BINARY_INFO_MAX_ENTRIES {
+            let entry = mem.read_memory(at(table, i * 12)?, 12)?;
+            let word =
+                |n: usize| u32::from_le_bytes([entry[n], entry[n + 1], entry[n + 2], entry[n + 3]]);
+            let (source, dest_start, dest_end) = (word(0), word(4), word(8));
+            if source == 0 {
+                return Ok(AddressMapping { ranges });
+            }
+            ranges.push((source, dest_start, dest_end));
+        }
+
+        Err(Error::BinaryInfoMalformed)
+    }
+
+    fn map(&self, addr: u32) -> u32 {
+        for &(source, dest_start, dest_end) in &self.ranges {
+            if addr >= dest_start && addr < dest_end {
+                return source.wrapping_add(addr - dest_start);
+            }
+        }
+        addr
+    }
+}
+
+struct Parser<'a, M: MemoryRead + ?Sized> {
+    mem: &'a mut M,
+    mapping: AddressMapping,
+}
+impl<M: MemoryRead + ?Sized> Parser<'_, M> {
+    fn read(&mut self, addr: u32, size: u32) -> Result<Vec<u8>> {
+        let addr = self.mapping.map(addr);
+        self.mem.read_memory(addr, size)
+    }
+
+    fn read_u16(&mut self, addr: u32) -> Result<u16> {
+        let buf = self.read(addr, 2)?;
+        Ok(u16::from_le_bytes([buf[0], buf[1]]))
+    }
+
+    fn read_u32(&mut self, addr: u32) -> Result<u32> {
+        let buf = self.read(addr, 4)?;
+        Ok(u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]))
+    }
+
+    fn read_u64(&mut self, addr: u32) -> Result<u64> {
+        Ok(self.read_u32(addr)? as u64 | (self.read_u32(at(addr, 4)?)? as u64) << 32)
+    }
+
+    fn read_string(&mut self, addr: u32) -> Result<String> {
+        let mut buf = vec![];
+        for i in 0..BINARY_INFO_MAX_STRING_LEN {
+            let c = self.read(at(addr, i)?, 1)?[0];
+            if c == 0 {
+                return Ok(String::from_utf8_lossy(&buf).into_owned());
+            }
+            buf.push(c);
+        }
+
+        Err(Error::BinaryInfoMalformed)
+    }
+
+    fn read_string_ptr(&mut self, addr: u32) -> Result<String> {
+        let ptr = self.read_u32(addr)?;
+        self.read_string(ptr)
+    }
+
+    fn read_entry(&mut self, addr: u32) -> Result<BinaryInfoEntry> {
+        let entry_type = self.read_u16(addr)?;
+        let tag = self.read_u16(at(addr, 2)?)?;
+
+        let entry = match entry_type {
+            BINARY_INFO_TYPE_ID_AND_INT => BinaryInfoEntry::IdAndInt {
+                tag,
+                id: self.read_u32(at(addr, 4)?)?,
+                value: self.read_u32(at(addr, 8)?)? as i32,
+            },
+            BINARY_INFO_TYPE_ID_AND_STRING => BinaryInfoEntry::IdAndString {
+                tag,
+                id: self.read_u32(at(addr, 4)?)?,
+                value: self.read_string_ptr(at(addr, 8)?)?,
+            },
+            BINARY_INFO_TYPE_BLOCK_DEVICE => BinaryInfoEntry::BlockDevice {
+                tag,
+                name: self.read_string_ptr(at(addr, 4)?)?,
+                address: self.read_u32(at(addr, 8)?)?,
+                size: self.read_u32(at(addr, 12)?)?,
+                // skips the pointer to extra entries
+                flags: self.read_u16(at(addr, 20)?)?,
+            },
+            BINARY_INFO_TYPE_PINS_WITH_FUNC => {
+                let encoding = self.read_u32(at(addr, 4)?)? as u64;
+                let (pins, func) = decode_pins(encoding, 0xF, 7, 5, 5);
+                BinaryInfoEntry::PinsWithFunc { tag, pins, func }
+            }
+            BINARY_INFO_TYPE_PINS64_WITH_FUNC => {
+                let encoding = self.read_u64(at(addr, 4)?)?;
+                let (pins, func) = decode_pins(encoding, 0x1F, 8, 8, 7);
+                BinaryInfoEntry::PinsWithFunc { tag, pins, func }
+            }
+            BINARY_INFO_TYPE_PINS_WITH_NAME => BinaryInfoEntry::PinsWithName {
+                tag,
+                pin_mask: self.read_u32(at(addr, 4)?)? as u64,
+                label: self.read_string_ptr(at(addr, 8)?)?,
+            },
+            BINARY_INFO_TYPE_PINS64_WITH_NAME => BinaryInfoEntry::PinsWithName {
+                tag,
+                pin_mask: self.read_u64(at(addr, 4)?)?,
+                label: self.read_string_ptr(at(addr, 12)?)?,
+            },
+            BINARY_INFO_TYPE_NAMED_GROUP => BinaryInfoEntry::NamedGroup {
+                tag,
+                parent_id: self.read_u32(at(addr, 4)?)?,
+                flags: self.read_u16(at(addr, 8)?)?,
+                group_tag: self.read_u16(at(addr, 10)?)?,
+                group_id: self.read_u32(at(addr, 12)?)?,
+                label: self.read_string_ptr(at(addr, 16)?)?,
+            },
+            BINARY_INFO_TYPE_PTR_INT32_WITH_NAME => {
+                let id = self.read_u32(at(addr, 4)?)?;
+                let value_ptr = self.read_u32(at(addr, 8)?)?;
+                let label = self.read_string_ptr(at(addr, 12)?)?;
+                BinaryInfoEntry::PtrInt32WithName {
+                    tag,
+                    id,
+                    label,
+                    value: self.read_u32(value_ptr)? as i32,
+                }
+            }
+            BINARY_INFO_TYPE_PTR_STRING_WITH_NAME => BinaryInfoEntry::PtrStringWithName {
+                tag,
+                id: self.read_u32(at(addr, 4)?)?,
+                value: self.read_string_ptr(at(addr, 8)?)?,
+                label: self.read_string_ptr(at(addr, 12)?)?,
+            },
+            _ => BinaryInfoEntry::Unknown { entry_type, tag },
+        };
+
+        Ok(entry)
+    }
+}
+
+// returns the address `offset` bytes past `addr`, as long as it does not run
+// past the end of the address space
+fn at(addr: u32, offset: u32) -> Result<u32> {
+    addr.checked_add(offset).ok_or(Error::BinaryInfoMalformed)
+}
+
+// decodes an encoded pin list, where the low 3 bits give the encoding and the
+// function follows, then either a pin range or a list of pins terminated by
+// repeating the last pin
+fn decode_pins(
+    encoding: u64,
+    func_mask: u64,
+    pins_lsb: u32,
+    pin_bits: u32,
+    max_pins: u32,
+) -> (Vec<u8>, u8) {
+    let func = ((encoding >> 3) & func_mask) as u8;
+    let pin_mask = (1u64 << pin_bits) - 1;
+    let mut pins = vec![];
+
+    match encoding & 0x7 {
+        BI_PINS_ENCODING_RANGE => {
+            let lo = (encoding >> pins_lsb) & pin_mask;
+            let hi = (encoding >> (pins_lsb + pin_bits)) & pin_mask;
+            pins.extend((lo..=hi).map(|p| p as u8));
+        }
+        BI_PINS_ENCODING_MULTI => {
+            let mut work = encoding >> pins_lsb;
+            for _ in 0..max_pins {
+                let pin = (work & pin_mask) as u8;
+                if pins.last() == Some(&pin) {
+                    break;
+                }
+                pins.push(pin);
+                work >>= pin_bits;
+            }
+        }
+        _ => {}
+    }
+
+    (pins, func)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u32 = 0x10000000;
+
+    fn put_u32(image: &mut [u8], offset: usize, value: u32) {
+        image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u16(image: &mut [u8], offset: usize, value: u16) {
+        image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn decode_pin_range() {
+        let encoding = BI_PINS_ENCODING_RANGE | 5 << 3 | 4 << 7 | 7 << 12;
+        assert_eq!(decode_pins(encoding, 0xF, 7, 5, 5), (vec![4, 5, 6, 7], 5));
+    }
+
+    #[test]
+    fn decode_pin_list() {
+        // the list ends where the last pin is repeated
+        let encoding = BI_PINS_ENCODING_MULTI | 2 << 3 | 3 << 7 | 6 << 12 | 6 << 17;
+        assert_eq!(decode_pins(encoding, 0xF, 7, 5, 5), (vec![3, 6], 2));
+
+        // or after the most pins that fit
+        let encoding =
+            BI_PINS_ENCODING_MULTI | 3 << 3 | 1 << 7 | 2 << 12 | 3 << 17 | 4 << 22 | 5 << 27;
+        assert_eq!(
+            decode_pins(encoding, 0xF, 7, 5, 5),
+            (vec![1, 2, 3, 4, 5], 3)
+        );
+    }
+
+    #[test]
+    fn decode_pin_list_64() {
+        let encoding = BI_PINS_ENCODING_MULTI | 0x11 << 3 | 40 << 8 | 47 << 16 | 47 << 24;
+        assert_eq!(decode_pins(encoding, 0x1F, 8, 8, 7), (vec![40, 47], 0x11));
+    }
+
+    #[test]
+    fn parse_pins() {
+        let mut image = vec![0u8; 0x300];
+
+        put_u32(&mut image, 0x100, BINARY_INFO_MARKER_START);
+        put_u32(&mut image, 0x104, BASE + 0x200);
+        put_u32(&mut image, 0x108, BASE + 0x208);
+        put_u32(&mut image, 0x10C, 0);
+        put_u32(&mut image, 0x110, BINARY_INFO_MARKER_END);
+
+        put_u32(&mut image, 0x200, BASE + 0x240);
+        put_u32(&mut image, 0x204, BASE + 0x260);
+
+        put_u16(&mut image, 0x240, BINARY_INFO_TYPE_PINS_WITH_FUNC);
+        put_u16(&mut image, 0x242, BINARY_INFO_TAG_RASPBERRY_PI);
+        let encoding = BI_PINS_ENCODING_MULTI | 2 << 3 | 3 << 7 | 6 << 12 | 6 << 17;
+        put_u32(&mut image, 0x244, encoding as u32);
+
+        put_u16(&mut image, 0x260, BINARY_INFO_TYPE_PINS_WITH_NAME);
+        put_u16(&mut image, 0x262, BINARY_INFO_TAG_RASPBERRY_PI);
+        put_u32(&mut image, 0x264, 1 << 4 | 1 << 5);
+        put_u32(&mut image, 0x268, BASE + 0x280);
+        image[0x280..0x288].copy_from_slice(b"SDA|SCL\0");
+
+        let info = BinaryInfo::from_image(&image, BASE).unwrap();
+        assert_eq!(
+            info.get_pins(),
+            vec![
+                (3, PinUsage::Func(2)),
+                (4, PinUsage::Name("SDA".to_string())),
+                (5, PinUsage::Name("SCL".to_string())),
+                (6, PinUsage::Func(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_entries() {
+        let mut image = vec![0u8; 0x400];
+
+        put_u32(&mut image, 0x100, BINARY_INFO_MARKER_START);
+        put_u32(&mut image, 0x104, BASE + 0x200);
+        put_u32(&mut image, 0x108, BASE + 0x214);
+        put_u32(&mut image, 0x10C, BASE + 0x380);
+        put_u32(&mut image, 0x110, BINARY_INFO_MARKER_END);
+
+        for (i, entry) in [0x240, 0x250, 0x260, 0x280, 0x2A0].iter().enumerate() {
+            put_u32(&mut image, 0x200 + i * 4, BASE + entry);
+        }
+
+        put_u16(&mut image, 0x240, BINARY_INFO_TYPE_ID_AND_STRING);
+        put_u16(&mut image, 0x242, BINARY_INFO_TAG_RASPBERRY_PI);
+        put_u32(&mut image, 0x244, BINARY_INFO_ID_RP_PROGRAM_NAME);
+        put_u32(&mut image, 0x248, BASE + 0x300);
+        image[0x300..0x306].copy_from_slice(b"blink\0");
+
+        put_u16(&mut image, 0x250, BINARY_INFO_TYPE_ID_AND_INT);
+        put_u16(&mut image, 0x252, BINARY_INFO_TAG_RASPBERRY_PI);
+        put_u32(&mut image, 0x254, BINARY_INFO_ID_RP_BINARY_END);
+        put_u32(&mut image, 0x258, BASE + 0x400);
+
+        put_u16(&mut image, 0x260, BINARY_INFO_TYPE_NAMED_GROUP);
+        put_u16(&mut image, 0x262, BINARY_INFO_TAG_RASPBERRY_PI);
+        put_u32(&mut image, 0x264, BINARY_INFO_ID_RP_PROGRAM_FEATURE);
+        put_u16(&mut image, 0x268, 0x1);
+        put_u16(&mut image, 0x26A, 0x1234);
+        put_u32(&mut image, 0x26C, 0x5678);
+        put_u32(&mut image, 0x270, BASE + 0x310);
+        image[0x310..0x317].copy_from_slice(b"Config\0");
+
+        // the values of the pointer entries live in RAM, which the mapping
+        // table maps back to flash
+        put_u32(&mut image, 0x380, BASE + 0x3C0);
+        put_u32(&mut image, 0x384, 0x20000000);
+        put_u32(&mut image, 0x388, 0x20000010);
+
+        put_u16(&mut image, 0x280, BINARY_INFO_TYPE_PTR_INT32_WITH_NAME);
+        put_u16(&mut image, 0x282, 0x1234);
+        put_u32(&mut image, 0x284, 0x5678);
+        put_u32(&mut image, 0x288, 0x20000000);
+        put_u32(&mut image, 0x28C, BASE + 0x320);
+        image[0x320..0x327].copy_from_slice(b"Volume\0");
+        put_u32(&mut image, 0x3C0, -5i32 as u32);
+
+        put_u16(&mut image, 0x2A0, BINARY_INFO_TYPE_PTR_STRING_WITH_NAME);
+        put_u16(&mut image, 0x2A2, 0x1234);
+        put_u32(&mut image, 0x2A4, 0x5678);
+        put_u32(&mut image, 0x2A8, 0x20000004);
+        put_u32(&mut image, 0x2AC, BASE + 0x330);
+        image[0x330..0x335].copy_from_slice(b"SSID\0");
+        image[0x3C4..0x3CA].copy_from_slice(b"home!\0");
+
+        let info = BinaryInfo::from_image(&image, BASE).unwrap();
+        assert_eq!(info.get_program_name(), Some("blink"));
+        assert_eq!(info.get_binary_end(), Some(BASE + 0x400));
+        assert_eq!(
+            info.get_named_groups().collect::<Vec<_>>(),
+            vec![(0x1234, 0x5678, "Config")]
+        );
+        assert_eq!(
+            info.get_group_entries(0x1234, 0x5678).collect::<Vec<_>>(),
+            vec![
+                &BinaryInfoEntry::PtrInt32WithName {
+                    tag: 0x1234,
+                    id: 0x5678,
+                    label: "Volume".to_string(),
+                    value: -5,
+                },
+                &BinaryInfoEntry::PtrStringWithName {
+                    tag: 0x1234,
+                    id: 0x5678,
+                    label: "SSID".to_string(),
+                    value: "home!".to_string(),
+                },
+            ]
+        );
+    }
+}
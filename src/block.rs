@@ -0,0 +1,763 @@
+use crate::{
+    cmd::PicobootError,
+    memory::{Image, MemoryRead, PageCache},
+    partition::PartitionTable,
+};
+
+use serde::Serialize;
+
+// see https://datasheets.raspberrypi.com/rp2350/rp2350-datasheet.pdf
+// section 5.9 for details on metadata blocks
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// Marker word at the start of a block
+pub const PICOBIN_BLOCK_MARKER_START: u32 = 0xFFFFDED3;
+/// Marker word at the end of a block
+pub const PICOBIN_BLOCK_MARKER_END: u32 = 0xAB123579;
+/// The first block of a block loop must start within this many bytes of the
+/// start of the image
+pub const PICOBIN_MAX_BLOCK_SEARCH: u32 = 0x1000;
+
+const ITEM_PARTITION_TABLE: u8 = 0x0A;
+const ITEM_IMAGE_TYPE: u8 = 0x42;
+const ITEM_VECTOR_TABLE: u8 = 0x03;
+const ITEM_ENTRY_POINT: u8 = 0x44;
+const ITEM_ROLLING_WINDOW_DELTA: u8 = 0x05;
+const ITEM_LOAD_MAP: u8 = 0x06;
+const ITEM_VERSION: u8 = 0x48;
+const ITEM_HASH_DEF: u8 = 0x47;
+const ITEM_HASH_VALUE: u8 = 0x4B;
+const ITEM_SIGNATURE: u8 = 0x09;
+const ITEM_LAST: u8 = 0xFF;
+// top bit of the item type means the size is 2 bytes rather than 1
+const ITEM_2BS: u8 = 0x80;
+
+/// SHA-256 hash type for HASH_DEF items
+pub const PICOBIN_HASH_SHA256: u8 = 0x01;
+/// secp256k1 signature type for SIGNATURE items
+pub const PICOBIN_SIGNATURE_SECP256K1: u8 = 0x01;
+
+// guards against looping forever on a corrupt block loop
+const BLOCK_LOOP_MAX_BLOCKS: usize = 64;
+const BLOCK_MAX_WORDS: u32 = 0x400;
+
+/// Kind of image described by an IMAGE_DEF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageType {
+    /// Not a valid image.
+    Invalid,
+    /// An executable image.
+    Exe,
+    /// A data image.
+    Data,
+}
+
+/// Security mode an executable image runs in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExeSecurity {
+    /// Not specified.
+    Unspecified,
+    /// Non-Secure.
+    NonSecure,
+    /// Secure.
+    Secure,
+}
+
+/// CPU architecture an executable image runs on.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExeCpu {
+    /// ARM Cortex-M33.
+    Arm,
+    /// Hazard3 RISC-V.
+    Riscv,
+    /// Varmulet ARMv6-M emulator.
+    Varmulet,
+}
+
+/// Chip an executable image runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExeChip {
+    /// RP2040.
+    Rp2040,
+    /// RP2350.
+    Rp2350,
+}
+
+/// Contents of an IMAGE_TYPE item, which makes a block an IMAGE_DEF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageDef {
+    /// Kind of image.
+    pub image_type: ImageType,
+    /// Security mode, for executable images.
+    pub security: ExeSecurity,
+    /// CPU architecture, for executable images.
+    pub cpu: ExeCpu,
+    /// Chip, for executable images.
+    pub chip: ExeChip,
+    /// Image must be explicitly bought after booting, or the bootrom reverts.
+    pub try_before_you_buy: bool,
+}
+impl ImageDef {
+    /// Creates an IMAGE_DEF for an executable image.
+    pub fn exe(cpu: ExeCpu, security: ExeSecurity) -> Self {
+        ImageDef {
+            image_type: ImageType::Exe,
+            security,
+            cpu,
+            chip: ExeChip::Rp2350,
+            try_before_you_buy: false,
+        }
+    }
+
+    /// Decodes the image type flags.
+    pub fn from_flags(flags: u16) -> Self {
+        ImageDef {
+            image_type: match flags & 0xF {
+                1 => ImageType::Exe,
+                2 => ImageType::Data,
+                _ => ImageType::Invalid,
+            },
+            security: match (flags >> 4) & 0x3 {
+                1 => ExeSecurity::NonSecure,
+                2 => ExeSecurity::Secure,
+                _ => ExeSecurity::Unspecified,
+            },
+            cpu: match (flags >> 8) & 0x7 {
+                1 => ExeCpu::Riscv,
+                2 => ExeCpu::Varmulet,
+                _ => ExeCpu::Arm,
+            },
+            chip: match (flags >> 12) & 0x7 {
+                0 => ExeChip::Rp2040,
+                _ => ExeChip::Rp2350,
+            },
+            try_before_you_buy: flags & 0x8000 != 0,
+        }
+    }
+
+    /// Encodes the image type flags.
+    pub fn to_flags(&self) -> u16 {
+        let image_type = match self.image_type {
+            ImageType::Invalid => 0,
+            ImageType::Exe => 1,
+            ImageType::Data => 2,
+        };
+        let security = match self.security {
+            ExeSecurity::Unspecified => 0,
+            ExeSecurity::NonSecure => 1,
+            ExeSecurity::Secure => 2,
+        };
+        let cpu = match self.cpu {
+            ExeCpu::Arm => 0,
+            ExeCpu::Riscv => 1,
+            ExeCpu::Varmulet => 2,
+        };
+        let chip = match self.chip {
+            ExeChip::Rp2040 => 0,
+            ExeChip::Rp2350 => 1,
+        };
+
+        image_type
+            | security << 4
+            | cpu << 8
+            | chip << 12
+            | if self.try_before_you_buy { 0x8000 } else { 0 }
+    }
+}
+
+/// A single entry of a LOAD_MAP item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadMapEntry {
+    /// Where the data is stored. Relative to the address of the LOAD_MAP item
+    /// unless the load map is absolute. Zero means the runtime range is
+    /// filled with zeros instead of being copied.
+    pub storage_addr: u32,
+    /// Where the data is loaded to at runtime.
+    pub runtime_addr: u32,
+    /// Size of the data in bytes. Absolute load maps store the runtime end
+    /// address instead, the conversion happens when encoding and decoding.
+    pub size: u32,
+}
+
+/// A single item of a [`Block`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockItem {
+    /// IMAGE_TYPE item.
+    ImageDef(ImageDef),
+    /// VECTOR_TABLE item, with the address of the vector table.
+    VectorTable(u32),
+    /// ENTRY_POINT item.
+    EntryPoint {
+        pc: u32,
+        sp: u32,
+        sp_limit: Option<u32>,
+    },
+    /// ROLLING_WINDOW_DELTA item, with the offset of the flash window.
+    RollingWindowDelta(i32),
+    /// LOAD_MAP item.
+    LoadMap {
+        absolute: bool,
+        entries: Vec<LoadMapEntry>,
+    },
+    /// VERSION item, with the OTP rows used for rollback protection.
+    Version {
+        major: u16,
+        minor: u16,
+        rollback: Option<(u16, Vec<u16>)>,
+    },
+    /// HASH_DEF item.
+    HashDef {
+        hash_type: u8,
+        block_words_hashed: u16,
+    },
+    /// HASH_VALUE item.
+    HashValue(Vec<u8>),
+    /// SIGNATURE item, with the public key and signature.
+    Signature {
+        sig_type: u8,
+        public_key: [u8; 64],
+        signature: [u8; 64],
+    },
+    /// PARTITION_TABLE item.
+    PartitionTable(PartitionTable),
+    /// Any other item, including its header word.
+    Unknown(Vec<u32>),
+}
+impl BlockItem {
+    // every item written here has a 1 byte size, as the 2 byte form is only
+    // defined for the LAST item
+    fn header(item_type: u8, size: usize, b2: u8, b3: u8) -> Result<u32> {
+        if size > 0xFF {
+            return Err(Error::BlockItemTooLarge);
+        }
+        Ok(item_type as u32 | (size as u32) << 8 | (b2 as u32) << 16 | (b3 as u32) << 24)
+    }
+
+    fn from_words(words: &[u32]) -> Result<Self> {
+        let header = words[0];
+        let item_type = header as u8;
+        let b3 = (header >> 24) as u8;
+        let size = words.len();
+        let check = |ok: bool| {
+            if ok {
+                Ok(())
+            } else {
+                Err(Error::BlockInvalidItemSize)
+            }
+        };
+
+        let item = match item_type {
+            ITEM_IMAGE_TYPE => {
+                check(size == 1)?;
+                BlockItem::ImageDef(ImageDef::from_flags((header >> 16) as u16))
+            }
+            ITEM_VECTOR_TABLE => {
+                check(size == 2)?;
+                BlockItem::VectorTable(words[1])
+            }
+            ITEM_ENTRY_POINT => {
+                check(size == 3 || size == 4)?;
+                BlockItem::EntryPoint {
+                    pc: words[1],
+                    sp: words[2],
+                    sp_limit: words.get(3).copied(),
+                }
+            }
+            ITEM_ROLLING_WINDOW_DELTA => {
+                check(size == 2)?;
+                BlockItem::RollingWindowDelta(words[1] as i32)
+            }
+            ITEM_LOAD_MAP => {
+                let count = (b3 & 0x7F) as usize;
+                check(size == 1 + count * 3)?;
+                let absolute = b3 & 0x80 != 0;
+                BlockItem::LoadMap {
+                    absolute,
+                    entries: words[1..]
+                        .chunks_exact(3)
+                        .map(|e| LoadMapEntry {
+                            storage_addr: e[0],
+                            runtime_addr: e[1],
+                            size: if absolute {
+                                e[2].wrapping_sub(e[1])
+                            } else {
+                                e[2]
+                            },
+                        })
+                        .collect(),
+                }
+            }
+            ITEM_VERSION => {
+                let rows = b3 as usize;
+                let expected = if rows == 0 { 2 } else { 2 + (rows + 2) / 2 };
+                check(size == expected)?;
+
+                let rollback = if rows == 0 {
+                    None
+                } else {
+                    let halves: Vec<u16> = words[2..]
+                        .iter()
+                        .flat_map(|w| [*w as u16, (*w >> 16) as u16])
+                        .collect();
+                    Some((halves[0], halves[1..=rows].to_vec()))
+                };
+                BlockItem::Version {
+                    major: (words[1] >> 16) as u16,
+                    minor: words[1] as u16,
+                    rollback,
+                }
+            }
+            ITEM_HASH_DEF => {
+                check(size == 2)?;
+                BlockItem::HashDef {
+                    hash_type: b3,
+                    block_words_hashed: words[1] as u16,
+                }
+            }
+            ITEM_HASH_VALUE => BlockItem::HashValue(words_to_bytes(&words[1..])),
+            ITEM_SIGNATURE => {
+                check(size == 33)?;
+                let mut public_key = [0u8; 64];
+                let mut signature = [0u8; 64];
+                public_key.copy_from_slice(&words_to_bytes(&words[1..17]));
+                signature.copy_from_slice(&words_to_bytes(&words[17..33]));
+                BlockItem::Signature {
+                    sig_type: b3,
+                    public_key,
+                    signature,
+                }
+            }
+            ITEM_PARTITION_TABLE => BlockItem::PartitionTable(PartitionTable::from_words(words)?),
+            _ => BlockItem::Unknown(words.to_vec()),
+        };
+
+        Ok(item)
+    }
+
+    /// Encodes the item, including its header word.
+    ///
+    /// # Errors:
+    /// - [`Error::BlockItemTooLarge`]
+    pub fn to_words(&self) -> Result<Vec<u32>> {
+        let words = match self {
+            BlockItem::ImageDef(def) => {
+                vec![ITEM_IMAGE_TYPE as u32 | 1 << 8 | (def.to_flags() as u32) << 16]
+            }
+            BlockItem::VectorTable(addr) => vec![Self::header(ITEM_VECTOR_TABLE, 2, 0, 0)?, *addr],
+            BlockItem::EntryPoint { pc, sp, sp_limit } => {
+                let mut words = vec![0, *pc, *sp];
+                words.extend(sp_limit);
+                words[0] = Self::header(ITEM_ENTRY_POINT, words.len(), 0, 0)?;
+                words
+            }
+            BlockItem::RollingWindowDelta(delta) => {
+                vec![
+                    Self::header(ITEM_ROLLING_WINDOW_DELTA, 2, 0, 0)?,
+                    *delta as u32,
+                ]
+            }
+            BlockItem::LoadMap { absolute, entries } => {
+                let b3 = entries.len() as u8 & 0x7F | if *absolute { 0x80 } else { 0 };
+                let mut words = vec![Self::header(ITEM_LOAD_MAP, 1 + entries.len() * 3, 0, b3)?];
+                for e in entries {
+                    let end_or_size = if *absolute {
+                        e.runtime_addr.wrapping_add(e.size)
+                    } else {
+                        e.size
+                    };
+                    words.extend([e.storage_addr, e.runtime_addr, end_or_size]);
+                }
+                words
+            }
+            BlockItem::Version {
+                major,
+                minor,
+                rollback,
+            } => {
+                let mut words = vec![0, (*major as u32) << 16 | *minor as u32];
+                let mut rows = 0;
+                if let Some((version, otp_rows)) = rollback {
+                    rows = otp_rows.len();
+                    let mut halves = vec![*version];
+                    halves.extend(otp_rows);
+                    if halves.len() % 2 != 0 {
+                        halves.push(0);
+                    }
+                    words.extend(
+                        halves
+                            .chunks_exact(2)
+                            .map(|h| h[0] as u32 | (h[1] as u32) << 16),
+                    );
+                }
+                words[0] = Self::header(ITEM_VERSION, words.len(), 0, rows as u8)?;
+                words
+            }
+            BlockItem::HashDef {
+                hash_type,
+                block_words_hashed,
+            } => vec![
+                Self::header(ITEM_HASH_DEF, 2, 0, *hash_type)?,
+                *block_words_hashed as u32,
+            ],
+            BlockItem::HashValue(hash) => {
+                let mut words = bytes_to_words(hash);
+                words.insert(0, Self::header(ITEM_HASH_VALUE, 1 + words.len(), 0, 0)?);
+                words
+            }
+            BlockItem::Signature {
+                sig_type,
+                public_key,
+                signature,
+            } => {
+                let mut words = vec![Self::header(ITEM_SIGNATURE, 33, 0, *sig_type)?];
+                words.extend(bytes_to_words(public_key));
+                words.extend(bytes_to_words(signature));
+                words
+            }
+            BlockItem::PartitionTable(table) => {
+                let mut words = table.to_payload_words();
+                let count = table.partitions.len() as u8;
+                let header = Self::header(
+                    ITEM_PARTITION_TABLE,
+                    1 + words.len(),
+                    table.singleton as u8,
+                    count,
+                )?;
+                words.insert(0, header);
+                words
+            }
+            BlockItem::Unknown(words) => words.clone(),
+        };
+
+        Ok(words)
+    }
+}
+
+/// A metadata block, as found in RP2350 images and partition tables
+///
+/// Blocks hold a list of items and link to the next block, forming a loop
+/// which the bootrom walks to decide whether (and how) to boot an image.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Block {
+    /// Items of the block, excluding the LAST item.
+    pub items: Vec<BlockItem>,
+    /// Offset in bytes from the start of this block to the start of the next
+    /// block in the loop. Zero links the block to itself.
+    pub link: i32,
+}
+impl Block {
+    /// Decodes a block from its words, starting at the start marker.
+    ///
+    /// Returns the block and the number of words it occupies.
+    ///
+    /// # Errors:
+    /// - [`Error::BlockNotFound`]
+    /// - [`Error::BlockInvalidItemSize`]
+    pub fn from_words(words: &[u32]) -> Result<(Self, usize)> {
+        let bytes = words_to_bytes(words);
+        Self::read(&mut Image::new(0, &bytes), 0).map(|(block, size)| (block, size as usize / 4))
+    }
+
+    fn read<M: MemoryRead + ?Sized>(mem: &mut M, addr: u32) -> Result<(Self, u32)> {
+        let read = |mem: &mut M, offset: u32| {
+            let word_addr = addr
+                .checked_add(offset * 4)
+                .ok_or(Error::BlockInvalidItemSize)?;
+            match mem.read_u32(word_addr) {
+                Err(Error::MemoryOutOfRange) => Err(Error::BlockInvalidItemSize),
+                res => res,
+            }
+        };
+
+        if read(mem, 0)? != PICOBIN_BLOCK_MARKER_START {
+            return Err(Error::BlockNotFound);
+        }
+
+        let mut items = vec![];
+        let mut offset = 1;
+        loop {
+            let header = read(mem, offset)?;
+            let item_type = header as u8;
+            let size = if item_type & ITEM_2BS != 0 {
+                (header >> 8) & 0xFFFF
+            } else {
+                (header >> 8) & 0xFF
+            };
+
+            if item_type == ITEM_LAST {
+                // the LAST item holds the total size of the preceding items
+                if size != offset - 1 {
+                    return Err(Error::BlockInvalidItemSize);
+                }
+                break;
+            }
+            if size == 0 || offset + size > BLOCK_MAX_WORDS {
+                return Err(Error::BlockInvalidItemSize);
+            }
+
+            let words = (offset..offset + size)
+                .map(|i| read(mem, i))
+                .collect::<Result<Vec<_>>>()?;
+            items.push(BlockItem::from_words(&words)?);
+            offset += size;
+        }
+
+        let link = read(mem, offset + 1)? as i32;
+        if read(mem, offset + 2)? != PICOBIN_BLOCK_MARKER_END {
+            return Err(Error::BlockInvalidItemSize);
+        }
+
+        Ok((Block { items, link }, (offset + 3) * 4))
+    }
+
+    /// Encodes the block, including the start and end markers.
+    ///
+    /// # Errors:
+    /// - [`Error::BlockItemTooLarge`]
+    pub fn to_words(&self) -> Result<Vec<u32>> {
+        let mut words = vec![PICOBIN_BLOCK_MARKER_START];
+        for item in &self.items {
+            words.extend(item.to_words()?);
+        }
+        let items_size = words.len() as u32 - 1;
+        words.push(ITEM_LAST as u32 | items_size << 8);
+        words.push(self.link as u32);
+        words.push(PICOBIN_BLOCK_MARKER_END);
+        Ok(words)
+    }
+
+    /// Encodes the block as little-endian bytes.
+    ///
+    /// # Errors:
+    /// - [`Error::BlockItemTooLarge`]
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        self.to_words().map(|words| words_to_bytes(&words))
+    }
+
+    /// Returns the IMAGE_DEF of the block, if it is one.
+    pub fn get_image_def(&self) -> Option<&ImageDef> {
+        self.items.iter().find_map(|i| match i {
+            BlockItem::ImageDef(def) => Some(def),
+            _ => None,
+        })
+    }
+
+    /// Returns the partition table of the block, if it is one.
+    pub fn get_partition_table(&self) -> Option<&PartitionTable> {
+        self.items.iter().find_map(|i| match i {
+            BlockItem::PartitionTable(table) => Some(table),
+            _ => None,
+        })
+    }
+}
+
+/// A block located in memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocatedBlock {
+    /// Address of the start marker of the block.
+    pub addr: u32,
+    /// Size of the block in bytes.
+    pub size: u32,
+    /// The block itself.
+    pub block: Block,
+}
+
+/// A loop of linked blocks, starting at the first block in an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockLoop {
+    blocks: Vec<LocatedBlock>,
+}
+impl BlockLoop {
+    /// Finds and parses the block loop of an image.
+    ///
+    /// Searches for the first block within [`PICOBIN_MAX_BLOCK_SEARCH`] bytes
+    /// of `start`, then follows the links until they lead back to it. Every
+    /// block must lie within the memory being read.
+    ///
+    /// - `mem` - Memory to read from, either an [`Image`] or a connected device.
+    /// - `start` - Address the image starts at, e.g. [`PICO_FLASH_START`](crate::PICO_FLASH_START).
+    ///
+    /// # Errors:
+    /// - [`Error::BlockNotFound`]
+    /// - [`Error::BlockInvalidItemSize`]
+    /// - [`Error::BlockInvalidLink`]
+    /// - Any produced by `mem`
+    pub fn find<M: MemoryRead + ?Sized>(mem: &mut M, start: u32) -> Result<Self> {
+        let mut mem = PageCache::new(mem);
+
+        let mut first = None;
+        for addr in (start..start.saturating_add(PICOBIN_MAX_BLOCK_SEARCH)).step_by(4) {
+            match mem.read_u32(addr) {
+                Ok(PICOBIN_BLOCK_MARKER_START) => {}
+                Ok(_) => continue,
+                Err(Error::MemoryOutOfRange) => break,
+                Err(e) => return Err(e),
+            }
+
+            match Block::read(&mut mem, addr) {
+                Ok((block, size)) => {
+                    first = Some(LocatedBlock { addr, size, block });
+                    break;
+                }
+                Err(Error::BlockInvalidItemSize) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let first = first.ok_or(Error::BlockNotFound)?;
+        let first_addr = first.addr;
+        let mut blocks = vec![first];
+        loop {
+            let last = blocks.last().unwrap();
+            let next = (last.addr as i64 + last.block.link as i64) as u32;
+            if next == first_addr {
+                return Ok(BlockLoop { blocks });
+            }
+            if next % 4 != 0 || blocks.len() >= BLOCK_LOOP_MAX_BLOCKS {
+                return Err(Error::BlockInvalidLink);
+            }
+
+            let (block, size) = match Block::read(&mut mem, next) {
+                Ok(b) => b,
+                Err(Error::BlockNotFound) => return Err(Error::BlockInvalidLink),
+                Err(e) => return Err(e),
+            };
+            if blocks.iter().any(|b| b.addr == next) {
+                return Err(Error::BlockInvalidLink);
+            }
+            blocks.push(LocatedBlock {
+                addr: next,
+                size,
+                block,
+            });
+        }
+    }
+
+    /// Finds and parses the block loop of an image in host memory.
+    ///
+    /// # Errors:
+    /// - Any produced by [`Self::find`]
+    pub fn from_image(image: &[u8], start: u32) -> Result<Self> {
+        Self::find(&mut Image::new(start, image), start)
+    }
+
+    /// Returns the blocks of the loop, in link order from the first block.
+    pub fn get_blocks(&self) -> &[LocatedBlock] {
+        &self.blocks
+    }
+
+    /// Returns the IMAGE_DEF the bootrom would use, which is the last one in
+    /// the loop.
+    pub fn get_image_def(&self) -> Option<(&LocatedBlock, &ImageDef)> {
+        self.blocks
+            .iter()
+            .rev()
+            .find_map(|b| b.block.get_image_def().map(|def| (b, def)))
+    }
+
+    /// Returns the last partition table in the loop.
+    pub fn get_partition_table(&self) -> Option<(&LocatedBlock, &PartitionTable)> {
+        self.blocks
+            .iter()
+            .rev()
+            .find_map(|b| b.block.get_partition_table().map(|pt| (b, pt)))
+    }
+}
+
+pub(crate) fn words_to_bytes(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+pub(crate) fn bytes_to_words(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks(4)
+        .map(|c| {
+            let mut w = [0u8; 4];
+            w[..c.len()].copy_from_slice(c);
+            u32::from_le_bytes(w)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_def_flags_round_trip() {
+        // RP2350 ARM Secure executable, as the SDK emits by default
+        let def = ImageDef::from_flags(0x1021);
+        assert_eq!(def, ImageDef::exe(ExeCpu::Arm, ExeSecurity::Secure));
+        assert_eq!(def.to_flags(), 0x1021);
+
+        let def = ImageDef {
+            image_type: ImageType::Exe,
+            security: ExeSecurity::NonSecure,
+            cpu: ExeCpu::Riscv,
+            chip: ExeChip::Rp2350,
+            try_before_you_buy: true,
+        };
+        assert_eq!(def.to_flags(), 0x9111);
+        assert_eq!(ImageDef::from_flags(def.to_flags()), def);
+    }
+
+    #[test]
+    fn block_round_trip() {
+        let block = Block {
+            items: vec![
+                BlockItem::ImageDef(ImageDef::exe(ExeCpu::Arm, ExeSecurity::Secure)),
+                BlockItem::EntryPoint {
+                    pc: 0x10000101,
+                    sp: 0x20082000,
+                    sp_limit: Some(0x20080000),
+                },
+                BlockItem::LoadMap {
+                    absolute: true,
+                    entries: vec![LoadMapEntry {
+                        storage_addr: 0x10000000,
+                        runtime_addr: 0x20000000,
+                        size: 0x1000,
+                    }],
+                },
+                BlockItem::Version {
+                    major: 1,
+                    minor: 2,
+                    rollback: Some((3, vec![0x100, 0x101, 0x102])),
+                },
+                BlockItem::HashDef {
+                    hash_type: PICOBIN_HASH_SHA256,
+                    block_words_hashed: 12,
+                },
+                BlockItem::HashValue(vec![0xAA; 32]),
+            ],
+            link: -0x100,
+        };
+
+        let words = block.to_words().unwrap();
+        assert_eq!(words[0], PICOBIN_BLOCK_MARKER_START);
+        assert_eq!(words[words.len() - 1], PICOBIN_BLOCK_MARKER_END);
+        assert_eq!(Block::from_words(&words).unwrap(), (block, words.len()));
+    }
+
+    #[test]
+    fn find_at_end_of_address_space() {
+        // a start marker in the last word cannot begin a block
+        let mut image = [0xFF; 0x10];
+        image[0xC..].copy_from_slice(&PICOBIN_BLOCK_MARKER_START.to_le_bytes());
+        assert!(matches!(
+            BlockLoop::from_image(&image, u32::MAX - 0xF),
+            Err(Error::BlockNotFound)
+        ));
+    }
+
+    #[test]
+    fn item_too_large() {
+        let item = BlockItem::HashValue(vec![0; 255 * 4]);
+        assert!(matches!(item.to_words(), Err(Error::BlockItemTooLarge)));
+
+        let item = BlockItem::HashValue(vec![0; 254 * 4]);
+        assert_eq!(item.to_words().unwrap().len(), 255);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::cmd::PicobootError;
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// Size of the RP2040 second stage bootloader, including its checksum.
+pub const BOOT2_SIZE: usize = 0x100;
+/// Number of bytes of the second stage bootloader covered by its checksum.
+pub const BOOT2_CHECKSUM_OFFSET: usize = BOOT2_SIZE - 4;
+
+/// Flash chips with a known good RP2040 second stage bootloader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Boot2Flash {
+    /// Winbond W25Q080, used on the Raspberry Pi Pico.
+    W25Q080,
+    /// Winbond W25X10CL.
+    W25X10CL,
+    /// Adesto AT25SF128A.
+    AT25SF128A,
+    /// GigaDevice GD25Q64CS.
+    GD25Q64CS,
+    /// ISSI IS25LP080.
+    IS25LP080,
+    /// Any flash chip supporting the standard 03h read command. Slow, but
+    /// works almost everywhere.
+    Generic03H,
+}
+impl Boot2Flash {
+    /// Returns the second stage bootloader for this flash chip.
+    pub fn get_boot2(&self) -> &'static [u8; BOOT2_SIZE] {
+        match self {
+            Boot2Flash::W25Q080 => &rp2040_boot2::BOOT_LOADER_W25Q080,
+            Boot2Flash::W25X10CL => &rp2040_boot2::BOOT_LOADER_W25X10CL,
+            Boot2Flash::AT25SF128A => &rp2040_boot2::BOOT_LOADER_AT25SF128A,
+            Boot2Flash::GD25Q64CS => &rp2040_boot2::BOOT_LOADER_GD25Q64CS,
+            Boot2Flash::IS25LP080 => &rp2040_boot2::BOOT_LOADER_IS25LP080,
+            Boot2Flash::Generic03H => &rp2040_boot2::BOOT_LOADER_GENERIC_03H,
+        }
+    }
+}
+
+/// How to repair an RP2040 image whose second stage bootloader fails validation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Boot2Fixup {
+    /// Keep the existing boot2 code, but recompute its checksum.
+    RecomputeChecksum,
+    /// Replace boot2 entirely with a known good one for a flash chip.
+    Substitute(Boot2Flash),
+}
+
+/// Computes the checksum the RP2040 bootrom expects at the end of boot2.
+///
+/// This is CRC-32/MPEG-2: polynomial 0x04C11DB7, initial value 0xFFFFFFFF,
+/// no reflection and no final XOR.
+///
+/// - `data` - Data to compute the checksum of.
+pub fn boot2_checksum(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Checks the second stage bootloader at the start of an RP2040 image.
+///
+/// - `image` - Image that will be loaded at [`crate::PICO_FLASH_START`].
+///
+/// # Errors:
+/// - [`Error::Boot2Missing`]
+/// - [`Error::Boot2ChecksumMismatch`]
+pub fn check_boot2(image: &[u8]) -> Result<()> {
+    if image.len() < BOOT2_SIZE {
+        return Err(Error::Boot2Missing);
+    }
+
+    let found = u32::from_le_bytes([
+        image[BOOT2_CHECKSUM_OFFSET],
+        image[BOOT2_CHECKSUM_OFFSET + 1],
+        image[BOOT2_CHECKSUM_OFFSET + 2],
+        image[BOOT2_CHECKSUM_OFFSET + 3],
+    ]);
+    let expected = boot2_checksum(&image[..BOOT2_CHECKSUM_OFFSET]);
+    if found != expected {
+        return Err(Error::Boot2ChecksumMismatch { expected, found });
+    }
+
+    Ok(())
+}
+
+/// Repairs the second stage bootloader at the start of an RP2040 image.
+///
+/// - `image` - Image that will be loaded at [`crate::PICO_FLASH_START`].
+/// - `fixup` - How to repair it.
+///
+/// # Errors:
+/// - [`Error::Boot2Missing`]
+pub fn fix_boot2(image: &mut [u8], fixup: Boot2Fixup) -> Result<()> {
+    if image.len() < BOOT2_SIZE {
+        return Err(Error::Boot2Missing);
+    }
+
+    match fixup {
+        Boot2Fixup::RecomputeChecksum => {
+            let crc = boot2_checksum(&image[..BOOT2_CHECKSUM_OFFSET]);
+            image[BOOT2_CHECKSUM_OFFSET..BOOT2_SIZE].copy_from_slice(&crc.to_le_bytes());
+        }
+        Boot2Fixup::Substitute(flash) => {
+            image[..BOOT2_SIZE].copy_from_slice(flash.get_boot2());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLASHES: [Boot2Flash; 6] = [
+        Boot2Flash::W25Q080,
+        Boot2Flash::W25X10CL,
+        Boot2Flash::AT25SF128A,
+        Boot2Flash::GD25Q64CS,
+        Boot2Flash::IS25LP080,
+        Boot2Flash::Generic03H,
+    ];
+
+    #[test]
+    fn checksum_check_value() {
+        assert_eq!(boot2_checksum(b"123456789"), 0x0376E6E7);
+    }
+
+    #[test]
+    fn known_boot2_checksums() {
+        let boot2 = Boot2Flash::W25Q080.get_boot2();
+        let found = u32::from_le_bytes(boot2[BOOT2_CHECKSUM_OFFSET..].try_into().unwrap());
+        assert_eq!(boot2_checksum(&boot2[..BOOT2_CHECKSUM_OFFSET]), found);
+
+        for flash in FLASHES {
+            assert!(check_boot2(flash.get_boot2()).is_ok(), "{:?}", flash);
+        }
+    }
+
+    #[test]
+    fn fix_checksum() {
+        let mut image = Boot2Flash::W25Q080.get_boot2().to_vec();
+        image[0x10] ^= 0xFF;
+        assert!(matches!(
+            check_boot2(&image),
+            Err(Error::Boot2ChecksumMismatch { .. })
+        ));
+
+        fix_boot2(&mut image, Boot2Fixup::RecomputeChecksum).unwrap();
+        assert!(check_boot2(&image).is_ok());
+
+        fix_boot2(&mut image, Boot2Fixup::Substitute(Boot2Flash::Generic03H)).unwrap();
+        assert_eq!(&image[..], &Boot2Flash::Generic03H.get_boot2()[..]);
+        assert!(matches!(
+            check_boot2(&image[..0x80]),
+            Err(Error::Boot2Missing)
+        ));
+    }
+}
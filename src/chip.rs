@@ -0,0 +1,146 @@
+use crate::{
+    backend::UsbBackend,
+    cmd::{PicobootError, TargetID},
+    info::SYS_INFO_CHIP_INFO,
+    memory::MemoryRead,
+    usb::PicobootConnection,
+};
+
+use serde::Serialize;
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+// the bootrom of both chips starts its header with 'M', 'u', then a chip
+// specific byte, and the bootrom version
+const BOOTROM_MAGIC_ADDR: u32 = 0x10;
+const BOOTROM_MAGIC_RP2040: [u8; 3] = [b'M', b'u', 0x01];
+const BOOTROM_MAGIC_RP2350: [u8; 3] = [b'M', b'u', 0x02];
+
+const CHIP_INFO_PACKAGE_QFN60: u32 = 1 << 0;
+
+/// Silicon revision of a chip.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChipRevision {
+    /// RP2040 B0.
+    B0,
+    /// RP2040 B1.
+    B1,
+    /// RP2040 B2.
+    B2,
+    /// RP2350 A2.
+    A2,
+    /// RP2350 A3.
+    A3,
+    /// RP2350 A4.
+    A4,
+    /// Unrecognized bootrom version.
+    Unknown(u8),
+}
+impl ChipRevision {
+    /// Determines the revision of a chip from its bootrom version.
+    pub fn from_rom_version(target: TargetID, rom_version: u8) -> Self {
+        match (target, rom_version) {
+            (TargetID::Rp2040, 1) => ChipRevision::B0,
+            (TargetID::Rp2040, 2) => ChipRevision::B1,
+            (TargetID::Rp2040, 3) => ChipRevision::B2,
+            (TargetID::Rp2350, 2) => ChipRevision::A2,
+            (TargetID::Rp2350, 3) => ChipRevision::A3,
+            (TargetID::Rp2350, 4) => ChipRevision::A4,
+            (_, v) => ChipRevision::Unknown(v),
+        }
+    }
+}
+
+/// Package of a chip.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChipPackage {
+    /// RP2040, QFN-56.
+    Rp2040,
+    /// RP2350A, QFN-60 with 30 GPIOs.
+    Rp2350A,
+    /// RP2350B, QFN-80 with 48 GPIOs.
+    Rp2350B,
+}
+
+/// Chip identification read from the device itself.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChipInfo {
+    /// Chip family.
+    pub target: TargetID,
+    /// Bootrom version.
+    pub rom_version: u8,
+    /// Silicon revision.
+    pub revision: ChipRevision,
+    /// Package, if known.
+    pub package: Option<ChipPackage>,
+    /// Unique device ID, if known.
+    pub device_id: Option<u32>,
+    /// Wafer ID, if known.
+    pub wafer_id: Option<u32>,
+}
+
+impl<T: UsbBackend> PicobootConnection<T> {
+    /// Reads the chip family and bootrom version from the bootrom header.
+    ///
+    /// # Errors:
+    /// - [`Error::ChipUnknown`]
+    /// - Any produced by [`Self::flash_read`]
+    pub fn read_bootrom_version(&mut self) -> Result<(TargetID, u8)> {
+        let header = self.read_memory(BOOTROM_MAGIC_ADDR, 4)?;
+        let target = match &header[..3] {
+            m if m == BOOTROM_MAGIC_RP2040 => TargetID::Rp2040,
+            m if m == BOOTROM_MAGIC_RP2350 => TargetID::Rp2350,
+            _ => return Err(Error::ChipUnknown),
+        };
+
+        Ok((target, header[3]))
+    }
+
+    /// Identifies the chip by inspecting its bootrom.
+    ///
+    /// The family and revision come from the bootrom header. On the RP2350,
+    /// the package and IDs come from GET_INFO.
+    ///
+    /// The family read from the bootrom replaces the one guessed from the
+    /// VID/PID pair when connecting, see [`Self::get_device_type`].
+    ///
+    /// # Errors:
+    /// - Any produced by [`Self::read_bootrom_version`] or [`Self::get_info`]
+    pub fn get_chip_info(&mut self) -> Result<ChipInfo> {
+        let (target, rom_version) = self.read_bootrom_version()?;
+        self.set_device_type(target);
+
+        let mut info = ChipInfo {
+            target,
+            rom_version,
+            revision: ChipRevision::from_rom_version(target, rom_version),
+            package: None,
+            device_id: None,
+            wafer_id: None,
+        };
+
+        match target {
+            TargetID::Rp2040 => info.package = Some(ChipPackage::Rp2040),
+            TargetID::Rp2350 => {
+                let words = self.get_sys_info(SYS_INFO_CHIP_INFO)?;
+                match words[..] {
+                    [included, package, device_id, wafer_id, ..]
+                        if included & SYS_INFO_CHIP_INFO != 0 =>
+                    {
+                        info.package = Some(if package & CHIP_INFO_PACKAGE_QFN60 != 0 {
+                            ChipPackage::Rp2350A
+                        } else {
+                            ChipPackage::Rp2350B
+                        });
+                        info.device_id = Some(device_id);
+                        info.wafer_id = Some(wafer_id);
+                    }
+                    _ => return Err(Error::InfoMalformed),
+                }
+            }
+        }
+
+        Ok(info)
+    }
+}
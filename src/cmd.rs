@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::PICOBOOT_MAGIC;
+use crate::{backend::UsbError, PICOBOOT_MAGIC};
 
 /// Error type for this crate.
 #[derive(Error, Debug)]
@@ -9,6 +9,12 @@ pub enum PicobootError {
     /// USB device not found.
     #[error("usb device not found")]
     UsbDeviceNotFound,
+    /// Failed to open USB device.
+    #[error("failed to open usb device: {0}")]
+    UsbOpenFailure(UsbError),
+    /// Failed to read USB device descriptor.
+    #[error("failed to read usb device descriptor: {0}")]
+    UsbGetDescriptorFailure(UsbError),
     /// Failed to get USB bulk endpoints.
     #[error("failed to get usb bulk endpoints")]
     UsbEndpointsNotFound,
@@ -17,39 +23,50 @@ pub enum PicobootError {
     UsbEndpointsUnexpected,
     /// Failed to detach USB kernel driver.
     #[error("failed to detach usb kernel driver: {0}")]
-    UsbDetachKernelDriverFailure(rusb::Error),
+    UsbDetachKernelDriverFailure(UsbError),
     /// Failed to claim USB interface.
     #[error("failed to claim usb interface: {0}")]
-    UsbClaimInterfaceFailure(rusb::Error),
+    UsbClaimInterfaceFailure(UsbError),
     /// Failed to configure alt USB setting.
     #[error("failed to set alt usb setting: {0}")]
-    UsbSetAltSettingFailure(rusb::Error),
+    UsbSetAltSettingFailure(UsbError),
     /// Failed to read from USB bulk endpoint.
     #[error("failed to read bulk: {0}")]
-    UsbReadBulkFailure(rusb::Error),
+    UsbReadBulkFailure(UsbError),
     /// Read data from USB does not match expected size.
     #[error("read did not match expected size")]
     UsbReadBulkMismatch,
     /// Failed to write to USB bulk endpoint.
     #[error("failed to write bulk: {0}")]
-    UsbWriteBulkFailure(rusb::Error),
+    UsbWriteBulkFailure(UsbError),
     /// Written data to USB does not match expected size.
     #[error("write did not match expected size")]
     UsbWriteBulkMismatch,
 
     /// Failed to clear USB in address halt.
     #[error("failed to clear in addr halt: {0}")]
-    UsbClearInAddrHalt(rusb::Error),
+    UsbClearInAddrHalt(UsbError),
     /// Failed to clear USB out address halt.
     #[error("failed to clear out addr halt: {0}")]
-    UsbClearOutAddrHalt(rusb::Error),
+    UsbClearOutAddrHalt(UsbError),
     /// Failed to reset USB interface.
     #[error("failed to reset interface: {0}")]
-    UsbResetInterfaceFailure(rusb::Error),
+    UsbResetInterfaceFailure(UsbError),
+
+    /// Failed to request a reboot into BOOTSEL mode through the reset interface.
+    #[error("failed to request reboot into bootsel: {0}")]
+    UsbBootselRequestFailure(UsbError),
+    /// BOOTSEL options disable the PICOBOOT interface that is to be connected to.
+    #[error("bootsel options disable the picoboot interface")]
+    UsbBootselPicobootDisabled,
+
+    /// Timed out waiting for the USB device to re-enumerate.
+    #[error("timed out waiting for usb device to reconnect")]
+    UsbReconnectTimeout,
 
     /// Failed to get command status from device.
     #[error("failed to get command status: {0}")]
-    UsbGetCommandStatusFailure(rusb::Error),
+    UsbGetCommandStatusFailure(UsbError),
 
     /// Failed to serialize command for device.
     #[error("cmd failed to binary serialize: {0}")]
@@ -62,23 +79,131 @@ pub enum PicobootError {
     #[error("cmd not allowed for target device")]
     CmdNotAllowedForTarget,
 
+    /// Device reported a command failure.
+    #[error("cmd failed with status {0}")]
+    CmdStatusFailure(u32),
+
     /// Erase command address invalid.
     #[error("erase address invalid")]
     EraseInvalidAddr,
     /// Erase command size invalid.
     #[error("erase size invalid")]
     EraseInvalidSize,
+    /// Flash still holds data after being erased.
+    #[error("flash not blank after erase at address {0:#010x}")]
+    EraseNotBlank(u32),
+    /// Blank check would stop before finding any non-blank bytes.
+    #[error("blank check limit must be at least 1")]
+    BlankCheckInvalidLimit,
 
     /// Write command address invalid.
     #[error("write address invalid")]
     WriteInvalidAddr,
+
+    /// Reading from or writing to a caller provided stream failed.
+    #[error("stream io failed: {0}")]
+    StreamIoFailure(std::io::Error),
+
+    /// Image is too short to contain an RP2040 second stage bootloader.
+    #[error("image too short to contain boot2")]
+    Boot2Missing,
+    /// Checksum at the end of the RP2040 second stage bootloader is wrong.
+    #[error("boot2 checksum mismatch: expected {expected:#010x}, found {found:#010x}")]
+    Boot2ChecksumMismatch { expected: u32, found: u32 },
+    /// Image would not be booted by the bootrom.
+    #[error("image not bootable: {0}")]
+    ImageNotBootable(&'static str),
+    /// Data read back after loading does not match the image.
+    #[error("verify failed at address {0:#010x}")]
+    LoadVerifyMismatch(u32),
+
+    /// Signing key could not be loaded or used.
+    #[error("signing key invalid")]
+    SealKeyInvalid,
+    /// Image has no hash or signature.
+    #[error("image is not sealed")]
+    SealNotFound,
+    /// Image cannot be sealed or verified.
+    #[error("image seal invalid: {0}")]
+    SealInvalid(&'static str),
+    /// Image hash or signature does not match its contents.
+    #[error("image hash or signature mismatch")]
+    SealMismatch,
+
+    /// OTP row is already programmed with a value that cannot be changed to
+    /// the requested one.
+    #[error("otp row {0:#05x} already programmed with a conflicting value")]
+    OtpConflict(u16),
+    /// OTP contents changed since the plan was made.
+    #[error("otp changed since the plan was made")]
+    OtpPlanStale,
+    /// Confirmation does not match the OTP plan.
+    #[error("confirmation does not match the otp plan")]
+    OtpConfirmationMismatch,
+    /// OTP row read back after writing does not match.
+    #[error("otp verify failed at row {0:#05x}")]
+    OtpVerifyMismatch(u16),
+    /// OTP configuration cannot be provisioned.
+    #[error("otp configuration invalid: {0}")]
+    OtpInvalidConfig(&'static str),
+
+    /// Bootrom does not belong to a known chip.
+    #[error("bootrom not recognized")]
+    ChipUnknown,
+
+    /// GET_INFO response is malformed.
+    #[error("get info response malformed")]
+    InfoMalformed,
+
+    /// No partition table found.
+    #[error("partition table not found")]
+    PartitionTableNotFound,
+    /// No partition matches the target.
+    #[error("partition not found")]
+    PartitionNotFound,
+    /// Partition permissions do not allow the access from BOOTSEL mode.
+    #[error("partition permissions deny access")]
+    PartitionPermissionDenied,
+    /// Image does not fit in the partition.
+    #[error("image does not fit in partition")]
+    PartitionOverflow,
+    /// Partition is not part of an A/B pair.
+    #[error("partition not part of an a/b pair")]
+    PartitionNotAbPair,
+    /// Partition table would be rejected by the bootrom.
+    #[error("partition table invalid: {0}")]
+    PartitionTableInvalid(&'static str),
+
+    /// Address is outside of the memory being read.
+    #[error("address out of range of memory")]
+    MemoryOutOfRange,
+
+    /// Metadata block not found in image.
+    #[error("block not found")]
+    BlockNotFound,
+    /// Metadata block item has an invalid size.
+    #[error("block item size invalid")]
+    BlockInvalidItemSize,
+    /// Metadata block item is too large for the size field of its header.
+    #[error("block item too large")]
+    BlockItemTooLarge,
+    /// Metadata block link does not lead to another block.
+    #[error("block link invalid")]
+    BlockInvalidLink,
+
+    /// Binary info header not found in image.
+    #[error("binary info not found")]
+    BinaryInfoNotFound,
+    /// Binary info in image is malformed.
+    #[error("binary info malformed")]
+    BinaryInfoMalformed,
 }
 
 // see https://datasheets.raspberrypi.com/rp2040/rp2040-datasheet.pdf
 // section 2.8.5 for details on PICOBOOT interface
 
 /// The type of microcontroller detected as the PICOBOOT device.
-#[derive(Debug, Clone, Copy)]
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TargetID {
     /// RP2040 MCU target.
     Rp2040,
@@ -192,8 +317,8 @@ struct PicobootRangeCmd {
 impl PicobootRangeCmd {
     pub fn ser(addr: u32, size: u32) -> [u8; 16] {
         let c = PicobootRangeCmd {
-            addr: addr,
-            size: size,
+            addr,
+            size,
             _unused: 0,
         };
         bincode::serialize(&c)
@@ -205,6 +330,31 @@ impl PicobootRangeCmd {
     }
 }
 
+#[derive(Serialize, Debug, Clone)]
+#[repr(C, packed)]
+struct PicobootOtpCmd {
+    row: u16,
+    row_count: u16,
+    ecc: u8,
+    _unused: [u8; 11],
+}
+impl PicobootOtpCmd {
+    pub fn ser(row: u16, row_count: u16, ecc: bool) -> [u8; 16] {
+        let c = PicobootOtpCmd {
+            row,
+            row_count,
+            ecc: ecc as u8,
+            _unused: [0; 11],
+        };
+        bincode::serialize(&c)
+            .unwrap()
+            .try_into()
+            .unwrap_or_else(|v: Vec<u8>| {
+                panic!("Expected a Vec of length {} but it was {}", 16, v.len())
+            })
+    }
+}
+
 #[derive(Serialize, Debug, Clone)]
 #[repr(C, packed)]
 struct PicobootRebootCmd {
@@ -216,9 +366,9 @@ struct PicobootRebootCmd {
 impl PicobootRebootCmd {
     pub fn ser(pc: u32, sp: u32, delay: u32) -> [u8; 16] {
         let c = PicobootRebootCmd {
-            pc: pc,
-            sp: sp,
-            delay: delay,
+            pc,
+            sp,
+            delay,
             _unused: 0,
         };
         bincode::serialize(&c)
@@ -255,6 +405,31 @@ impl PicobootReboot2Cmd {
     }
 }
 
+#[derive(Serialize, Debug, Clone)]
+#[repr(C, packed)]
+struct PicobootGetInfoCmd {
+    info_type: u8,
+    param: u8,
+    wparam: u16,
+    dparams: [u32; 3],
+}
+impl PicobootGetInfoCmd {
+    pub fn ser(info_type: u8, param: u8, wparam: u16, dparams: [u32; 3]) -> [u8; 16] {
+        let c = PicobootGetInfoCmd {
+            info_type,
+            param,
+            wparam,
+            dparams,
+        };
+        bincode::serialize(&c)
+            .unwrap()
+            .try_into()
+            .unwrap_or_else(|v: Vec<u8>| {
+                panic!("Expected a Vec of length {} but it was {}", 16, v.len())
+            })
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 #[repr(C, packed)]
 pub struct PicobootStatusCmd {
@@ -280,6 +455,18 @@ impl PicobootStatusCmd {
     pub fn get_in_progress(&self) -> u8 {
         self.in_progress
     }
+
+    /// Fails unless the device reported the command as successful so far.
+    ///
+    /// # Errors:
+    /// - [`PicobootError::CmdStatusFailure`]
+    pub fn check(&self) -> Result<(), PicobootError> {
+        if self.status_code == PicobootStatus::Ok as u32 {
+            Ok(())
+        } else {
+            Err(PicobootError::CmdStatusFailure(self.status_code))
+        }
+    }
 }
 
 /// Command structure for PICOBOOT interface.
@@ -311,10 +498,10 @@ impl PicobootCmd {
             magic: PICOBOOT_MAGIC,
             token: 0,
             cmd_id: cmd_id as u8,
-            cmd_size: cmd_size,
+            cmd_size,
             _unused: 0,
-            transfer_len: transfer_len,
-            args: args,
+            transfer_len,
+            args,
         }
     }
 
@@ -351,6 +538,21 @@ impl PicobootCmd {
         PicobootCmd::new(PicobootCmdId::Reboot2, 0x10, 0, args)
     }
 
+    /// Creates a REBOOT2 command that reboots into BOOTSEL mode
+    pub fn reboot2_bootsel(delay: u32) -> Self {
+        let flags: u32 = 0x2; // BOOTSEL
+        let args = PicobootReboot2Cmd::ser(flags, delay, 0, 0);
+        PicobootCmd::new(PicobootCmdId::Reboot2, 0x10, 0, args)
+    }
+
+    /// Creates a REBOOT2 command that boots the updated flash region at `addr`
+    /// in flash update mode
+    pub fn reboot2_flash_update(addr: u32, delay: u32) -> Self {
+        let flags: u32 = 0x4; // FLASH_UPDATE
+        let args = PicobootReboot2Cmd::ser(flags, delay, addr, 0);
+        PicobootCmd::new(PicobootCmdId::Reboot2, 0x10, 0, args)
+    }
+
     /// Creates a FLASH_ERASE command
     pub fn flash_erase(addr: u32, size: u32) -> Self {
         let args = PicobootRangeCmd::ser(addr, size);
@@ -369,6 +571,32 @@ impl PicobootCmd {
         PicobootCmd::new(PicobootCmdId::Read, 8, size, args)
     }
 
+    /// Creates a GET_INFO command
+    pub fn get_info(
+        info_type: u8,
+        param: u8,
+        wparam: u16,
+        dparams: [u32; 3],
+        transfer_len: u32,
+    ) -> Self {
+        let args = PicobootGetInfoCmd::ser(info_type, param, wparam, dparams);
+        PicobootCmd::new(PicobootCmdId::GetInfo, 0x10, transfer_len, args)
+    }
+
+    /// Creates an OTP_READ command
+    pub fn otp_read(row: u16, row_count: u16, ecc: bool) -> Self {
+        let args = PicobootOtpCmd::ser(row, row_count, ecc);
+        let size = row_count as u32 * if ecc { 2 } else { 4 };
+        PicobootCmd::new(PicobootCmdId::OtpRead, 5, size, args)
+    }
+
+    /// Creates an OTP_WRITE command
+    pub fn otp_write(row: u16, row_count: u16, ecc: bool) -> Self {
+        let args = PicobootOtpCmd::ser(row, row_count, ecc);
+        let size = row_count as u32 * if ecc { 2 } else { 4 };
+        PicobootCmd::new(PicobootCmdId::OtpWrite, 5, size, args)
+    }
+
     /// Creates an ENTER_XIP command
     pub fn enter_xip() -> Self {
         PicobootCmd::new(PicobootCmdId::EnterCmdXip, 0, 0, [0; 16])
@@ -378,4 +606,11 @@ impl PicobootCmd {
     pub fn exit_xip() -> Self {
         PicobootCmd::new(PicobootCmdId::ExitXip, 0, 0, [0; 16])
     }
+
+    /// Creates an EXEC command
+    pub fn exec(addr: u32) -> Self {
+        // only the address of the range is sent
+        let args = PicobootRangeCmd::ser(addr, 0);
+        PicobootCmd::new(PicobootCmdId::Exec, 4, 0, args)
+    }
 }
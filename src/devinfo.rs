@@ -0,0 +1,313 @@
+use crate::{
+    backend::{sealed::Handle, UsbBackend},
+    bininfo::BinaryInfo,
+    block::ExeCpu,
+    chip::ChipInfo,
+    cmd::{PicobootError, TargetID},
+    info::{BootInfo, SYS_INFO_CPU_INFO, SYS_INFO_CRITICAL},
+    otp::{
+        OTP_BOOTKEY_COUNT, OTP_BOOT_FLAGS1_COPIES, OTP_BOOT_FLAGS1_ROW, OTP_CRIT1_DEBUG_DISABLE,
+        OTP_CRIT1_SECURE_BOOT_ENABLE, OTP_CRIT1_SECURE_DEBUG_DISABLE,
+    },
+    partition::PartitionLink,
+    usb::PicobootConnection,
+    PICO_FLASH_START,
+};
+
+use serde::Serialize;
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+const CPU_INFO_RISCV: u32 = 1;
+
+const FLASH_CMD_READ_JEDEC_ID: u8 = 0x9F;
+const FLASH_CMD_READ_UNIQUE_ID: u8 = 0x4B;
+const FLASH_UNIQUE_ID_DUMMY_BYTES: usize = 4;
+
+/// USB descriptor information of a device.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct UsbInfo {
+    /// Vendor ID.
+    pub vendor_id: u16,
+    /// Product ID.
+    pub product_id: u16,
+    /// Device release number, as `major.minor.sub_minor`.
+    pub device_version: String,
+    /// Manufacturer string, if any.
+    pub manufacturer: Option<String>,
+    /// Product string, if any.
+    pub product: Option<String>,
+    /// Serial number string, if any.
+    pub serial_number: Option<String>,
+    /// Number of the bus the device is connected to.
+    pub bus: u8,
+    /// Chain of hub port numbers leading to the device.
+    pub ports: Vec<u8>,
+}
+
+/// Secure boot and debug lock state of an RP2350.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SecurityInfo {
+    /// Only signed images boot.
+    pub secure_boot: bool,
+    /// Debug access to Secure code is disabled.
+    pub secure_debug_disabled: bool,
+    /// All debug access is disabled.
+    pub debug_disabled: bool,
+    /// Boot key slots marked valid, one bit per slot. `None` if the OTP rows
+    /// could not be read.
+    pub boot_keys_valid: Option<u8>,
+}
+
+/// Summary of one partition of the partition table.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartitionSummary {
+    /// Index of the partition in the table.
+    pub index: u8,
+    /// Name of the partition, if any.
+    pub name: Option<String>,
+    /// Offset of the partition from the start of flash in bytes.
+    pub offset: u32,
+    /// Size of the partition in bytes.
+    pub size: u32,
+    /// Raw access permission bits, see [`Permissions`](crate::partition::Permissions).
+    pub permissions: u8,
+    /// Index of the A partition, if this is the B partition of an A/B pair.
+    pub a_partition: Option<u8>,
+}
+
+/// Summary of the binary info of the program installed in flash.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProgramInfo {
+    /// Program name.
+    pub name: Option<String>,
+    /// Program version string.
+    pub version: Option<String>,
+    /// Program build date string.
+    pub build_date: Option<String>,
+    /// Program URL.
+    pub url: Option<String>,
+    /// Program description.
+    pub description: Option<String>,
+    /// Pico SDK version the program was built with.
+    pub sdk_version: Option<String>,
+    /// Board the program was built for.
+    pub board: Option<String>,
+    /// Name of the second stage bootloader. (Only for RP2040)
+    pub boot2_name: Option<String>,
+    /// Address of the end of the binary.
+    pub binary_end: Option<u32>,
+    /// Program features.
+    pub features: Vec<String>,
+    /// Program build attributes.
+    pub build_attributes: Vec<String>,
+}
+impl ProgramInfo {
+    /// Summarizes parsed binary info.
+    pub fn from_binary_info(info: &BinaryInfo) -> Self {
+        let owned = |s: Option<&str>| s.map(str::to_owned);
+        ProgramInfo {
+            name: owned(info.get_program_name()),
+            version: owned(info.get_program_version()),
+            build_date: owned(info.get_build_date()),
+            url: owned(info.get_program_url()),
+            description: owned(info.get_program_description()),
+            sdk_version: owned(info.get_sdk_version()),
+            board: owned(info.get_pico_board()),
+            boot2_name: owned(info.get_boot2_name()),
+            binary_end: info.get_binary_end(),
+            features: info.get_features().map(str::to_owned).collect(),
+            build_attributes: info.get_build_attributes().map(str::to_owned).collect(),
+        }
+    }
+}
+
+/// Everything that can be learned about a device in BOOTSEL mode, see
+/// [`PicobootConnection::device_info`].
+///
+/// Fields are `None` when the chip does not support the query, or the query
+/// failed.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// Chip family, revision and bootrom version.
+    pub chip: ChipInfo,
+    /// USB descriptor information.
+    pub usb: UsbInfo,
+    /// Size in bytes of the flash on chip select 0. (Only for RP2350)
+    pub flash_size: Option<u32>,
+    /// JEDEC ID of the flash: manufacturer, memory type and capacity bytes.
+    /// (Only for RP2040)
+    pub flash_jedec_id: Option<u32>,
+    /// Unique ID of the flash. (Only for RP2040)
+    pub flash_unique_id: Option<u64>,
+    /// Secure boot and debug lock state. (Only for RP2350)
+    pub security: Option<SecurityInfo>,
+    /// Architecture the bootrom is running on. (Only for RP2350)
+    pub cpu: Option<ExeCpu>,
+    /// Diagnostics about the most recent boot. (Only for RP2350)
+    pub boot_info: Option<BootInfo>,
+    /// Partitions of the partition table. (Only for RP2350)
+    pub partitions: Option<Vec<PartitionSummary>>,
+    /// Binary info of the program installed in flash.
+    pub program: Option<ProgramInfo>,
+}
+
+impl<T: UsbBackend> PicobootConnection<T> {
+    /// Gathers everything that can be learned about the device, for example
+    /// to attach to a bug report.
+    ///
+    /// Only identifying the chip must succeed; anything else that fails is
+    /// left out of the report.
+    ///
+    /// On the RP2040 the flash ID queries run a code stub, which overwrites
+    /// the start of SRAM and takes the flash out of XIP mode. XIP mode is
+    /// entered again afterwards.
+    ///
+    /// # Errors:
+    /// - Any produced by [`Self::get_chip_info`]
+    pub fn device_info(&mut self) -> Result<DeviceInfo> {
+        let chip = self.get_chip_info()?;
+        let usb = self.read_usb_info();
+        let program = BinaryInfo::parse(self, PICO_FLASH_START)
+            .ok()
+            .map(|info| ProgramInfo::from_binary_info(&info));
+
+        // the GET_INFO and OTP queries fail on the RP2040, and the flash ID
+        // queries on the RP2350
+        let flash_size = self.get_flash_size().ok();
+        let flash_jedec_id = self.read_flash_jedec_id().ok();
+        let flash_unique_id = self.read_flash_unique_id().ok();
+        if let TargetID::Rp2040 = self.get_device_type() {
+            // best effort, the report is complete either way
+            let _ = self.enter_xip();
+        }
+        let security = self.read_security_info().ok();
+        let cpu = self.read_cpu().ok();
+        let boot_info = self.get_boot_info().ok();
+        let partitions = self.get_partition_table().ok().map(|table| {
+            table
+                .partitions
+                .iter()
+                .enumerate()
+                .map(|(i, p)| PartitionSummary {
+                    index: i as u8,
+                    name: p.name.clone(),
+                    offset: p.get_offset(),
+                    size: p.get_size(),
+                    permissions: p.permissions.get_bits(),
+                    a_partition: match p.link {
+                        PartitionLink::APartition(a) => Some(a),
+                        _ => None,
+                    },
+                })
+                .collect()
+        });
+
+        Ok(DeviceInfo {
+            chip,
+            usb,
+            flash_size,
+            flash_jedec_id,
+            flash_unique_id,
+            security,
+            cpu,
+            boot_info,
+            partitions,
+            program,
+        })
+    }
+
+    /// Reads the JEDEC ID of the flash. (Only for RP2040)
+    ///
+    /// Returns the manufacturer ID in bits 16-23, followed by the memory type
+    /// and capacity bytes.
+    ///
+    /// # Errors:
+    /// - [`Error::UsbReadBulkMismatch`]
+    /// - Any produced by [`Self::flash_spi_transfer`]
+    pub fn read_flash_jedec_id(&mut self) -> Result<u32> {
+        let rx = self.flash_spi_transfer(&[FLASH_CMD_READ_JEDEC_ID, 0, 0, 0])?;
+        match rx[..] {
+            [_, manufacturer, memory_type, capacity] => {
+                Ok(u32::from_be_bytes([0, manufacturer, memory_type, capacity]))
+            }
+            _ => Err(Error::UsbReadBulkMismatch),
+        }
+    }
+
+    /// Reads the 64-bit unique ID of the flash. (Only for RP2040)
+    ///
+    /// # Errors:
+    /// - [`Error::UsbReadBulkMismatch`]
+    /// - Any produced by [`Self::flash_spi_transfer`]
+    pub fn read_flash_unique_id(&mut self) -> Result<u64> {
+        let mut tx = [0u8; 1 + FLASH_UNIQUE_ID_DUMMY_BYTES + 8];
+        tx[0] = FLASH_CMD_READ_UNIQUE_ID;
+        let rx = self.flash_spi_transfer(&tx)?;
+
+        let id = rx
+            .get(1 + FLASH_UNIQUE_ID_DUMMY_BYTES..)
+            .and_then(|id| id.try_into().ok())
+            .ok_or(Error::UsbReadBulkMismatch)?;
+        Ok(u64::from_be_bytes(id))
+    }
+
+    // reads the device descriptor strings, leaving out any that fail
+    fn read_usb_info(&self) -> UsbInfo {
+        let (handle, entry) = self.get_usb_device();
+        let version = entry.device_version;
+        let identity = self.get_identity();
+
+        UsbInfo {
+            vendor_id: entry.vendor_id,
+            product_id: entry.product_id,
+            // the release number is BCD encoded as major.minor.sub_minor
+            device_version: format!(
+                "{}.{}.{}",
+                (version >> 12) * 10 + ((version >> 8) & 0xF),
+                (version >> 4) & 0xF,
+                version & 0xF
+            ),
+            manufacturer: handle.read_manufacturer_string(),
+            product: handle.read_product_string(),
+            serial_number: identity.get_serial_number().map(str::to_owned),
+            bus: identity.get_bus_number(),
+            ports: identity.get_port_numbers().to_vec(),
+        }
+    }
+
+    // reads the critical flags as the bootrom sees them, and the boot key
+    // valid flags from OTP
+    fn read_security_info(&mut self) -> Result<SecurityInfo> {
+        let words = self.get_sys_info(SYS_INFO_CRITICAL)?;
+        let critical = match words[..] {
+            [included, critical, ..] if included & SYS_INFO_CRITICAL != 0 => critical,
+            _ => return Err(Error::InfoMalformed),
+        };
+
+        let boot_keys_valid = self
+            .otp_read_redundant(OTP_BOOT_FLAGS1_ROW, OTP_BOOT_FLAGS1_COPIES)
+            .ok()
+            .map(|flags| (flags & ((1 << OTP_BOOTKEY_COUNT) - 1)) as u8);
+
+        Ok(SecurityInfo {
+            secure_boot: critical & OTP_CRIT1_SECURE_BOOT_ENABLE != 0,
+            secure_debug_disabled: critical & OTP_CRIT1_SECURE_DEBUG_DISABLE != 0,
+            debug_disabled: critical & OTP_CRIT1_DEBUG_DISABLE != 0,
+            boot_keys_valid,
+        })
+    }
+
+    // reads the architecture the bootrom is running on
+    fn read_cpu(&mut self) -> Result<ExeCpu> {
+        let words = self.get_sys_info(SYS_INFO_CPU_INFO)?;
+        match words[..] {
+            [included, CPU_INFO_RISCV, ..] if included & SYS_INFO_CPU_INFO != 0 => {
+                Ok(ExeCpu::Riscv)
+            }
+            [included, _, ..] if included & SYS_INFO_CPU_INFO != 0 => Ok(ExeCpu::Arm),
+            _ => Err(Error::InfoMalformed),
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use crate::{
+    backend::UsbBackend,
+    cmd::{PicobootError, TargetID},
+    usb::{PicobootConnection, TRANSFER_CHUNK_SIZE},
+    PICO_FLASH_START, PICO_SECTOR_SIZE,
+};
+
+use std::fmt;
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+// size of the flash address window the blank check stub can read
+const FLASH_WINDOW_SIZE: u32 = 0x1000000;
+// bytes checked by each run of the blank check stub, small enough to finish
+// well within the command timeout
+const BLANK_CHECK_STUB_CHUNK_SIZE: u32 = 0x40000;
+
+/// A single FLASH_ERASE command in an [`ErasePlan`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EraseCommand {
+    /// Address of the first sector erased.
+    pub addr: u32,
+    /// Number of bytes erased, a multiple of [`PICO_SECTOR_SIZE`].
+    pub size: u32,
+}
+impl fmt::Display for EraseCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "erase {:#010x}..{:#010x} ({} sectors)",
+            self.addr,
+            self.addr as u64 + self.size as u64,
+            self.size / PICO_SECTOR_SIZE
+        )
+    }
+}
+
+/// The erase commands needed to erase ranges of flash.
+///
+/// Made by [`PicobootConnection::plan_erase`] without erasing anything, and
+/// carried out by [`PicobootConnection::apply_erase_plan`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErasePlan {
+    erases: Vec<EraseCommand>,
+    skipped: Vec<u32>,
+}
+impl ErasePlan {
+    /// Returns the erase commands the plan would send, sorted by address.
+    pub fn get_erases(&self) -> &[EraseCommand] {
+        &self.erases
+    }
+
+    /// Returns the addresses of the sectors left out of the plan because they
+    /// were already blank.
+    pub fn get_skipped_sectors(&self) -> &[u32] {
+        &self.skipped
+    }
+
+    /// Returns the number of sectors the plan would erase.
+    pub fn get_sector_count(&self) -> u32 {
+        self.erases.iter().map(|e| e.size / PICO_SECTOR_SIZE).sum()
+    }
+
+    /// Returns whether the plan would erase nothing.
+    pub fn is_empty(&self) -> bool {
+        self.erases.is_empty()
+    }
+
+    // adds the sectors from `addr` to `end` to the last command if they follow
+    // on from it, or as a new command otherwise
+    fn push(&mut self, addr: u32, end: u32) {
+        match self.erases.last_mut() {
+            Some(last) if last.addr + last.size == addr => last.size += end - addr,
+            _ => self.erases.push(EraseCommand {
+                addr,
+                size: end - addr,
+            }),
+        }
+    }
+}
+impl fmt::Display for ErasePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for e in &self.erases {
+            writeln!(f, "{}", e)?;
+        }
+        if !self.skipped.is_empty() {
+            writeln!(f, "skip {} blank sectors", self.skipped.len())?;
+        }
+        Ok(())
+    }
+}
+
+// widens ranges to whole sectors, and merges overlapping and adjacent ones
+// into runs of sectors sorted by address, as start and end address pairs
+fn sector_runs(ranges: &[(u32, u32)]) -> Result<Vec<(u32, u32)>> {
+    let mut sectors = Vec::with_capacity(ranges.len());
+    for &(addr, size) in ranges {
+        if size == 0 {
+            continue;
+        }
+        let end = addr
+            .checked_add(size)
+            .and_then(|end| end.checked_add(PICO_SECTOR_SIZE - 1))
+            .ok_or(Error::MemoryOutOfRange)?;
+        sectors.push((addr - addr % PICO_SECTOR_SIZE, end - end % PICO_SECTOR_SIZE));
+    }
+    sectors.sort_unstable();
+
+    let mut runs: Vec<(u32, u32)> = Vec::with_capacity(sectors.len());
+    for (start, end) in sectors {
+        match runs.last_mut() {
+            Some(last) if start <= last.1 => last.1 = std::cmp::max(last.1, end),
+            _ => runs.push((start, end)),
+        }
+    }
+
+    Ok(runs)
+}
+
+impl<T: UsbBackend> PicobootConnection<T> {
+    /// Plans erasing ranges of flash without erasing anything.
+    ///
+    /// Every range is widened to whole sectors, so data sharing a sector with
+    /// either end of a range is erased too. Overlapping and adjacent ranges
+    /// are merged, so each contiguous run of sectors is erased with a single
+    /// command.
+    ///
+    /// - `ranges` - Ranges to erase, as address and size in bytes.
+    /// - `skip_blank` - Read the sectors first and leave out those that are already blank.
+    ///
+    /// # Errors:
+    /// - [`Error::MemoryOutOfRange`]
+    /// - Any produced by [`Self::flash_read`]
+    pub fn plan_erase(&mut self, ranges: &[(u32, u32)], skip_blank: bool) -> Result<ErasePlan> {
+        let mut plan = ErasePlan::default();
+        for (start, end) in sector_runs(ranges)? {
+            if !skip_blank {
+                plan.push(start, end);
+                continue;
+            }
+
+            let mut addr = start;
+            while addr < end {
+                let len = std::cmp::min(end - addr, TRANSFER_CHUNK_SIZE);
+                let data = self.flash_read(addr, len)?;
+                for (i, sector) in data.chunks(PICO_SECTOR_SIZE as usize).enumerate() {
+                    let sector_addr = addr + i as u32 * PICO_SECTOR_SIZE;
+                    if sector.iter().all(|b| *b == 0xFF) {
+                        plan.skipped.push(sector_addr);
+                    } else {
+                        plan.push(sector_addr, sector_addr + PICO_SECTOR_SIZE);
+                    }
+                }
+                addr += len;
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// Checks that a range of flash is blank, i.e. every byte is 0xFF.
+    ///
+    /// On the RP2040, a stub run on the device reads the flash, so only the
+    /// addresses of non-blank bytes cross the bus, see [`Self::exec`]. This
+    /// leaves the flash out of XIP mode. The RP2350 bootrom cannot run code
+    /// from the host, so there the range is read [`TRANSFER_CHUNK_SIZE`]
+    /// bytes at a time. Either way, the check stops once `limit` non-blank
+    /// bytes have been found.
+    ///
+    /// Returns the addresses of the first non-blank bytes, at most `limit` of
+    /// them. The range is blank if there are none.
+    ///
+    /// - `addr` - Address to start the check.
+    /// - `size` - Number of bytes to check.
+    /// - `limit` - Number of non-blank bytes to stop after, at least 1.
+    ///
+    /// # Errors:
+    /// - [`Error::BlankCheckInvalidLimit`]
+    /// - [`Error::MemoryOutOfRange`]
+    /// - Any produced by [`Self::flash_read`] or [`Self::exec`]
+    pub fn blank_check(&mut self, addr: u32, size: u32, limit: usize) -> Result<Vec<u32>> {
+        // no addresses would otherwise read as a blank range
+        if limit == 0 {
+            return Err(Error::BlankCheckInvalidLimit);
+        }
+        let end = addr.checked_add(size).ok_or(Error::MemoryOutOfRange)?;
+        let on_device = matches!(self.get_device_type(), TargetID::Rp2040)
+            && addr >= PICO_FLASH_START
+            && end <= PICO_FLASH_START + FLASH_WINDOW_SIZE;
+
+        let mut found = Vec::new();
+        let mut offset = 0;
+        while offset < size && found.len() < limit {
+            let chunk_addr = addr.checked_add(offset).ok_or(Error::MemoryOutOfRange)?;
+
+            if on_device {
+                let len = std::cmp::min(size - offset, BLANK_CHECK_STUB_CHUNK_SIZE);
+                let blank = self.flash_blank_count(chunk_addr, len)?;
+                if blank < len {
+                    found.push(chunk_addr + blank);
+                    offset += blank + 1;
+                } else {
+                    offset += len;
+                }
+                continue;
+            }
+
+            let len = std::cmp::min(size - offset, TRANSFER_CHUNK_SIZE);
+            let data = self.flash_read(chunk_addr, len)?;
+            found.extend(
+                data.iter()
+                    .enumerate()
+                    .filter(|(_, b)| **b != 0xFF)
+                    .map(|(i, _)| chunk_addr + i as u32)
+                    .take(limit - found.len()),
+            );
+            offset += len;
+        }
+
+        Ok(found)
+    }
+
+    /// Erases flash and checks that it is blank afterwards.
+    ///
+    /// - `addr` - Address to start the erase. Must be on a multiple of [`PICO_SECTOR_SIZE`].
+    /// - `size` - Number of bytes to erase. Must be a multiple of [`PICO_SECTOR_SIZE`].
+    ///
+    /// # Errors:
+    /// - [`Error::EraseNotBlank`]
+    /// - Any produced by [`Self::flash_erase`] or [`Self::blank_check`]
+    pub fn flash_erase_verified(&mut self, addr: u32, size: u32) -> Result<()> {
+        self.flash_erase(addr, size)?;
+        match self.blank_check(addr, size, 1)?.first() {
+            Some(&addr) => Err(Error::EraseNotBlank(addr)),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends the erase commands of a plan.
+    ///
+    /// - `plan` - Plan made by [`Self::plan_erase`].
+    ///
+    /// # Errors:
+    /// - Any produced by [`Self::flash_erase`]
+    pub fn apply_erase_plan(&mut self, plan: &ErasePlan) -> Result<()> {
+        for e in &plan.erases {
+            self.flash_erase(e.addr, e.size)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const S: u32 = PICO_SECTOR_SIZE;
+
+    #[test]
+    fn sector_runs_widen() {
+        assert_eq!(
+            sector_runs(&[(0x1000_0010, 0x20)]).unwrap(),
+            [(0x1000_0000, 0x1000_0000 + S)]
+        );
+        assert_eq!(
+            sector_runs(&[(0x1000_0000 + S - 1, 2)]).unwrap(),
+            [(0x1000_0000, 0x1000_0000 + 2 * S)]
+        );
+        assert_eq!(
+            sector_runs(&[(0x1000_0000, S)]).unwrap(),
+            [(0x1000_0000, 0x1000_0000 + S)]
+        );
+        assert_eq!(sector_runs(&[(0x1000_0000, 0)]).unwrap(), []);
+    }
+
+    #[test]
+    fn sector_runs_merge() {
+        let base = 0x1000_0000;
+        // overlapping, adjacent, sharing a sector, and out of order
+        let ranges = [
+            (base + 4 * S, S),
+            (base, 2 * S),
+            (base + S, S),
+            (base + 2 * S, 0x10),
+            (base + 2 * S + 0x800, 0x10),
+            (base + 8 * S, S),
+        ];
+        assert_eq!(
+            sector_runs(&ranges).unwrap(),
+            [
+                (base, base + 3 * S),
+                (base + 4 * S, base + 5 * S),
+                (base + 8 * S, base + 9 * S)
+            ]
+        );
+    }
+
+    #[test]
+    fn sector_runs_overflow() {
+        assert!(matches!(
+            sector_runs(&[(u32::MAX - 0x10, 0x20)]),
+            Err(Error::MemoryOutOfRange)
+        ));
+        assert!(matches!(
+            sector_runs(&[(u32::MAX - S + 1, 1)]),
+            Err(Error::MemoryOutOfRange)
+        ));
+    }
+
+    #[test]
+    fn plan_push_merges_following() {
+        let mut plan = ErasePlan::default();
+        plan.push(0x1000_0000, 0x1000_0000 + S);
+        plan.push(0x1000_0000 + S, 0x1000_0000 + 3 * S);
+        plan.push(0x1000_0000 + 4 * S, 0x1000_0000 + 5 * S);
+
+        assert_eq!(
+            plan.get_erases(),
+            [
+                EraseCommand {
+                    addr: 0x1000_0000,
+                    size: 3 * S
+                },
+                EraseCommand {
+                    addr: 0x1000_0000 + 4 * S,
+                    size: S
+                },
+            ]
+        );
+        assert_eq!(plan.get_sector_count(), 4);
+        assert!(!plan.is_empty());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::{
+    backend::UsbBackend,
+    block::bytes_to_words,
+    cmd::{PicobootCmd, PicobootError, TargetID},
+    usb::PicobootConnection,
+};
+
+use serde::Serialize;
+
+// see https://datasheets.raspberrypi.com/rp2350/rp2350-datasheet.pdf
+// section 5.6.8.12 for details on GET_INFO
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// GET_INFO type for system information
+pub const INFO_SYS: u8 = 0x1;
+/// GET_INFO type for partition table information
+pub const INFO_PARTITION_INFO: u8 = 0x2;
+/// GET_INFO type for the partition a UF2 family would be downloaded to
+pub const INFO_UF2_TARGET_PARTITION: u8 = 0x3;
+/// GET_INFO type for the status of the last UF2 download
+pub const INFO_UF2_STATUS: u8 = 0x4;
+
+/// System information: chip package and device ID (3 words)
+pub const SYS_INFO_CHIP_INFO: u32 = 0x0001;
+/// System information: critical OTP register (1 word)
+pub const SYS_INFO_CRITICAL: u32 = 0x0002;
+/// System information: current architecture (1 word)
+pub const SYS_INFO_CPU_INFO: u32 = 0x0004;
+/// System information: flash device info (1 word)
+pub const SYS_INFO_FLASH_DEV_INFO: u32 = 0x0008;
+/// System information: per-boot random value (4 words)
+pub const SYS_INFO_BOOT_RANDOM: u32 = 0x0010;
+/// System information: boot diagnostics (4 words)
+pub const SYS_INFO_BOOT_INFO: u32 = 0x0040;
+
+/// Partition information: table-wide information
+pub const PT_INFO_PT_INFO: u32 = 0x0001;
+/// Partition information: location and flags of each partition
+pub const PT_INFO_PARTITION_LOCATION_AND_FLAGS: u32 = 0x0010;
+/// Partition information: ID of each partition
+pub const PT_INFO_PARTITION_ID: u32 = 0x0020;
+/// Partition information: extra family IDs of each partition
+pub const PT_INFO_PARTITION_FAMILY_IDS: u32 = 0x0040;
+/// Partition information: name of each partition
+pub const PT_INFO_PARTITION_NAME: u32 = 0x0080;
+/// Partition information: only return the partition given in bits 24-31
+pub const PT_INFO_SINGLE_PARTITION: u32 = 0x8000;
+
+/// Boot info flag: the booted image is waiting to be bought
+pub const BOOT_TBYB_AND_UPDATE_FLAG_BUY_PENDING: u8 = 0x1;
+/// Boot info flag: the other A/B slot was erased after buying
+pub const BOOT_TBYB_AND_UPDATE_FLAG_OTHER_ERASED: u8 = 0x2;
+/// Boot info flag: the boot was a flash update boot
+pub const BOOT_TBYB_AND_UPDATE_FLAG_FLASH_UPDATE: u8 = 0x4;
+
+const GET_INFO_MAX_SIZE: u32 = 0x100;
+
+const FLASH_DEV_INFO_CS0_SIZE_LSB: u32 = 8;
+const FLASH_DEV_INFO_SIZE_MASK: u32 = 0xF;
+
+/// Diagnostics about the most recent boot, see [`PicobootConnection::get_boot_info`].
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BootInfo {
+    /// Partition that was booted, if any.
+    pub partition: Option<u8>,
+    /// Boot type of the most recent boot, as used in REBOOT2 flags.
+    pub boot_type: u8,
+    /// `BOOT_TBYB_AND_UPDATE_FLAG_*` bits.
+    pub tbyb_and_update_flags: u8,
+    /// Partition the diagnostic words describe.
+    pub diagnostic_partition: u8,
+    /// Boot diagnostic flags of the A (low half) and B (high half) regions.
+    pub diagnostic: u32,
+    /// Parameters passed with the most recent reboot.
+    pub reboot_params: [u32; 2],
+}
+
+impl<T: UsbBackend> PicobootConnection<T> {
+    /// Requests information from the bootrom with a GET_INFO command.
+    ///
+    /// Returns the words of the response, excluding the leading word count.
+    /// For most types, the first returned word echoes the fields that are
+    /// included in the response.
+    ///
+    /// - `info_type` - One of the `INFO_*` constants.
+    /// - `param` - Type specific 8-bit parameter.
+    /// - `wparam` - Type specific 16-bit parameter.
+    /// - `dparams` - Type specific 32-bit parameters, usually `*_INFO_*` flags.
+    ///
+    /// # Errors:
+    /// - [`Error::CmdNotAllowedForTarget`]
+    /// - [`Error::InfoMalformed`]
+    /// - Any produced by [`Self::cmd`]
+    pub fn get_info(
+        &mut self,
+        info_type: u8,
+        param: u8,
+        wparam: u16,
+        dparams: [u32; 3],
+    ) -> Result<Vec<u32>> {
+        if let TargetID::Rp2040 = self.get_device_type() {
+            return Err(Error::CmdNotAllowedForTarget);
+        }
+
+        let cmd = PicobootCmd::get_info(info_type, param, wparam, dparams, GET_INFO_MAX_SIZE);
+        let words = bytes_to_words(&self.cmd(cmd, &[0u8; 0])?);
+
+        let count = *words.first().ok_or(Error::InfoMalformed)? as usize;
+        if count >= words.len() {
+            return Err(Error::InfoMalformed);
+        }
+
+        Ok(words[1..=count].to_vec())
+    }
+
+    /// Requests system information from the bootrom.
+    ///
+    /// Returns the words of each requested field that the bootrom supports, in
+    /// order of their flag bits, preceded by the flags of the included fields.
+    ///
+    /// - `flags` - Any combination of the `SYS_INFO_*` constants.
+    ///
+    /// # Errors:
+    /// - Any produced by [`Self::get_info`]
+    pub fn get_sys_info(&mut self, flags: u32) -> Result<Vec<u32>> {
+        self.get_info(INFO_SYS, 0, 0, [flags, 0, 0])
+    }
+
+    /// Returns the size in bytes of the flash attached to chip select 0, as
+    /// configured in the bootrom, or zero if there is none.
+    ///
+    /// # Errors:
+    /// - [`Error::InfoMalformed`]
+    /// - Any produced by [`Self::get_info`]
+    pub fn get_flash_size(&mut self) -> Result<u32> {
+        let words = self.get_sys_info(SYS_INFO_FLASH_DEV_INFO)?;
+        match words[..] {
+            [included, dev_info, ..] if included & SYS_INFO_FLASH_DEV_INFO != 0 => {
+                match (dev_info >> FLASH_DEV_INFO_CS0_SIZE_LSB) & FLASH_DEV_INFO_SIZE_MASK {
+                    0 => Ok(0),
+                    size => Ok(0x1000 << size),
+                }
+            }
+            _ => Err(Error::InfoMalformed),
+        }
+    }
+
+    /// Returns the partition the bootrom would download a UF2 of a family to.
+    ///
+    /// For an A/B pair, this is the slot the bootrom would not boot. Returns
+    /// `None` if no partition accepts the family.
+    ///
+    /// - `family_id` - UF2 family ID, e.g. [`crate::UF2_RP2350_ARM_S_FAMILY_ID`].
+    ///
+    /// # Errors:
+    /// - [`Error::InfoMalformed`]
+    /// - Any produced by [`Self::get_info`]
+    pub fn get_uf2_target_partition(&mut self, family_id: u32) -> Result<Option<u8>> {
+        let words = self.get_info(INFO_UF2_TARGET_PARTITION, 0, 0, [family_id, 0, 0])?;
+        match words.first() {
+            Some(&partition) if (partition as i32) < 0 => Ok(None),
+            Some(&partition) => Ok(Some(partition as u8)),
+            None => Err(Error::InfoMalformed),
+        }
+    }
+
+    /// Returns diagnostics about the most recent boot.
+    ///
+    /// When connected over PICOBOOT this describes the boot into BOOTSEL mode,
+    /// unless the bootrom fell back to BOOTSEL because nothing else booted.
+    ///
+    /// # Errors:
+    /// - [`Error::InfoMalformed`]
+    /// - Any produced by [`Self::get_info`]
+    pub fn get_boot_info(&mut self) -> Result<BootInfo> {
+        let words = self.get_sys_info(SYS_INFO_BOOT_INFO)?;
+        match words[..] {
+            [included, info, diagnostic, p0, p1, ..] if included & SYS_INFO_BOOT_INFO != 0 => {
+                let partition = (info >> 16) as i8;
+                Ok(BootInfo {
+                    partition: if partition < 0 {
+                        None
+                    } else {
+                        Some(partition as u8)
+                    },
+                    tbyb_and_update_flags: (info >> 24) as u8,
+                    boot_type: (info >> 8) as u8,
+                    diagnostic_partition: info as u8,
+                    diagnostic,
+                    reboot_params: [p0, p1],
+                })
+            }
+            _ => Err(Error::InfoMalformed),
+        }
+    }
+}
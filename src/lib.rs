@@ -19,7 +19,7 @@
 //!
 //! Flash a UF2 to a Pico device!
 //!
-//! ```rust
+//! ```rust,no_run
 //! use picoboot_rs::{
 //!     PicobootConnection, TargetID, PICO_FLASH_START, PICO_PAGE_SIZE, PICO_SECTOR_SIZE,
 //!     PICO_STACK_POINTER,
@@ -91,8 +91,9 @@
 //!                     conn.reboot(0x0, PICO_STACK_POINTER, delay)
 //!                         .expect("failed to reboot device");
 //!                 }
-//!                 TargetID::Rp2350 => conn.reboot2_normal(delay)
-//! 					.expect("failed to reboot device"),
+//!                 TargetID::Rp2350 => conn
+//!                     .reboot2_normal(delay)
+//!                     .expect("failed to reboot device"),
 //!             }
 //!         }
 //!         Err(e) => panic!("Could not initialize libusb: {}", e),
@@ -131,10 +132,90 @@ pub const UF2_RP2350_RISCV_FAMILY_ID: u32 = 0xE48BFF5A;
 pub const UF2_RP2350_ARM_NS_FAMILY_ID: u32 = 0xE48BFF5B;
 // pub const UF2_FAMILY_ID_MAX: u32 = 0xE48BFF5B;
 
+/// Async Connection Module
+#[cfg(feature = "libusb")]
+pub mod asynch;
+#[cfg(feature = "libusb")]
+pub use asynch::{AsyncPicobootConnection, PipelineOptions};
+
+/// USB Backend Module
+pub mod backend;
+#[cfg(feature = "nusb")]
+pub use backend::Nusb;
+pub use backend::{UsbBackend, UsbError};
+
+/// Binary Info Module
+pub mod bininfo;
+pub use bininfo::{BinaryInfo, BinaryInfoEntry};
+
+/// Metadata Block Module
+pub mod block;
+pub use block::{Block, BlockItem, BlockLoop};
+
+/// Second Stage Bootloader Module
+pub mod boot2;
+pub use boot2::{Boot2Fixup, Boot2Flash};
+
+/// Chip Identification Module
+pub mod chip;
+pub use chip::{ChipInfo, ChipPackage, ChipRevision};
+
 /// Command Module
 pub mod cmd;
 pub use cmd::{PicobootCmd, PicobootCmdId, PicobootError, TargetID};
 
+/// Device Report Module
+pub mod devinfo;
+pub use devinfo::DeviceInfo;
+
+/// Flash Erase Module
+pub mod erase;
+pub use erase::{EraseCommand, ErasePlan};
+
+/// Device Information Module
+pub mod info;
+
+/// Flash Loader Module
+pub mod loader;
+pub use loader::{FlashReport, LoadOptions};
+
+/// Device Memory Module
+pub mod memory;
+pub use memory::{DeviceMemory, Image, MemoryRead};
+
+/// OTP Module
+pub mod otp;
+pub use otp::{OtpPlan, OtpWrite, SecureBootConfig};
+
+/// Partition Table Module
+pub mod partition;
+pub use partition::{Partition, PartitionTable, PartitionTarget};
+
+/// Reset Interface Module
+pub mod reset;
+pub use reset::{BootselOptions, ResetConnection};
+
+/// Image Sealing Module
+#[cfg(feature = "seal")]
+pub mod seal;
+
+/// Embedded Storage Module
+#[cfg(feature = "embedded-storage")]
+pub mod storage;
+#[cfg(feature = "embedded-storage")]
+pub use storage::FlashStorage;
+
+/// RP2040 Code Stub Module
+pub mod stub;
+
+/// Flash Update Module
+pub mod update;
+pub use update::{AbPair, FlashUpdate, FlashUpdateStatus};
+
 /// USB Connection Module
 pub mod usb;
-pub use usb::PicobootConnection;
+pub use usb::{DeviceIdentity, PicobootConnection, TRANSFER_CHUNK_SIZE};
+
+/// USB White Label Module
+pub mod whitelabel;
+pub use whitelabel::WhiteLabel;
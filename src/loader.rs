@@ -0,0 +1,348 @@
+use crate::{
+    backend::UsbBackend,
+    bininfo::BinaryInfo,
+    block::{BlockLoop, ExeChip, ExeCpu, ExeSecurity, ImageType},
+    boot2::{check_boot2, fix_boot2, Boot2Fixup},
+    cmd::{PicobootError, TargetID},
+    partition::{
+        PartitionTarget, Permissions, PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_ARM_NS,
+        PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_ARM_S, PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_RISCV,
+    },
+    usb::PicobootConnection,
+    PICO_FLASH_START, PICO_PAGE_SIZE, PICO_SECTOR_SIZE,
+};
+
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// Options for loading an image with [`PicobootConnection::load`].
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// Skip the erase/write cycle entirely if the device already has the same
+    /// program name, version string and image contents.
+    pub skip_if_up_to_date: bool,
+    /// Check that the flash is blank after erasing it, before writing.
+    pub verify_erase: bool,
+    /// Read back every page after writing it and compare it to the image.
+    pub verify: bool,
+    /// Architecture an RP2350 is expected to boot the image on. `None`
+    /// accepts any architecture.
+    pub boot_cpu: Option<ExeCpu>,
+    /// Security mode an RP2350 is expected to boot the image in. `None`
+    /// accepts any mode the bootrom can boot directly.
+    pub boot_security: Option<ExeSecurity>,
+    /// Repair applied to RP2040 images whose second stage bootloader fails
+    /// validation. `None` leaves the image untouched.
+    pub boot2_fixup: Option<Boot2Fixup>,
+    /// Load images that the bootrom would refuse to boot anyway, reporting the
+    /// reason in [`FlashReport::warnings`] instead of failing.
+    pub allow_unbootable: bool,
+}
+
+/// Summary of what [`PicobootConnection::load`] and the other high-level
+/// flash writes did to the device.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlashReport {
+    /// The device already had the image, so nothing was erased or written.
+    pub up_to_date: bool,
+    /// Number of sectors erased.
+    pub sectors_erased: u32,
+    /// Number of pages written.
+    pub pages_written: u32,
+    /// Number of pages left as erased instead of written, because they were
+    /// blank.
+    pub pages_skipped: u32,
+    /// Problems with the image that did not stop it from being loaded.
+    pub warnings: Vec<String>,
+}
+
+/// Checks that the RP2350 bootrom would boot an image.
+///
+/// The image must contain a valid block loop whose IMAGE_DEF describes an
+/// RP2350 executable for the given architecture and security mode. Without
+/// one, the bootrom silently falls back to BOOTSEL mode.
+///
+/// - `image` - Image to check.
+/// - `addr` - Address the image is loaded at.
+/// - `cpu` - Architecture the image should boot on, or `None` for any.
+/// - `security` - Security mode the image should boot in, or `None` for any
+///   bootable mode.
+///
+/// # Errors:
+/// - [`Error::ImageNotBootable`]
+pub fn check_rp2350_bootable(
+    image: &[u8],
+    addr: u32,
+    cpu: Option<ExeCpu>,
+    security: Option<ExeSecurity>,
+) -> Result<()> {
+    let block_loop = match BlockLoop::from_image(image, addr) {
+        Ok(l) => l,
+        Err(Error::BlockNotFound) => {
+            return Err(Error::ImageNotBootable("no metadata block found"))
+        }
+        Err(_) => return Err(Error::ImageNotBootable("metadata block loop is invalid")),
+    };
+    let (_, def) = block_loop
+        .get_image_def()
+        .ok_or(Error::ImageNotBootable("no IMAGE_DEF in block loop"))?;
+
+    if def.image_type != ImageType::Exe {
+        return Err(Error::ImageNotBootable("IMAGE_DEF is not an executable"));
+    }
+    if def.chip != ExeChip::Rp2350 {
+        return Err(Error::ImageNotBootable("IMAGE_DEF is not for RP2350"));
+    }
+    if cpu.map_or(false, |cpu| cpu != def.cpu) {
+        return Err(Error::ImageNotBootable(
+            "IMAGE_DEF is for a different architecture",
+        ));
+    }
+    match security {
+        Some(security) if security != def.security => Err(Error::ImageNotBootable(
+            "IMAGE_DEF is for a different security mode",
+        )),
+        // the bootrom only boots secure ARM images directly
+        None if def.cpu == ExeCpu::Arm && def.security == ExeSecurity::NonSecure => {
+            Err(Error::ImageNotBootable("IMAGE_DEF is for non-secure ARM"))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl<T: UsbBackend> PicobootConnection<T> {
+    /// Loads an image into flash, erasing and writing as needed.
+    ///
+    /// The device should already be in exclusive access mode and out of XIP
+    /// mode, see [`Self::access_exclusive_eject`] and [`Self::exit_xip`].
+    ///
+    /// - `addr` - Address to load the image at. Must be on a multiple of [`PICO_SECTOR_SIZE`].
+    /// - `image` - Image to load. The remainder of the final page is zero-filled.
+    /// - `options` - See [`LoadOptions`].
+    ///
+    /// Images loaded at the start of flash are checked before anything is
+    /// erased: RP2040 images with [`check_boot2`], repaired according to
+    /// [`LoadOptions::boot2_fixup`], and RP2350 images with
+    /// [`check_rp2350_bootable`].
+    ///
+    /// Pages of the image that are entirely 0xFF are left as erased rather
+    /// than written, see [`Self::flash_write_sparse`].
+    ///
+    /// # Errors:
+    /// - [`Error::EraseInvalidAddr`]
+    /// - [`Error::Boot2Missing`]
+    /// - [`Error::Boot2ChecksumMismatch`]
+    /// - [`Error::ImageNotBootable`]
+    /// - [`Error::EraseNotBlank`]
+    /// - [`Error::LoadVerifyMismatch`]
+    /// - Any produced by [`Self::flash_erase`], [`Self::flash_write`] or [`Self::flash_read`]
+    pub fn load(&mut self, addr: u32, image: &[u8], options: &LoadOptions) -> Result<FlashReport> {
+        self.load_image(addr, image, options, addr == PICO_FLASH_START)
+    }
+
+    /// Loads an image into a partition of the partition table the bootrom
+    /// loaded at boot, see [`Self::get_partition_table`].
+    ///
+    /// The image is placed at the start of the partition. Images for
+    /// partitions that accept executable UF2 families are checked with
+    /// [`check_rp2350_bootable`] first.
+    ///
+    /// - `target` - Partition to load the image into.
+    /// - `image` - Image to load. The remainder of the final page is zero-filled.
+    /// - `options` - See [`LoadOptions`].
+    ///
+    /// # Errors:
+    /// - [`Error::PartitionNotFound`]
+    /// - [`Error::PartitionPermissionDenied`]
+    /// - [`Error::PartitionOverflow`]
+    /// - Any produced by [`Self::get_partition_table`] or [`Self::load`]
+    pub fn load_partition(
+        &mut self,
+        target: &PartitionTarget,
+        image: &[u8],
+        options: &LoadOptions,
+    ) -> Result<FlashReport> {
+        let table = self.get_partition_table()?;
+        let (_, partition) = table
+            .find_partition(target)
+            .ok_or(Error::PartitionNotFound)?;
+
+        let mut required = Permissions::BOOTSEL_WRITE;
+        if options.verify || options.verify_erase || options.skip_if_up_to_date {
+            required = required | Permissions::BOOTSEL_READ;
+        }
+        if !partition.permissions.contains(required) {
+            return Err(Error::PartitionPermissionDenied);
+        }
+        if image.len() > partition.get_size() as usize {
+            return Err(Error::PartitionOverflow);
+        }
+
+        let boot = partition.flags
+            & (PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_ARM_S
+                | PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_RISCV
+                | PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_ARM_NS)
+            != 0;
+        let addr = PICO_FLASH_START + partition.get_offset();
+        self.load_image(addr, image, options, boot)
+    }
+
+    // loads an image, checking that it would boot first if `boot` is set
+    fn load_image(
+        &mut self,
+        addr: u32,
+        image: &[u8],
+        options: &LoadOptions,
+        boot: bool,
+    ) -> Result<FlashReport> {
+        let mut report = FlashReport::default();
+        if addr % PICO_SECTOR_SIZE != 0 {
+            return Err(Error::EraseInvalidAddr);
+        }
+
+        let mut image = Cow::Borrowed(image);
+        let res = match (self.get_device_type(), boot) {
+            (TargetID::Rp2040, true) => match (check_boot2(&image), options.boot2_fixup) {
+                (Err(e @ Error::Boot2ChecksumMismatch { .. }), Some(fixup)) => {
+                    fix_boot2(image.to_mut(), fixup)?;
+                    report.warnings.push(format!("{}, applied {:?}", e, fixup));
+                    Ok(())
+                }
+                (res, _) => res,
+            },
+            (TargetID::Rp2350, true) => {
+                check_rp2350_bootable(&image, addr, options.boot_cpu, options.boot_security)
+            }
+            _ => Ok(()),
+        };
+        match res {
+            Err(e) if options.allow_unbootable => report.warnings.push(e.to_string()),
+            res => res?,
+        }
+        let image = &image[..];
+
+        if options.skip_if_up_to_date && self.is_up_to_date(addr, image)? {
+            report.up_to_date = true;
+            return Ok(report);
+        }
+
+        let len = image.len() as u32;
+        let sectors = (len + PICO_SECTOR_SIZE - 1) / PICO_SECTOR_SIZE;
+        if sectors > 0 {
+            if options.verify_erase {
+                self.flash_erase_verified(addr, sectors * PICO_SECTOR_SIZE)?;
+            } else {
+                self.flash_erase(addr, sectors * PICO_SECTOR_SIZE)?;
+            }
+            report.sectors_erased = sectors;
+        }
+
+        // one WRITE command per sector rather than per page saves most of the
+        // command round trips
+        for (i, chunk) in image.chunks(PICO_SECTOR_SIZE as usize).enumerate() {
+            let chunk_addr = addr + i as u32 * PICO_SECTOR_SIZE;
+            let pages = (chunk.len() as u32 + PICO_PAGE_SIZE - 1) / PICO_PAGE_SIZE;
+            let mut data = chunk.to_vec();
+            data.resize((pages * PICO_PAGE_SIZE) as usize, 0);
+
+            let written = self.flash_write_sparse(chunk_addr, &data)?;
+            report.pages_written += written.pages_written;
+            report.pages_skipped += written.pages_skipped;
+
+            if options.verify {
+                let read = self.flash_read(chunk_addr, data.len() as u32)?;
+                if let Some(offset) = read.iter().zip(&data).position(|(a, b)| a != b) {
+                    let offset = offset as u32 - offset as u32 % PICO_PAGE_SIZE;
+                    return Err(Error::LoadVerifyMismatch(chunk_addr + offset));
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Writes a buffer to freshly erased flash, skipping blank pages.
+    ///
+    /// Pages that are entirely 0xFF already hold their contents once erased,
+    /// so only the runs of pages in between are written. This saves sending
+    /// padding, such as the gaps between partitions of an image.
+    ///
+    /// - `addr` - Address to start the write. Must be on a multiple of [`PICO_PAGE_SIZE`].
+    /// - `buf` - Buffer of data to write to flash. If it is not a multiple of [`PICO_PAGE_SIZE`], the final page is always written and zero-filled.
+    ///
+    /// # Errors:
+    /// - [`Error::WriteInvalidAddr`]
+    /// - Any produced by [`Self::flash_write`]
+    pub fn flash_write_sparse(&mut self, addr: u32, buf: &[u8]) -> Result<FlashReport> {
+        if addr % PICO_PAGE_SIZE != 0 {
+            return Err(Error::WriteInvalidAddr);
+        }
+
+        let mut report = FlashReport::default();
+        let blank = buf
+            .chunks(PICO_PAGE_SIZE as usize)
+            .map(|p| p.len() == PICO_PAGE_SIZE as usize && p.iter().all(|b| *b == 0xFF))
+            .collect::<Vec<_>>();
+
+        let mut page = 0;
+        while page < blank.len() {
+            let run = blank[page..]
+                .iter()
+                .take_while(|b| **b == blank[page])
+                .count();
+            if blank[page] {
+                report.pages_skipped += run as u32;
+            } else {
+                let offset = page * PICO_PAGE_SIZE as usize;
+                let end = std::cmp::min(offset + run * PICO_PAGE_SIZE as usize, buf.len());
+                self.flash_write(addr + offset as u32, &buf[offset..end])?;
+                report.pages_written += run as u32;
+            }
+            page += run;
+        }
+
+        Ok(report)
+    }
+
+    /// Checks whether the device already has an image loaded at an address.
+    ///
+    /// The program name and version string from the binary info of both must
+    /// match, as well as a SHA-256 hash of the image contents. If the binary
+    /// info on the device is missing or malformed, the image is not up to
+    /// date.
+    ///
+    /// - `addr` - Address the image would be loaded at.
+    /// - `image` - Image to compare against.
+    ///
+    /// # Errors:
+    /// - Any produced by [`BinaryInfo::parse`], other than
+    ///   [`Error::BinaryInfoNotFound`], [`Error::BinaryInfoMalformed`] and
+    ///   [`Error::MemoryOutOfRange`]
+    /// - Any produced by [`Self::flash_read_to`]
+    pub fn is_up_to_date(&mut self, addr: u32, image: &[u8]) -> Result<bool> {
+        let ours = BinaryInfo::from_image(image, addr).unwrap_or_default();
+        // whatever is on the device is not worth keeping if it cannot be read,
+        // but USB errors should still reach the caller
+        let theirs = match BinaryInfo::parse(self, addr) {
+            Ok(info) => info,
+            Err(
+                Error::BinaryInfoNotFound | Error::BinaryInfoMalformed | Error::MemoryOutOfRange,
+            ) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        if ours.get_program_name() != theirs.get_program_name()
+            || ours.get_program_version() != theirs.get_program_version()
+        {
+            return Ok(false);
+        }
+
+        let mut hasher = Sha256::new();
+        self.flash_read_to(addr, image.len() as u32, &mut hasher)?;
+
+        Ok(hasher.finalize() == Sha256::digest(image))
+    }
+}
@@ -0,0 +1,492 @@
+use crate::{
+    backend::UsbBackend,
+    cmd::PicobootError,
+    loader::FlashReport,
+    usb::{PicobootConnection, TRANSFER_CHUNK_SIZE},
+    PICO_PAGE_SIZE, PICO_SECTOR_SIZE,
+};
+
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// Read access to the memory of a Raspberry Pi microcontroller
+///
+/// Implemented both by a live [`PicobootConnection`] and by [`Image`], so
+/// anything parsing device memory can work on either a connected device or a
+/// firmware file on disk.
+pub trait MemoryRead {
+    /// Reads `size` bytes starting at `addr`.
+    fn read_memory(&mut self, addr: u32, size: u32) -> Result<Vec<u8>>;
+
+    /// Reads a little-endian word at `addr`.
+    fn read_u32(&mut self, addr: u32) -> Result<u32> {
+        let buf = self.read_memory(addr, 4)?;
+        Ok(u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]))
+    }
+}
+
+impl<T: UsbBackend> MemoryRead for PicobootConnection<T> {
+    fn read_memory(&mut self, addr: u32, size: u32) -> Result<Vec<u8>> {
+        let buf = self.flash_read(addr, size)?;
+        if buf.len() != size as usize {
+            return Err(Error::UsbReadBulkMismatch);
+        }
+        Ok(buf)
+    }
+}
+
+/// A firmware image in host memory, as it would be mapped on the device
+#[derive(Debug, Clone, Copy)]
+pub struct Image<'a> {
+    base: u32,
+    data: &'a [u8],
+}
+impl<'a> Image<'a> {
+    /// Creates a new image of `data` mapped starting at `base`, e.g.
+    /// [`PICO_FLASH_START`](crate::PICO_FLASH_START) for a flash binary.
+    pub fn new(base: u32, data: &'a [u8]) -> Self {
+        Image { base, data }
+    }
+
+    /// Returns the address the image is mapped at.
+    pub fn get_base(&self) -> u32 {
+        self.base
+    }
+
+    /// Returns the contents of the image.
+    pub fn get_data(&self) -> &'a [u8] {
+        self.data
+    }
+}
+impl MemoryRead for Image<'_> {
+    fn read_memory(&mut self, addr: u32, size: u32) -> Result<Vec<u8>> {
+        let start = addr.checked_sub(self.base).ok_or(Error::MemoryOutOfRange)? as usize;
+        let end = start + size as usize;
+        if end > self.data.len() {
+            return Err(Error::MemoryOutOfRange);
+        }
+
+        Ok(self.data[start..end].to_vec())
+    }
+}
+
+/// Memory of a connected device as a file
+///
+/// Implements [`Read`], [`Write`] and [`Seek`] over a window of the address
+/// space, with position 0 at the start of the window. Writing requires the
+/// window to be in flash.
+///
+/// Writes may start and end anywhere. They are collected a sector at a time,
+/// and written back when a write moves on to another sector, on
+/// [`Write::flush`], or when dropped. Only the pages that changed are
+/// programmed, and the sector is only erased if one of them was not blank, so
+/// data next to the written range is preserved.
+///
+/// Errors from the device are returned as [`io::Error`]s wrapping a
+/// [`PicobootError`]. Errors when writing back on drop are ignored, so call
+/// [`Write::flush`] first to see them.
+#[derive(Debug)]
+pub struct DeviceMemory<'a, T: UsbBackend> {
+    conn: &'a mut PicobootConnection<T>,
+    start: u32,
+    len: u32,
+    pos: u64,
+    sector: Option<CachedSector>,
+}
+
+// a sector being written, with its contents before the first write
+#[derive(Debug)]
+struct CachedSector {
+    addr: u32,
+    original: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl<'a, T: UsbBackend> DeviceMemory<'a, T> {
+    /// Creates a window over device memory.
+    ///
+    /// The device should already be in exclusive access mode and out of XIP
+    /// mode, see [`PicobootConnection::access_exclusive_eject`] and
+    /// [`PicobootConnection::exit_xip`].
+    ///
+    /// - `conn` - Connection to the device.
+    /// - `start` - Address of the start of the window.
+    /// - `len` - Size of the window in bytes.
+    ///
+    /// # Errors:
+    /// - [`Error::MemoryOutOfRange`]
+    pub fn new(conn: &'a mut PicobootConnection<T>, start: u32, len: u32) -> Result<Self> {
+        if start.checked_add(len).is_none() {
+            return Err(Error::MemoryOutOfRange);
+        }
+
+        Ok(DeviceMemory {
+            conn,
+            start,
+            len,
+            pos: 0,
+            sector: None,
+        })
+    }
+
+    /// Returns the address of the start of the window.
+    pub fn get_start(&self) -> u32 {
+        self.start
+    }
+
+    /// Returns the size of the window in bytes.
+    pub fn get_len(&self) -> u32 {
+        self.len
+    }
+
+    // makes the sector at `sector_addr` the cached one, writing back the
+    // previous one first
+    fn load_sector(&mut self, sector_addr: u32) -> Result<&mut CachedSector> {
+        if self.sector.as_ref().map_or(true, |s| s.addr != sector_addr) {
+            self.write_back()?;
+            let original = self.conn.read_memory(sector_addr, PICO_SECTOR_SIZE)?;
+            self.sector = Some(CachedSector {
+                addr: sector_addr,
+                data: original.clone(),
+                original,
+            });
+        }
+
+        Ok(self.sector.as_mut().unwrap())
+    }
+
+    // programs the changes to the cached sector
+    fn write_back(&mut self) -> Result<()> {
+        if let Some(sector) = &mut self.sector {
+            let mut report = FlashReport::default();
+            program_sector(
+                self.conn,
+                sector.addr,
+                &sector.original,
+                &sector.data,
+                &mut report,
+            )?;
+            sector.original.clone_from(&sector.data);
+        }
+
+        Ok(())
+    }
+}
+impl<T: UsbBackend> Read for DeviceMemory<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = (self.len as u64).saturating_sub(self.pos);
+        let addr = self.start as u64 + self.pos;
+        let mut len = remaining
+            .min(buf.len() as u64)
+            .min(TRANSFER_CHUNK_SIZE as u64);
+        if len == 0 {
+            return Ok(0);
+        }
+
+        // unwritten changes in the cached sector take precedence
+        if let Some(sector) = &self.sector {
+            let sector_start = sector.addr as u64;
+            let sector_end = sector_start + PICO_SECTOR_SIZE as u64;
+            if addr >= sector_start && addr < sector_end {
+                let offset = (addr - sector_start) as usize;
+                let len = len.min(sector_end - addr) as usize;
+                buf[..len].copy_from_slice(&sector.data[offset..offset + len]);
+                self.pos += len as u64;
+                return Ok(len);
+            }
+            if sector_start > addr && sector_start < addr + len {
+                len = sector_start - addr;
+            }
+        }
+
+        let data = self
+            .conn
+            .read_memory(addr as u32, len as u32)
+            .map_err(io_error)?;
+        buf[..data.len()].copy_from_slice(&data);
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+}
+impl<T: UsbBackend> Write for DeviceMemory<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let remaining = (self.len as u64).saturating_sub(self.pos);
+        if remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let addr = (self.start as u64 + self.pos) as u32;
+        let sector_addr = addr - addr % PICO_SECTOR_SIZE;
+        let sector = self.load_sector(sector_addr).map_err(io_error)?;
+
+        let offset = (addr - sector_addr) as usize;
+        let len = remaining
+            .min(buf.len() as u64)
+            .min((PICO_SECTOR_SIZE as usize - offset) as u64) as usize;
+        sector.data[offset..offset + len].copy_from_slice(&buf[..len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_back().map_err(io_error)
+    }
+}
+impl<T: UsbBackend> Seek for DeviceMemory<'_, T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i128,
+            SeekFrom::End(offset) => self.len as i128 + offset as i128,
+            SeekFrom::Current(offset) => self.pos as i128 + offset as i128,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            ));
+        }
+
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+impl<T: UsbBackend> Drop for DeviceMemory<'_, T> {
+    fn drop(&mut self) {
+        let _ = self.write_back();
+    }
+}
+
+impl<T: UsbBackend> PicobootConnection<T> {
+    /// Writes a buffer to flash at any address, preserving the data around it.
+    ///
+    /// Each sector the write touches is read, merged with the buffer, and
+    /// only the pages that changed are programmed. The sector is only erased
+    /// if one of them was not blank. Unlike [`Self::flash_write`], neither
+    /// `addr` nor the length of `buf` need to be aligned.
+    ///
+    /// The device should already be in exclusive access mode and out of XIP
+    /// mode, see [`Self::access_exclusive_eject`] and [`Self::exit_xip`].
+    ///
+    /// - `addr` - Address to start the write.
+    /// - `buf` - Buffer of data to write to flash.
+    ///
+    /// # Errors:
+    /// - [`Error::MemoryOutOfRange`]
+    /// - Any produced by [`Self::flash_erase`], [`Self::flash_write`] or [`Self::flash_read`]
+    pub fn flash_write_unaligned(&mut self, addr: u32, buf: &[u8]) -> Result<FlashReport> {
+        let mut report = FlashReport::default();
+        let end = u32::try_from(buf.len())
+            .ok()
+            .and_then(|len| addr.checked_add(len))
+            .ok_or(Error::MemoryOutOfRange)?;
+
+        let mut sector_addr = addr - addr % PICO_SECTOR_SIZE;
+        while sector_addr < end {
+            let original = self.read_memory(sector_addr, PICO_SECTOR_SIZE)?;
+            let mut data = original.clone();
+
+            let from = std::cmp::max(addr, sector_addr);
+            let to = std::cmp::min(end, sector_addr + PICO_SECTOR_SIZE);
+            data[(from - sector_addr) as usize..(to - sector_addr) as usize]
+                .copy_from_slice(&buf[(from - addr) as usize..(to - addr) as usize]);
+
+            program_sector(self, sector_addr, &original, &data, &mut report)?;
+            sector_addr += PICO_SECTOR_SIZE;
+        }
+
+        Ok(report)
+    }
+}
+
+// brings a sector from `original` to `data`, see `plan_sector`
+fn program_sector<T: UsbBackend>(
+    conn: &mut PicobootConnection<T>,
+    addr: u32,
+    original: &[u8],
+    data: &[u8],
+    report: &mut FlashReport,
+) -> Result<()> {
+    let (erase, runs) = plan_sector(original, data, report);
+    if erase {
+        conn.flash_erase(addr, PICO_SECTOR_SIZE)?;
+    }
+    for (offset, len) in runs {
+        conn.flash_write(addr + offset as u32, &data[offset..offset + len])?;
+    }
+
+    Ok(())
+}
+
+// decides how to bring a sector from `original` to `data`, returning whether
+// to erase it first and the runs of pages to write as offset and length
+// pairs. only the pages that changed are written, unless one of them was not
+// blank: then the sector is erased and every page that is not blank is
+// written. what would be done is added to `report`
+fn plan_sector(
+    original: &[u8],
+    data: &[u8],
+    report: &mut FlashReport,
+) -> (bool, Vec<(usize, usize)>) {
+    let page_size = PICO_PAGE_SIZE as usize;
+    let changed = original
+        .chunks(page_size)
+        .zip(data.chunks(page_size))
+        .map(|(old, new)| old != new)
+        .collect::<Vec<_>>();
+    let erase = original
+        .chunks(page_size)
+        .zip(&changed)
+        .any(|(old, changed)| *changed && old.iter().any(|b| *b != 0xFF));
+
+    let write = data
+        .chunks(page_size)
+        .zip(&changed)
+        .map(|(new, changed)| {
+            if erase {
+                new.iter().any(|b| *b != 0xFF)
+            } else {
+                *changed
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if erase {
+        report.sectors_erased += 1;
+    }
+    let mut runs = vec![];
+    let mut page = 0;
+    while page < write.len() {
+        if !write[page] {
+            if erase {
+                report.pages_skipped += 1;
+            }
+            page += 1;
+            continue;
+        }
+        let run = write[page..].iter().take_while(|w| **w).count();
+        runs.push((page * page_size, run * page_size));
+        report.pages_written += run as u32;
+        page += run;
+    }
+
+    (erase, runs)
+}
+
+fn io_error(e: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Caches reads from another [`MemoryRead`] a page at a time
+///
+/// Parsers tend to do many small reads close together, each of which would
+/// otherwise be a full USB round trip.
+pub(crate) struct PageCache<'a, M: MemoryRead + ?Sized> {
+    inner: &'a mut M,
+    pages: HashMap<u32, Vec<u8>>,
+}
+impl<'a, M: MemoryRead + ?Sized> PageCache<'a, M> {
+    pub(crate) fn new(inner: &'a mut M) -> Self {
+        PageCache {
+            inner,
+            pages: HashMap::new(),
+        }
+    }
+
+    fn page(&mut self, page_addr: u32) -> Result<&[u8]> {
+        if !self.pages.contains_key(&page_addr) {
+            let page = self.inner.read_memory(page_addr, PICO_PAGE_SIZE)?;
+            self.pages.insert(page_addr, page);
+        }
+
+        Ok(&self.pages[&page_addr])
+    }
+
+    fn read_cached(&mut self, addr: u32, size: u32) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(size as usize);
+        let mut addr = addr;
+        let end = addr.checked_add(size).ok_or(Error::MemoryOutOfRange)?;
+        while addr < end {
+            let page_addr = addr - addr % PICO_PAGE_SIZE;
+            let offset = (addr - page_addr) as usize;
+            let len = std::cmp::min(PICO_PAGE_SIZE - offset as u32, end - addr) as usize;
+            buf.extend_from_slice(&self.page(page_addr)?[offset..offset + len]);
+            addr += len as u32;
+        }
+
+        Ok(buf)
+    }
+}
+impl<M: MemoryRead + ?Sized> MemoryRead for PageCache<'_, M> {
+    fn read_memory(&mut self, addr: u32, size: u32) -> Result<Vec<u8>> {
+        match self.read_cached(addr, size) {
+            // the last page of an image may be cut short
+            Err(Error::MemoryOutOfRange) => self.inner.read_memory(addr, size),
+            res => res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: usize = PICO_PAGE_SIZE as usize;
+
+    // a sector where page 0 holds data, and every other page is blank
+    fn sector() -> Vec<u8> {
+        let mut data = vec![0xFF; PICO_SECTOR_SIZE as usize];
+        data[..PAGE].fill(0x11);
+        data
+    }
+
+    #[test]
+    fn plan_sector_unchanged() {
+        let mut report = FlashReport::default();
+        assert_eq!(
+            plan_sector(&sector(), &sector(), &mut report),
+            (false, vec![])
+        );
+        assert_eq!(report, FlashReport::default());
+    }
+
+    #[test]
+    fn plan_sector_blank_page() {
+        // writing into blank pages needs no erase
+        let mut data = sector();
+        data[2 * PAGE..4 * PAGE].fill(0x22);
+
+        let mut report = FlashReport::default();
+        assert_eq!(
+            plan_sector(&sector(), &data, &mut report),
+            (false, vec![(2 * PAGE, 2 * PAGE)])
+        );
+        assert_eq!(report.sectors_erased, 0);
+        assert_eq!(report.pages_written, 2);
+        assert_eq!(report.pages_skipped, 0);
+    }
+
+    #[test]
+    fn plan_sector_non_blank_page() {
+        // changing page 0 erases the sector, so the unchanged page 3 has to be
+        // written again too
+        let mut original = sector();
+        original[3 * PAGE] = 0x33;
+        let mut data = original.clone();
+        data[1] = 0x00;
+
+        let mut report = FlashReport::default();
+        assert_eq!(
+            plan_sector(&original, &data, &mut report),
+            (true, vec![(0, PAGE), (3 * PAGE, PAGE)])
+        );
+        assert_eq!(report.sectors_erased, 1);
+        assert_eq!(report.pages_written, 2);
+        assert_eq!(
+            report.pages_skipped,
+            (PICO_SECTOR_SIZE / PICO_PAGE_SIZE) - 2
+        );
+    }
+}
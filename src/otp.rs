@@ -0,0 +1,383 @@
+use crate::{backend::UsbBackend, cmd::PicobootError, usb::PicobootConnection};
+
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+// see https://datasheets.raspberrypi.com/rp2350/rp2350-datasheet.pdf
+// section 13.10 for the OTP data layout
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// First row of the critical flags, stored with 8-way redundancy
+pub const OTP_CRIT1_ROW: u16 = 0x040;
+const OTP_CRIT1_COPIES: u16 = 8;
+/// Critical flag: only boot signed images
+pub const OTP_CRIT1_SECURE_BOOT_ENABLE: u32 = 1 << 0;
+/// Critical flag: disable debug access to Secure code
+pub const OTP_CRIT1_SECURE_DEBUG_DISABLE: u32 = 1 << 1;
+/// Critical flag: disable all debug access
+pub const OTP_CRIT1_DEBUG_DISABLE: u32 = 1 << 2;
+
+/// First row of the boot flags that mark boot keys valid, stored with 3-way
+/// redundancy
+pub const OTP_BOOT_FLAGS1_ROW: u16 = 0x04B;
+pub(crate) const OTP_BOOT_FLAGS1_COPIES: u16 = 3;
+const OTP_BOOT_FLAGS1_KEY_VALID_LSB: u32 = 0;
+
+/// First row of the first boot key hash
+pub const OTP_BOOTKEY_ROW: u16 = 0x080;
+/// Number of boot key slots
+pub const OTP_BOOTKEY_COUNT: usize = 4;
+const OTP_BOOTKEY_ROWS: u16 = 16;
+
+const OTP_RAW_MASK: u32 = 0xFF_FFFF;
+
+/// Returns the hash of a public key, as stored in the boot key OTP rows.
+///
+/// - `public_key` - X and Y coordinates of the secp256k1 public key.
+pub fn boot_key_hash(public_key: &[u8; 64]) -> [u8; 32] {
+    Sha256::digest(public_key).into()
+}
+
+/// Decodes rows stored with redundancy, keeping the bits that are set in more
+/// than half of the copies.
+pub fn majority_vote(copies: &[u32]) -> u32 {
+    (0..32)
+        .filter(|bit| copies.iter().filter(|c| *c & 1 << bit != 0).count() * 2 > copies.len())
+        .fold(0, |flags, bit| flags | 1 << bit)
+}
+
+/// A single requested OTP write, see [`PicobootConnection::plan_otp`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtpWrite {
+    /// Write a 16-bit value to an ECC protected row. ECC rows can only be
+    /// written once.
+    Ecc { row: u16, value: u16 },
+    /// Set bits in a raw 24-bit row. Bits that are already set stay set.
+    RawBits { row: u16, bits: u32 },
+}
+
+/// A single change to an OTP row in an [`OtpPlan`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OtpChange {
+    /// Row being written.
+    pub row: u16,
+    /// Row is written with error correction.
+    pub ecc: bool,
+    /// Current value of the row.
+    pub current: u32,
+    /// Value of the row after the change.
+    pub new: u32,
+}
+impl fmt::Display for OtpChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.ecc {
+            write!(
+                f,
+                "row {:#05x} (ecc): {:#06x} -> {:#06x}",
+                self.row, self.current, self.new
+            )
+        } else {
+            write!(
+                f,
+                "row {:#05x} (raw): {:#08x} -> {:#08x}",
+                self.row, self.current, self.new
+            )
+        }
+    }
+}
+
+/// A dry run of irreversible OTP writes.
+///
+/// Made by [`PicobootConnection::plan_otp`] without touching the device, and
+/// only applied by [`PicobootConnection::apply_otp_plan`] together with the
+/// confirmation from [`Self::get_confirmation`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OtpPlan {
+    changes: Vec<OtpChange>,
+}
+impl OtpPlan {
+    /// Returns the changes the plan would make, in the order they are made.
+    pub fn get_changes(&self) -> &[OtpChange] {
+        &self.changes
+    }
+
+    /// Returns whether the plan would change nothing.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Returns the confirmation that must be passed to
+    /// [`PicobootConnection::apply_otp_plan`].
+    ///
+    /// The confirmation depends on every change in the plan, so it cannot be
+    /// reused for a different plan.
+    pub fn get_confirmation(&self) -> String {
+        let mut hasher = Sha256::new();
+        for c in &self.changes {
+            hasher.update(c.row.to_le_bytes());
+            hasher.update([c.ecc as u8]);
+            hasher.update(c.current.to_le_bytes());
+            hasher.update(c.new.to_le_bytes());
+        }
+        let hash = hasher.finalize();
+        format!(
+            "PERMANENTLY PROGRAM OTP {:02x}{:02x}{:02x}{:02x}",
+            hash[0], hash[1], hash[2], hash[3]
+        )
+    }
+}
+impl fmt::Display for OtpPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in &self.changes {
+            writeln!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+/// Secure boot configuration to provision with
+/// [`PicobootConnection::plan_secure_boot`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SecureBootConfig {
+    /// Public keys to install, as the X and Y coordinates of each secp256k1
+    /// public key. Installed into boot key slots in order and marked valid.
+    pub public_keys: Vec<[u8; 64]>,
+    /// Set the secure boot enable flag, after which only images signed with
+    /// one of the valid keys boot.
+    pub enable_secure_boot: bool,
+}
+
+impl<T: UsbBackend> PicobootConnection<T> {
+    /// Reads ECC protected OTP rows.
+    ///
+    /// # Errors:
+    /// - Any produced by [`Self::otp_read`]
+    pub fn otp_read_ecc(&mut self, row: u16, row_count: u16) -> Result<Vec<u16>> {
+        let buf = self.otp_read(row, row_count, true)?;
+        Ok(buf
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect())
+    }
+
+    /// Reads raw 24-bit OTP rows.
+    ///
+    /// # Errors:
+    /// - Any produced by [`Self::otp_read`]
+    pub fn otp_read_raw(&mut self, row: u16, row_count: u16) -> Result<Vec<u32>> {
+        let buf = self.otp_read(row, row_count, false)?;
+        Ok(buf
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]) & OTP_RAW_MASK)
+            .collect())
+    }
+
+    /// Reads raw OTP rows stored with redundancy and decodes them by majority
+    /// vote, see [`majority_vote`].
+    ///
+    /// # Errors:
+    /// - [`Error::UsbReadBulkMismatch`]
+    /// - Any produced by [`Self::otp_read`]
+    pub fn otp_read_redundant(&mut self, row: u16, copies: u16) -> Result<u32> {
+        let rows = self.otp_read_raw(row, copies)?;
+        if rows.len() != copies as usize {
+            return Err(Error::UsbReadBulkMismatch);
+        }
+
+        Ok(majority_vote(&rows))
+    }
+
+    // reads the current value of a row as it would appear in an OtpChange
+    fn otp_read_current(&mut self, row: u16, ecc: bool) -> Result<u32> {
+        let raw = self.otp_read_raw(row, 1)?;
+        let raw = *raw.first().ok_or(Error::UsbReadBulkMismatch)?;
+        if !ecc || raw == 0 {
+            return Ok(raw);
+        }
+
+        let value = self.otp_read_ecc(row, 1)?;
+        value
+            .first()
+            .map(|v| *v as u32)
+            .ok_or(Error::UsbReadBulkMismatch)
+    }
+
+    /// Plans OTP writes without making them.
+    ///
+    /// Rows that already hold the requested value are left out of the plan.
+    ///
+    /// - `writes` - Writes to plan, in the order they should be made.
+    ///
+    /// # Errors:
+    /// - [`Error::OtpConflict`]
+    /// - [`Error::OtpInvalidConfig`]
+    /// - Any produced by [`Self::otp_read`]
+    pub fn plan_otp(&mut self, writes: &[OtpWrite]) -> Result<OtpPlan> {
+        let mut plan = OtpPlan::default();
+        for write in writes {
+            let (row, ecc) = match *write {
+                OtpWrite::Ecc { row, .. } => (row, true),
+                OtpWrite::RawBits { row, .. } => (row, false),
+            };
+
+            let existing = plan.changes.iter().position(|c| c.row == row);
+            if existing.map_or(false, |i| plan.changes[i].ecc != ecc) {
+                return Err(Error::OtpInvalidConfig("row written both raw and with ecc"));
+            }
+            let current = match existing {
+                Some(i) => plan.changes[i].current,
+                None => self.otp_read_current(row, ecc)?,
+            };
+
+            let new = match *write {
+                OtpWrite::Ecc { value, .. } => {
+                    let value = value as u32;
+                    // an ecc row holding zero may still have been programmed
+                    let programmed = current != 0
+                        || existing.is_none() && self.otp_read_raw(row, 1)?.first() != Some(&0);
+                    if programmed && current != value {
+                        return Err(Error::OtpConflict(row));
+                    }
+                    if existing.map_or(false, |i| plan.changes[i].new != value) {
+                        return Err(Error::OtpInvalidConfig(
+                            "conflicting writes to the same row",
+                        ));
+                    }
+                    value
+                }
+                OtpWrite::RawBits { bits, .. } => {
+                    let prev = existing.map_or(current, |i| plan.changes[i].new);
+                    prev | (bits & OTP_RAW_MASK)
+                }
+            };
+
+            match existing {
+                Some(i) => plan.changes[i].new = new,
+                None if new != current => plan.changes.push(OtpChange {
+                    row,
+                    ecc,
+                    current,
+                    new,
+                }),
+                None => {}
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// Makes the OTP writes of a plan. This cannot be undone.
+    ///
+    /// Every row is checked to still hold the value the plan was made with
+    /// before anything is written, and each row is read back after it is
+    /// written.
+    ///
+    /// - `plan` - Plan made by [`Self::plan_otp`].
+    /// - `confirmation` - Must be exactly [`OtpPlan::get_confirmation`].
+    ///
+    /// # Errors:
+    /// - [`Error::OtpConfirmationMismatch`]
+    /// - [`Error::OtpPlanStale`]
+    /// - [`Error::OtpVerifyMismatch`]
+    /// - Any produced by [`Self::otp_read`] or [`Self::otp_write`]
+    pub fn apply_otp_plan(&mut self, plan: &OtpPlan, confirmation: &str) -> Result<()> {
+        if confirmation != plan.get_confirmation() {
+            return Err(Error::OtpConfirmationMismatch);
+        }
+
+        for c in &plan.changes {
+            if self.otp_read_current(c.row, c.ecc)? != c.current {
+                return Err(Error::OtpPlanStale);
+            }
+        }
+
+        for c in &plan.changes {
+            if c.ecc {
+                self.otp_write(c.row, &(c.new as u16).to_le_bytes(), true)?;
+            } else {
+                self.otp_write(c.row, &c.new.to_le_bytes(), false)?;
+            }
+
+            if self.otp_read_current(c.row, c.ecc)? != c.new {
+                return Err(Error::OtpVerifyMismatch(c.row));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Plans provisioning secure boot keys and flags without making any
+    /// changes.
+    ///
+    /// Key hashes are written first, then the keys are marked valid, and
+    /// secure boot is enabled last, so an interrupted provisioning never
+    /// leaves secure boot enabled without a valid key.
+    ///
+    /// - `config` - Keys and flags to provision.
+    ///
+    /// # Errors:
+    /// - [`Error::OtpInvalidConfig`]
+    /// - Any produced by [`Self::plan_otp`]
+    pub fn plan_secure_boot(&mut self, config: &SecureBootConfig) -> Result<OtpPlan> {
+        if config.public_keys.len() > OTP_BOOTKEY_COUNT {
+            return Err(Error::OtpInvalidConfig("too many boot keys"));
+        }
+        if config.enable_secure_boot && config.public_keys.is_empty() {
+            return Err(Error::OtpInvalidConfig(
+                "secure boot needs at least one boot key",
+            ));
+        }
+
+        let mut writes = vec![];
+        let mut key_valid = 0;
+        for (i, key) in config.public_keys.iter().enumerate() {
+            let first_row = OTP_BOOTKEY_ROW + i as u16 * OTP_BOOTKEY_ROWS;
+            let hash = boot_key_hash(key);
+            for (j, value) in hash.chunks_exact(2).enumerate() {
+                writes.push(OtpWrite::Ecc {
+                    row: first_row + j as u16,
+                    value: u16::from_le_bytes([value[0], value[1]]),
+                });
+            }
+            key_valid |= 1 << (OTP_BOOT_FLAGS1_KEY_VALID_LSB + i as u32);
+        }
+
+        if key_valid != 0 {
+            for i in 0..OTP_BOOT_FLAGS1_COPIES {
+                writes.push(OtpWrite::RawBits {
+                    row: OTP_BOOT_FLAGS1_ROW + i,
+                    bits: key_valid,
+                });
+            }
+        }
+
+        if config.enable_secure_boot {
+            for i in 0..OTP_CRIT1_COPIES {
+                writes.push(OtpWrite::RawBits {
+                    row: OTP_CRIT1_ROW + i,
+                    bits: OTP_CRIT1_SECURE_BOOT_ENABLE,
+                });
+            }
+        }
+
+        self.plan_otp(&writes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn majority_vote_copies() {
+        assert_eq!(majority_vote(&[0b0111, 0b0101, 0b1100]), 0b0101);
+        assert_eq!(majority_vote(&[0xFF_FFFF, 0, 0]), 0);
+        assert_eq!(majority_vote(&[0xFF_FFFF, 0xFF_FFFF, 0]), 0xFF_FFFF);
+        // an even split is not a majority
+        assert_eq!(majority_vote(&[1, 1, 1, 1, 0, 0, 0, 0]), 0);
+        assert_eq!(majority_vote(&[1, 1, 1, 1, 1, 0, 0, 0]), 1);
+        assert_eq!(majority_vote(&[]), 0);
+    }
+}
@@ -0,0 +1,632 @@
+use crate::{
+    backend::UsbBackend,
+    block::{Block, BlockItem, BlockLoop},
+    cmd::{PicobootError, TargetID},
+    info::{
+        INFO_PARTITION_INFO, PT_INFO_PARTITION_FAMILY_IDS, PT_INFO_PARTITION_ID,
+        PT_INFO_PARTITION_LOCATION_AND_FLAGS, PT_INFO_PARTITION_NAME, PT_INFO_PT_INFO,
+    },
+    usb::PicobootConnection,
+    PICO_FLASH_START, PICO_PAGE_SIZE, PICO_SECTOR_SIZE,
+};
+
+// see https://datasheets.raspberrypi.com/rp2350/rp2350-datasheet.pdf
+// section 5.9.4 for details on partition tables
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// Maximum number of partitions in a partition table
+pub const PARTITION_TABLE_MAX_PARTITIONS: usize = 16;
+/// Maximum number of extra UF2 family IDs a partition can accept
+pub const PARTITION_MAX_EXTRA_FAMILIES: usize = 3;
+/// Maximum length of a partition name in bytes
+pub const PARTITION_MAX_NAME_LEN: usize = 0x7F;
+
+const LOCATION_FIRST_SECTOR_LSB: u32 = 0;
+const LOCATION_LAST_SECTOR_LSB: u32 = 13;
+const LOCATION_SECTOR_MASK: u32 = 0x1FFF;
+const PERMISSIONS_LSB: u32 = 26;
+const PERMISSIONS_MASK: u32 = 0x3F;
+const PERMISSIONS_BITS: u32 = PERMISSIONS_MASK << PERMISSIONS_LSB;
+
+/// Partition has a 64-bit ID
+pub const PARTITION_FLAGS_HAS_ID: u32 = 1 << 0;
+const PARTITION_FLAGS_LINK_TYPE_LSB: u32 = 1;
+const PARTITION_FLAGS_LINK_VALUE_LSB: u32 = 3;
+const PARTITION_FLAGS_LINK_BITS: u32 = 0x3F << 1;
+const PARTITION_FLAGS_NUM_EXTRA_FAMILIES_LSB: u32 = 7;
+const PARTITION_FLAGS_NUM_EXTRA_FAMILIES_BITS: u32 = 0x3 << 7;
+/// Partition accepts UF2s with the absolute family ID
+pub const PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_ABSOLUTE: u32 = 1 << 9;
+/// Partition accepts UF2s with the RP2040 family ID
+pub const PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_RP2040: u32 = 1 << 10;
+/// Partition accepts UF2s with the RP2350 ARM Secure family ID
+pub const PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_ARM_S: u32 = 1 << 11;
+/// Partition accepts UF2s with the RP2350 RISC-V family ID
+pub const PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_RISCV: u32 = 1 << 12;
+/// Partition accepts UF2s with the RP2350 ARM Non-Secure family ID
+pub const PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_ARM_NS: u32 = 1 << 13;
+/// Partition accepts UF2s with the data family ID
+pub const PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_DATA: u32 = 1 << 14;
+/// UF2 downloads prefer the non-bootable partition of an A/B pair
+pub const PARTITION_FLAGS_UF2_DOWNLOAD_AB_NON_BOOTABLE_OWNER_AFFINITY: u32 = 1 << 15;
+/// Do not reboot after a UF2 download into the partition
+pub const PARTITION_FLAGS_UF2_DOWNLOAD_NO_REBOOT: u32 = 1 << 16;
+/// Partition is not considered when booting on ARM
+pub const PARTITION_FLAGS_IGNORED_DURING_ARM_BOOT: u32 = 1 << 17;
+/// Partition is not considered when booting on RISC-V
+pub const PARTITION_FLAGS_IGNORED_DURING_RISCV_BOOT: u32 = 1 << 18;
+/// Partition has a name
+pub const PARTITION_FLAGS_HAS_NAME: u32 = 1 << 19;
+
+// flags derived from the other fields of a partition when serializing
+const PARTITION_FLAGS_DERIVED: u32 = PARTITION_FLAGS_HAS_ID
+    | PARTITION_FLAGS_LINK_BITS
+    | PARTITION_FLAGS_NUM_EXTRA_FAMILIES_BITS
+    | PARTITION_FLAGS_HAS_NAME;
+
+/// Access permissions of a partition, or of unpartitioned space.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Permissions(u8);
+impl Permissions {
+    /// Readable from Secure code
+    pub const SECURE_READ: Self = Permissions(1 << 0);
+    /// Writable from Secure code
+    pub const SECURE_WRITE: Self = Permissions(1 << 1);
+    /// Readable from Non-Secure code
+    pub const NONSECURE_READ: Self = Permissions(1 << 2);
+    /// Writable from Non-Secure code
+    pub const NONSECURE_WRITE: Self = Permissions(1 << 3);
+    /// Readable from the bootloader (UF2 and PICOBOOT)
+    pub const BOOTSEL_READ: Self = Permissions(1 << 4);
+    /// Writable from the bootloader (UF2 and PICOBOOT)
+    pub const BOOTSEL_WRITE: Self = Permissions(1 << 5);
+    /// All permissions
+    pub const ALL: Self = Permissions(0x3F);
+
+    /// Creates permissions from their raw 6-bit representation.
+    pub fn from_bits(bits: u8) -> Self {
+        Permissions(bits & PERMISSIONS_MASK as u8)
+    }
+
+    /// Returns the raw 6-bit representation of the permissions.
+    pub fn get_bits(&self) -> u8 {
+        self.0
+    }
+
+    /// Returns whether all permissions in `other` are granted.
+    pub fn contains(&self, other: Permissions) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    fn from_word(word: u32) -> Self {
+        Permissions(((word >> PERMISSIONS_LSB) & PERMISSIONS_MASK) as u8)
+    }
+
+    fn to_word(self) -> u32 {
+        (self.0 as u32) << PERMISSIONS_LSB
+    }
+}
+impl std::ops::BitOr for Permissions {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Permissions(self.0 | rhs.0)
+    }
+}
+
+/// Link of a partition to another partition.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PartitionLink {
+    /// Not linked.
+    #[default]
+    None,
+    /// This is the B partition of an A/B pair, with the numbered A partition.
+    APartition(u8),
+    /// This partition is owned by the numbered partition.
+    OwnerPartition(u8),
+}
+/// A single partition of a [`PartitionTable`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Partition {
+    /// First flash sector of the partition.
+    pub first_sector: u16,
+    /// Last flash sector of the partition (inclusive).
+    pub last_sector: u16,
+    /// Access permissions of the partition.
+    pub permissions: Permissions,
+    /// Link to another partition.
+    pub link: PartitionLink,
+    /// Remaining `PARTITION_FLAGS_*` bits, e.g. accepted UF2 families.
+    pub flags: u32,
+    /// Optional 64-bit ID.
+    pub id: Option<u64>,
+    /// Additional UF2 family IDs accepted by the partition (up to 3).
+    pub extra_families: Vec<u32>,
+    /// Optional name (up to 127 bytes).
+    pub name: Option<String>,
+}
+impl Partition {
+    /// Returns the offset of the partition from the start of flash in bytes.
+    pub fn get_offset(&self) -> u32 {
+        self.first_sector as u32 * PICO_SECTOR_SIZE
+    }
+
+    /// Returns the size of the partition in bytes.
+    pub fn get_size(&self) -> u32 {
+        (self.last_sector as u32 + 1).saturating_sub(self.first_sector as u32) * PICO_SECTOR_SIZE
+    }
+
+    fn location_word(&self) -> u32 {
+        self.permissions.to_word()
+            | ((self.first_sector as u32 & LOCATION_SECTOR_MASK) << LOCATION_FIRST_SECTOR_LSB)
+            | ((self.last_sector as u32 & LOCATION_SECTOR_MASK) << LOCATION_LAST_SECTOR_LSB)
+    }
+
+    fn flags_word(&self) -> u32 {
+        let mut word = self.permissions.to_word()
+            | (self.flags & !PARTITION_FLAGS_DERIVED & !PERMISSIONS_BITS);
+
+        let (link_type, link_value) = match self.link {
+            PartitionLink::None => (0, 0),
+            PartitionLink::APartition(n) => (1, n),
+            PartitionLink::OwnerPartition(n) => (2, n),
+        };
+        word |= link_type << PARTITION_FLAGS_LINK_TYPE_LSB;
+        word |= (link_value as u32 & 0xF) << PARTITION_FLAGS_LINK_VALUE_LSB;
+        word |= (self.extra_families.len() as u32 & 0x3) << PARTITION_FLAGS_NUM_EXTRA_FAMILIES_LSB;
+
+        if self.id.is_some() {
+            word |= PARTITION_FLAGS_HAS_ID;
+        }
+        if self.name.is_some() {
+            word |= PARTITION_FLAGS_HAS_NAME;
+        }
+
+        word
+    }
+
+    /// Decodes a partition from its location and flags words, ignoring the
+    /// optional ID, families and name.
+    pub fn from_location_and_flags(location: u32, flags: u32) -> Self {
+        let link_value = ((flags >> PARTITION_FLAGS_LINK_VALUE_LSB) & 0xF) as u8;
+        let link = match (flags >> PARTITION_FLAGS_LINK_TYPE_LSB) & 0x3 {
+            1 => PartitionLink::APartition(link_value),
+            2 => PartitionLink::OwnerPartition(link_value),
+            _ => PartitionLink::None,
+        };
+
+        Partition {
+            first_sector: ((location >> LOCATION_FIRST_SECTOR_LSB) & LOCATION_SECTOR_MASK) as u16,
+            last_sector: ((location >> LOCATION_LAST_SECTOR_LSB) & LOCATION_SECTOR_MASK) as u16,
+            permissions: Permissions::from_word(location),
+            link,
+            flags: flags & !PARTITION_FLAGS_DERIVED & !PERMISSIONS_BITS,
+            id: None,
+            extra_families: vec![],
+            name: None,
+        }
+    }
+
+    // decodes a partition, including the optional fields its flags announce
+    fn decode(words: &mut impl Iterator<Item = u32>) -> Result<Self> {
+        let mut next = || words.next().ok_or(Error::BlockInvalidItemSize);
+
+        let location = next()?;
+        let flags = next()?;
+        let mut partition = Partition::from_location_and_flags(location, flags);
+
+        if flags & PARTITION_FLAGS_HAS_ID != 0 {
+            partition.id = Some(next()? as u64 | (next()? as u64) << 32);
+        }
+        let num_families = (flags & PARTITION_FLAGS_NUM_EXTRA_FAMILIES_BITS)
+            >> PARTITION_FLAGS_NUM_EXTRA_FAMILIES_LSB;
+        for _ in 0..num_families {
+            partition.extra_families.push(next()?);
+        }
+        if flags & PARTITION_FLAGS_HAS_NAME != 0 {
+            let first = next()?;
+            let len = (first & 0x7F) as usize;
+            let mut bytes = first.to_le_bytes()[1..].to_vec();
+            while bytes.len() < len {
+                bytes.extend(next()?.to_le_bytes());
+            }
+            bytes.truncate(len);
+            partition.name = Some(String::from_utf8_lossy(&bytes).into_owned());
+        }
+
+        Ok(partition)
+    }
+}
+
+/// Selects a partition of a [`PartitionTable`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionTarget {
+    /// Partition at an index in the table.
+    Index(u8),
+    /// First partition with a name.
+    Name(String),
+}
+
+/// Contents of a PARTITION_TABLE block item.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PartitionTable {
+    /// The table is the only one in flash, and the bootrom does not need to
+    /// search for another.
+    pub singleton: bool,
+    /// Access permissions of space not covered by any partition.
+    pub unpartitioned_permissions: Permissions,
+    /// Remaining `PARTITION_FLAGS_*` bits for unpartitioned space.
+    pub unpartitioned_flags: u32,
+    /// The partitions, in order.
+    pub partitions: Vec<Partition>,
+}
+impl PartitionTable {
+    /// Decodes a partition table from the words of a PARTITION_TABLE item,
+    /// including the item header.
+    ///
+    /// # Errors:
+    /// - [`Error::BlockInvalidItemSize`]
+    pub fn from_words(words: &[u32]) -> Result<Self> {
+        let header = *words.first().ok_or(Error::BlockInvalidItemSize)?;
+        let singleton = (header >> 16) & 0x1 != 0;
+        let count = (header >> 24) as usize;
+
+        let mut words = words[1..].iter().copied();
+        let unpartitioned = words.next().ok_or(Error::BlockInvalidItemSize)?;
+        let partitions = (0..count)
+            .map(|_| Partition::decode(&mut words))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(PartitionTable {
+            singleton,
+            unpartitioned_permissions: Permissions::from_word(unpartitioned),
+            unpartitioned_flags: unpartitioned & !PERMISSIONS_BITS,
+            partitions,
+        })
+    }
+
+    /// Decodes a partition table from the response to a GET_INFO
+    /// PARTITION_INFO request, see [`PicobootConnection::get_partition_table`].
+    ///
+    /// The response does not say whether the table is a singleton, so
+    /// [`Self::singleton`] is always false.
+    ///
+    /// # Errors:
+    /// - [`Error::PartitionTableNotFound`]
+    /// - [`Error::InfoMalformed`]
+    pub fn from_info_words(words: &[u32]) -> Result<Self> {
+        let mut words = words.iter().copied();
+        let mut next = || words.next().ok_or(Error::InfoMalformed);
+
+        let _included = next()?;
+        let info = next()?;
+        let count = (info & 0xFF) as usize;
+        if (info >> 8) & 0x1 == 0 {
+            return Err(Error::PartitionTableNotFound);
+        }
+        let _unpartitioned_location = next()?;
+        let unpartitioned = next()?;
+
+        let partitions = (0..count)
+            .map(|_| Partition::decode(&mut words).map_err(|_| Error::InfoMalformed))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(PartitionTable {
+            singleton: false,
+            unpartitioned_permissions: Permissions::from_word(unpartitioned),
+            unpartitioned_flags: unpartitioned & !PERMISSIONS_BITS,
+            partitions,
+        })
+    }
+
+    /// Checks that the bootrom would accept the partition table.
+    ///
+    /// - `flash_size` - Size of the flash in bytes, which all partitions must
+    ///   fit in.
+    ///
+    /// # Errors:
+    /// - [`Error::PartitionTableInvalid`]
+    pub fn validate(&self, flash_size: u32) -> Result<()> {
+        if self.partitions.len() > PARTITION_TABLE_MAX_PARTITIONS {
+            return Err(Error::PartitionTableInvalid("too many partitions"));
+        }
+
+        for (i, p) in self.partitions.iter().enumerate() {
+            if p.first_sector > p.last_sector {
+                return Err(Error::PartitionTableInvalid(
+                    "partition ends before it starts",
+                ));
+            }
+            if p.last_sector as u32 > LOCATION_SECTOR_MASK
+                || p.get_offset() + p.get_size() > flash_size
+            {
+                return Err(Error::PartitionTableInvalid("partition exceeds flash size"));
+            }
+            if p.extra_families.len() > PARTITION_MAX_EXTRA_FAMILIES {
+                return Err(Error::PartitionTableInvalid("too many extra families"));
+            }
+            if p.name
+                .as_ref()
+                .map_or(false, |n| n.len() > PARTITION_MAX_NAME_LEN)
+            {
+                return Err(Error::PartitionTableInvalid("partition name too long"));
+            }
+            match p.link {
+                PartitionLink::APartition(n) | PartitionLink::OwnerPartition(n)
+                    if n as usize >= self.partitions.len() || n as usize == i =>
+                {
+                    return Err(Error::PartitionTableInvalid("invalid partition link"));
+                }
+                _ => {}
+            }
+
+            let overlaps = self.partitions[..i]
+                .iter()
+                .any(|o| p.first_sector <= o.last_sector && o.first_sector <= p.last_sector);
+            if overlaps {
+                return Err(Error::PartitionTableInvalid("partitions overlap"));
+            }
+        }
+
+        // the item header counts its size, including itself, in a single byte
+        if 1 + self.to_payload_words().len() > 0xFF {
+            return Err(Error::PartitionTableInvalid("partition table too large"));
+        }
+
+        Ok(())
+    }
+
+    /// Finds a partition in the table.
+    ///
+    /// Returns the index of the partition along with the partition.
+    ///
+    /// - `target` - Partition to find.
+    pub fn find_partition(&self, target: &PartitionTarget) -> Option<(usize, &Partition)> {
+        match target {
+            PartitionTarget::Index(i) => self.partitions.get(*i as usize).map(|p| (*i as usize, p)),
+            PartitionTarget::Name(name) => self
+                .partitions
+                .iter()
+                .enumerate()
+                .find(|(_, p)| p.name.as_deref() == Some(name.as_str())),
+        }
+    }
+
+    /// Wraps the partition table in a block of its own, linked to itself.
+    pub fn to_block(&self) -> Block {
+        Block {
+            items: vec![BlockItem::PartitionTable(self.clone())],
+            link: 0,
+        }
+    }
+
+    /// Encodes the partition table as the payload of a PARTITION_TABLE item,
+    /// without the item header.
+    pub fn to_payload_words(&self) -> Vec<u32> {
+        let mut words = vec![
+            self.unpartitioned_permissions.to_word()
+                | (self.unpartitioned_flags & !PERMISSIONS_BITS),
+        ];
+
+        for p in &self.partitions {
+            words.push(p.location_word());
+            words.push(p.flags_word());
+            if let Some(id) = p.id {
+                words.push(id as u32);
+                words.push((id >> 32) as u32);
+            }
+            words.extend(p.extra_families.iter().take(3));
+            if let Some(name) = &p.name {
+                let name = name.as_bytes();
+                let len = std::cmp::min(name.len(), 0x7F);
+                let mut bytes = vec![len as u8];
+                bytes.extend_from_slice(&name[..len]);
+                while bytes.len() % 4 != 0 {
+                    bytes.push(0);
+                }
+                words.extend(
+                    bytes
+                        .chunks_exact(4)
+                        .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]])),
+                );
+            }
+        }
+
+        words
+    }
+}
+
+impl<T: UsbBackend> PicobootConnection<T> {
+    /// Returns the partition table the bootrom loaded at boot.
+    ///
+    /// # Errors:
+    /// - [`Error::PartitionTableNotFound`]
+    /// - Any produced by [`PartitionTable::from_info_words`] or [`Self::get_info`]
+    pub fn get_partition_table(&mut self) -> Result<PartitionTable> {
+        let flags = PT_INFO_PT_INFO
+            | PT_INFO_PARTITION_LOCATION_AND_FLAGS
+            | PT_INFO_PARTITION_ID
+            | PT_INFO_PARTITION_FAMILY_IDS
+            | PT_INFO_PARTITION_NAME;
+        let words = self.get_info(INFO_PARTITION_INFO, 0, 0, [flags, 0, 0])?;
+        PartitionTable::from_info_words(&words)
+    }
+
+    /// Reads the partition table from the block loop at the start of flash.
+    ///
+    /// Unlike [`Self::get_partition_table`], this sees a table that was
+    /// written since the last reboot, and includes [`PartitionTable::singleton`].
+    ///
+    /// # Errors:
+    /// - [`Error::PartitionTableNotFound`]
+    /// - Any produced by [`BlockLoop::find`]
+    pub fn read_partition_table(&mut self) -> Result<PartitionTable> {
+        let block_loop = match BlockLoop::find(self, PICO_FLASH_START) {
+            Err(Error::BlockNotFound) => return Err(Error::PartitionTableNotFound),
+            res => res?,
+        };
+        block_loop
+            .get_partition_table()
+            .map(|(_, table)| table.clone())
+            .ok_or(Error::PartitionTableNotFound)
+    }
+
+    /// Writes a partition table block to the start of flash.
+    ///
+    /// The table is validated against the flash size first, and no partition
+    /// may overlap the sectors the block itself occupies. The bootrom only
+    /// uses the new table after a reboot.
+    ///
+    /// The device should already be in exclusive access mode and out of XIP
+    /// mode, see [`Self::access_exclusive_eject`] and [`Self::exit_xip`].
+    ///
+    /// - `table` - Partition table to write.
+    ///
+    /// # Errors:
+    /// - [`Error::CmdNotAllowedForTarget`]
+    /// - [`Error::PartitionTableInvalid`]
+    /// - Any produced by [`Self::get_flash_size`], [`Self::flash_erase`] or [`Self::flash_write`]
+    pub fn write_partition_table(&mut self, table: &PartitionTable) -> Result<()> {
+        if let TargetID::Rp2040 = self.get_device_type() {
+            return Err(Error::CmdNotAllowedForTarget);
+        }
+
+        table.validate(self.get_flash_size()?)?;
+
+        let bytes = table.to_block().to_bytes()?;
+        let sectors = (bytes.len() as u32 + PICO_SECTOR_SIZE - 1) / PICO_SECTOR_SIZE;
+        if table
+            .partitions
+            .iter()
+            .any(|p| (p.first_sector as u32) < sectors)
+        {
+            return Err(Error::PartitionTableInvalid(
+                "partition overlaps the partition table",
+            ));
+        }
+
+        self.flash_erase(PICO_FLASH_START, sectors * PICO_SECTOR_SIZE)?;
+        for (i, chunk) in bytes.chunks(PICO_PAGE_SIZE as usize).enumerate() {
+            let mut page = chunk.to_vec();
+            page.resize(PICO_PAGE_SIZE as usize, 0);
+            self.flash_write(PICO_FLASH_START + i as u32 * PICO_PAGE_SIZE, &page)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLASH_SIZE: u32 = 4 * 1024 * 1024;
+
+    fn partition(first_sector: u16, last_sector: u16) -> Partition {
+        Partition {
+            first_sector,
+            last_sector,
+            permissions: Permissions::ALL,
+            ..Default::default()
+        }
+    }
+
+    fn ab_table() -> PartitionTable {
+        let mut a = partition(1, 0x1FF);
+        a.flags = PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_ARM_S;
+        a.id = Some(0x0123_4567_89AB_CDEF);
+        a.name = Some("A".to_string());
+
+        let mut b = partition(0x200, 0x3FE);
+        b.flags = PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_ARM_S;
+        b.link = PartitionLink::APartition(0);
+        b.extra_families = vec![0xE48BFF5A, 0xE48BFF5B];
+        b.name = Some("firmware B".to_string());
+
+        let mut data = partition(0x3FF, 0x3FF);
+        data.permissions = Permissions::SECURE_READ | Permissions::SECURE_WRITE;
+        data.link = PartitionLink::OwnerPartition(0);
+
+        PartitionTable {
+            singleton: true,
+            unpartitioned_permissions: Permissions::BOOTSEL_READ,
+            unpartitioned_flags: PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_DATA,
+            partitions: vec![a, b, data],
+        }
+    }
+
+    #[test]
+    fn payload_round_trip() {
+        let table = ab_table();
+        let words = BlockItem::PartitionTable(table.clone()).to_words().unwrap();
+        assert_eq!(words[1..], table.to_payload_words()[..]);
+        assert_eq!(PartitionTable::from_words(&words).unwrap(), table);
+    }
+
+    #[test]
+    fn flags_word() {
+        let table = ab_table();
+        let b = &table.partitions[1];
+        let flags = b.flags_word();
+        assert_eq!(
+            flags & PARTITION_FLAGS_LINK_BITS,
+            1 << PARTITION_FLAGS_LINK_TYPE_LSB
+        );
+        assert_eq!(flags & PARTITION_FLAGS_NUM_EXTRA_FAMILIES_BITS, 2 << 7);
+        assert_eq!(flags & PARTITION_FLAGS_HAS_ID, 0);
+        assert_ne!(flags & PARTITION_FLAGS_HAS_NAME, 0);
+
+        // bit 7 belongs to the extra family count, not the link
+        assert_eq!(
+            PARTITION_FLAGS_LINK_BITS & PARTITION_FLAGS_NUM_EXTRA_FAMILIES_BITS,
+            0
+        );
+    }
+
+    #[test]
+    fn validate() {
+        assert!(ab_table().validate(FLASH_SIZE).is_ok());
+        assert!(ab_table().validate(FLASH_SIZE / 2).is_err());
+
+        let mut table = ab_table();
+        table.partitions[1].first_sector = 0x1FF;
+        assert!(table.validate(FLASH_SIZE).is_err());
+
+        let mut table = ab_table();
+        table.partitions[1].link = PartitionLink::APartition(1);
+        assert!(table.validate(FLASH_SIZE).is_err());
+
+        let mut table = ab_table();
+        table.partitions[2].first_sector = 0x400;
+        assert!(table.validate(FLASH_SIZE).is_err());
+
+        let mut table = ab_table();
+        table.partitions[0].name = Some("a".repeat(PARTITION_MAX_NAME_LEN + 1));
+        assert!(table.validate(FLASH_SIZE).is_err());
+    }
+
+    #[test]
+    fn validate_size() {
+        // 16 partitions with long names do not fit the 1 byte item size
+        let mut table = PartitionTable::default();
+        for i in 0..PARTITION_TABLE_MAX_PARTITIONS as u16 {
+            let mut p = partition(1 + i * 4, 4 + i * 4);
+            p.id = Some(i as u64);
+            p.name = Some("n".repeat(PARTITION_MAX_NAME_LEN));
+            table.partitions.push(p);
+        }
+        assert!(matches!(
+            table.validate(FLASH_SIZE),
+            Err(Error::PartitionTableInvalid("partition table too large"))
+        ));
+        assert!(matches!(
+            table.to_block().to_bytes(),
+            Err(Error::BlockItemTooLarge)
+        ));
+
+        table.partitions.truncate(4);
+        assert!(table.validate(FLASH_SIZE).is_ok());
+    }
+}
@@ -0,0 +1,205 @@
+use crate::{
+    backend::{
+        sealed::{DeviceEntry, Handle},
+        UsbBackend,
+    },
+    cmd::PicobootError,
+    usb::{DeviceIdentity, PicobootConnection},
+};
+
+use std::time::Duration;
+
+// see https://github.com/raspberrypi/pico-sdk/blob/master/src/rp2_common/pico_stdio_usb/reset_interface.c
+// for the device side of the reset interface
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// USB interface class of the Pico SDK reset interface
+pub const RESET_INTERFACE_CLASS: u8 = 0xFF;
+/// USB interface subclass of the Pico SDK reset interface
+pub const RESET_INTERFACE_SUBCLASS: u8 = 0x00;
+/// USB interface protocol of the Pico SDK reset interface
+pub const RESET_INTERFACE_PROTOCOL: u8 = 0x01;
+
+/// Reset interface request to reboot into BOOTSEL mode
+const RESET_REQUEST_BOOTSEL: u8 = 0x01;
+/// Bit of [`BootselOptions::disable_interface_mask`] disabling PICOBOOT
+const BOOTSEL_DISABLE_PICOBOOT: u8 = 0x02;
+
+/// Options for rebooting a running application into BOOTSEL mode.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BootselOptions {
+    /// GPIO pin to use as an activity LED while in BOOTSEL mode, if any.
+    pub activity_led: Option<u8>,
+    /// Mask of BOOTSEL mode interfaces to disable. Bit 0 disables the USB
+    /// Mass Storage interface, bit 1 disables the PICOBOOT interface.
+    pub disable_interface_mask: u8,
+}
+impl BootselOptions {
+    fn to_value(self) -> u16 {
+        let mut value = (self.disable_interface_mask & 0x7F) as u16;
+        if let Some(pin) = self.activity_led {
+            value |= 0x100 | ((pin as u16 & 0x7F) << 9);
+        }
+        value
+    }
+}
+
+/// A running application exposing the Pico SDK reset interface
+///
+/// Applications built with the Pico SDK using `stdio_usb` expose a vendor
+/// "reset" interface alongside the CDC serial port, which can be used to
+/// reboot the board into BOOTSEL mode without pressing any buttons.
+#[derive(Debug)]
+pub struct ResetConnection<T: UsbBackend> {
+    context: T,
+    _entry: DeviceEntry<T::Device>,
+    handle: T::Handle,
+    iface: u8,
+    identity: DeviceIdentity,
+}
+impl<T: UsbBackend> Drop for ResetConnection<T> {
+    fn drop(&mut self) {
+        // the device has usually left the bus by now
+        let _ = self.handle.release_interface(self.iface);
+    }
+}
+impl<T: UsbBackend> ResetConnection<T> {
+    /// Creates a new reset interface connection
+    ///
+    /// Takes a USB backend (see [`UsbBackend`]) and an optional USB VID/PID
+    /// pair tuple. If `None` is provided, the first device exposing a reset
+    /// interface is used.
+    ///
+    /// # Errors
+    /// - [`Error::UsbDeviceNotFound`]
+    /// - [`Error::UsbOpenFailure`]
+    /// - [`Error::UsbClaimInterfaceFailure`]
+    pub fn new(ctx: T, vidpid: impl Into<Option<(u16, u16)>>) -> Result<Self> {
+        let vidpid = vidpid.into();
+        let devices = ctx.list_devices().map_err(|_| Error::UsbDeviceNotFound)?;
+
+        for entry in devices {
+            if let Some((vid, pid)) = vidpid {
+                if entry.vendor_id != vid || entry.product_id != pid {
+                    continue;
+                }
+            }
+
+            if let Some(iface) = Self::get_reset_interface(&entry) {
+                return Self::open(ctx, entry, iface);
+            }
+        }
+
+        Err(Error::UsbDeviceNotFound)
+    }
+
+    /// Connects to the reset interface of a device plugged into the same port
+    /// as `identity`, if it is running an application exposing one.
+    ///
+    /// # Errors
+    /// - [`Error::UsbOpenFailure`]
+    /// - [`Error::UsbClaimInterfaceFailure`]
+    pub(crate) fn find(ctx: T, identity: &DeviceIdentity) -> Result<Option<Self>> {
+        let devices = match ctx.list_devices() {
+            Ok(d) => d,
+            Err(_) => return Ok(None),
+        };
+
+        for entry in devices {
+            if entry.bus != identity.get_bus_number() || entry.ports != identity.get_port_numbers()
+            {
+                continue;
+            }
+
+            if let Some(iface) = Self::get_reset_interface(&entry) {
+                return Self::open(ctx, entry, iface).map(Some);
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn get_reset_interface(entry: &DeviceEntry<T::Device>) -> Option<u8> {
+        entry.find_interface(
+            RESET_INTERFACE_CLASS,
+            RESET_INTERFACE_SUBCLASS,
+            RESET_INTERFACE_PROTOCOL,
+        )
+    }
+
+    fn open(ctx: T, entry: DeviceEntry<T::Device>, iface: u8) -> Result<Self> {
+        let mut handle = ctx.open(&entry).map_err(Error::UsbOpenFailure)?;
+        handle
+            .claim_interface(iface)
+            .map_err(Error::UsbClaimInterfaceFailure)?;
+
+        let identity = DeviceIdentity::from_port(&entry);
+        Ok(ResetConnection {
+            context: ctx,
+            _entry: entry,
+            handle,
+            iface,
+            identity,
+        })
+    }
+
+    /// Returns the identity of the connected device.
+    ///
+    /// The serial number is left out, as the application and the bootrom may
+    /// report different ones.
+    pub fn get_identity(&self) -> &DeviceIdentity {
+        &self.identity
+    }
+
+    /// Reboots the application into BOOTSEL mode.
+    ///
+    /// The device may drop off the bus before the request completes, which is
+    /// not treated as an error.
+    ///
+    /// # Errors
+    /// - [`Error::UsbBootselRequestFailure`]
+    pub fn reboot_to_bootsel(mut self, options: BootselOptions) -> Result<()> {
+        let timeout = Duration::from_secs(2);
+        let setup = (
+            0b00100001,
+            RESET_REQUEST_BOOTSEL,
+            options.to_value(),
+            self.iface.into(),
+        );
+        let res = self.handle.write_control(setup, &[0u8; 0], timeout);
+
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) if e.is_disconnected() => Ok(()),
+            Err(e) => Err(Error::UsbBootselRequestFailure(e)),
+        }
+    }
+
+    /// Reboots the application into BOOTSEL mode and connects to the PICOBOOT
+    /// interface once the device has re-enumerated.
+    ///
+    /// `options` must not disable the PICOBOOT interface.
+    ///
+    /// # Errors
+    /// - [`Error::UsbBootselPicobootDisabled`]
+    /// - Any produced by [`Self::reboot_to_bootsel`]
+    /// - Any produced by [`PicobootConnection::wait_for_device`]
+    pub fn reboot_to_picoboot(
+        self,
+        options: BootselOptions,
+        timeout: Duration,
+    ) -> Result<PicobootConnection<T>> {
+        // the device would never show up, so fail before rebooting it
+        if options.disable_interface_mask & BOOTSEL_DISABLE_PICOBOOT != 0 {
+            return Err(Error::UsbBootselPicobootDisabled);
+        }
+
+        let ctx = self.context.clone();
+        let identity = self.identity.clone();
+        self.reboot_to_bootsel(options)?;
+
+        PicobootConnection::wait_for_device(ctx, &identity, timeout)
+    }
+}
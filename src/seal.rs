@@ -0,0 +1,375 @@
+use crate::{
+    block::{
+        bytes_to_words, words_to_bytes, Block, BlockItem, BlockLoop, LoadMapEntry, LocatedBlock,
+        PICOBIN_HASH_SHA256, PICOBIN_SIGNATURE_SECP256K1,
+    },
+    cmd::PicobootError,
+};
+
+use k256::ecdsa::{
+    signature::hazmat::{PrehashSigner, PrehashVerifier},
+    Signature, SigningKey, VerifyingKey,
+};
+use k256::pkcs8::DecodePrivateKey;
+use sha2::{Digest, Sha256};
+
+// see https://datasheets.raspberrypi.com/rp2350/rp2350-datasheet.pdf
+// section 5.10 for details on image hashing and signing
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// Result of verifying a sealed image with [`verify_image`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SealStatus {
+    /// SHA-256 hash of the image, as defined by its HASH_DEF.
+    pub hash: [u8; 32],
+    /// Public key the image is signed with, as the X and Y coordinates of
+    /// the curve point. `None` if the image is only hashed.
+    pub public_key: Option<[u8; 64]>,
+}
+
+/// Loads a secp256k1 private key from a PEM file.
+///
+/// Both SEC1 (`EC PRIVATE KEY`, as produced by `openssl ecparam -genkey`)
+/// and PKCS#8 (`PRIVATE KEY`) encodings are accepted.
+///
+/// - `pem` - Contents of the PEM file.
+///
+/// # Errors:
+/// - [`Error::SealKeyInvalid`]
+pub fn signing_key_from_pem(pem: &str) -> Result<SigningKey> {
+    match k256::SecretKey::from_sec1_pem(pem) {
+        Ok(key) => Ok(SigningKey::from(key)),
+        Err(_) => SigningKey::from_pkcs8_pem(pem).map_err(|_| Error::SealKeyInvalid),
+    }
+}
+
+/// Returns the public key of a signing key, as the X and Y coordinates of
+/// the curve point.
+///
+/// This is the form stored in SIGNATURE items, and hashed into the boot key
+/// OTP rows.
+pub fn public_key_bytes(key: &SigningKey) -> [u8; 64] {
+    let point = key.verifying_key().to_encoded_point(false);
+    let mut bytes = [0u8; 64];
+    bytes.copy_from_slice(&point.as_bytes()[1..]);
+    bytes
+}
+
+/// Signs an RP2350 image for secure boot.
+///
+/// A new block is appended to the image and linked into its block loop. It
+/// holds a copy of the items of the current IMAGE_DEF block, an absolute
+/// LOAD_MAP, a HASH_DEF and a SIGNATURE item. As the last IMAGE_DEF in the
+/// loop, the new block is the one the bootrom uses.
+///
+/// Without an existing LOAD_MAP, the image is hashed from its start up to
+/// the new block.
+///
+/// - `image` - Image to sign.
+/// - `base` - Address the image is loaded at.
+/// - `key` - Key to sign with, see [`signing_key_from_pem`].
+///
+/// # Errors:
+/// - [`Error::SealInvalid`]
+/// - [`Error::SealKeyInvalid`]
+/// - [`Error::BlockItemTooLarge`]
+/// - Any produced by [`BlockLoop::from_image`]
+pub fn seal_image(image: &[u8], base: u32, key: &SigningKey) -> Result<Vec<u8>> {
+    let block_loop = BlockLoop::from_image(image, base)?;
+    let (def_block, _) = block_loop
+        .get_image_def()
+        .ok_or(Error::SealInvalid("no IMAGE_DEF in block loop"))?;
+
+    let mut out = image.to_vec();
+    while out.len() % 4 != 0 {
+        out.push(0);
+    }
+    let new_addr = base + out.len() as u32;
+
+    // link the new block in after the last block of the loop
+    let blocks = block_loop.get_blocks();
+    let first = &blocks[0];
+    let last = &blocks[blocks.len() - 1];
+    let link_offset = (last.addr + last.size - 8 - base) as usize;
+    let link = new_addr.wrapping_sub(last.addr);
+    out[link_offset..link_offset + 4].copy_from_slice(&link.to_le_bytes());
+
+    let mut load_map = None;
+    let mut items = vec![];
+    for (i, item) in def_block.block.items.iter().enumerate() {
+        match item {
+            BlockItem::HashDef { .. } | BlockItem::HashValue(_) | BlockItem::Signature { .. } => {}
+            BlockItem::LoadMap { .. } => load_map = Some(absolute_load_map(def_block, i)?),
+            item => items.push(item.clone()),
+        }
+    }
+    let entries = load_map.unwrap_or_else(|| {
+        vec![LoadMapEntry {
+            storage_addr: base,
+            runtime_addr: base,
+            size: new_addr - base,
+        }]
+    });
+    items.push(BlockItem::LoadMap {
+        absolute: true,
+        entries: entries.clone(),
+    });
+
+    // the hashed words run from the start marker to the end of the HASH_DEF
+    let items_words = items
+        .iter()
+        .map(|i| i.to_words().map(|w| w.len()))
+        .sum::<Result<usize>>()?;
+    let block_words_hashed = 1 + items_words + 2;
+    items.push(BlockItem::HashDef {
+        hash_type: PICOBIN_HASH_SHA256,
+        block_words_hashed: block_words_hashed as u16,
+    });
+    let mut block = Block {
+        items,
+        link: first.addr.wrapping_sub(new_addr) as i32,
+    };
+
+    let block_words = block.to_words()?;
+    let hash = hash_contents(&out, base, &entries, &block_words[..block_words_hashed])?;
+
+    let signature: Signature = key.sign_prehash(&hash).map_err(|_| Error::SealKeyInvalid)?;
+    let mut sig_bytes = [0u8; 64];
+    sig_bytes.copy_from_slice(&signature.to_bytes());
+    block.items.push(BlockItem::Signature {
+        sig_type: PICOBIN_SIGNATURE_SECP256K1,
+        public_key: public_key_bytes(key),
+        signature: sig_bytes,
+    });
+
+    out.extend(block.to_bytes()?);
+    Ok(out)
+}
+
+/// Verifies the hash and signature of an RP2350 image.
+///
+/// - `image` - Image to verify.
+/// - `base` - Address the image is loaded at.
+///
+/// # Errors:
+/// - [`Error::SealNotFound`]
+/// - [`Error::SealInvalid`]
+/// - [`Error::SealMismatch`]
+/// - Any produced by [`BlockLoop::from_image`]
+pub fn verify_image(image: &[u8], base: u32) -> Result<SealStatus> {
+    let block_loop = BlockLoop::from_image(image, base)?;
+    let (def_block, _) = block_loop
+        .get_image_def()
+        .ok_or(Error::SealInvalid("no IMAGE_DEF in block loop"))?;
+
+    let mut hash_def = None;
+    let mut hash_value = None;
+    let mut signature = None;
+    let mut load_map = None;
+    for (i, item) in def_block.block.items.iter().enumerate() {
+        match item {
+            BlockItem::HashDef {
+                hash_type,
+                block_words_hashed,
+            } => hash_def = Some((*hash_type, *block_words_hashed as usize)),
+            BlockItem::HashValue(value) => hash_value = Some(value),
+            BlockItem::Signature {
+                sig_type,
+                public_key,
+                signature: sig,
+            } => signature = Some((*sig_type, public_key, sig)),
+            BlockItem::LoadMap { .. } => load_map = Some(absolute_load_map(def_block, i)?),
+            _ => {}
+        }
+    }
+
+    let (hash_type, block_words_hashed) = hash_def.ok_or(Error::SealNotFound)?;
+    if hash_type != PICOBIN_HASH_SHA256 {
+        return Err(Error::SealInvalid("unsupported hash type"));
+    }
+    let entries = load_map.ok_or(Error::SealInvalid("no LOAD_MAP in IMAGE_DEF block"))?;
+
+    let start = (def_block.addr - base) as usize;
+    let block_words = bytes_to_words(&image[start..start + def_block.size as usize]);
+    if block_words_hashed > block_words.len() {
+        return Err(Error::SealInvalid("HASH_DEF covers more than the block"));
+    }
+    let hash = hash_contents(image, base, &entries, &block_words[..block_words_hashed])?;
+
+    if let Some(value) = hash_value {
+        if value.is_empty() || value.len() > hash.len() || hash[..value.len()] != value[..] {
+            return Err(Error::SealMismatch);
+        }
+    }
+
+    let public_key = match signature {
+        Some((PICOBIN_SIGNATURE_SECP256K1, public_key, sig)) => {
+            let mut point = vec![0x04];
+            point.extend_from_slice(public_key);
+            let key = VerifyingKey::from_sec1_bytes(&point)
+                .map_err(|_| Error::SealInvalid("invalid public key"))?;
+            let sig =
+                Signature::from_slice(sig).map_err(|_| Error::SealInvalid("invalid signature"))?;
+            key.verify_prehash(&hash, &sig)
+                .map_err(|_| Error::SealMismatch)?;
+            Some(*public_key)
+        }
+        Some(_) => return Err(Error::SealInvalid("unsupported signature type")),
+        None if hash_value.is_none() => return Err(Error::SealNotFound),
+        None => None,
+    };
+
+    Ok(SealStatus { hash, public_key })
+}
+
+// returns the entries of the LOAD_MAP item at `index` with absolute storage
+// addresses, relative ones are offsets from the address of the item itself
+fn absolute_load_map(block: &LocatedBlock, index: usize) -> Result<Vec<LoadMapEntry>> {
+    let items = &block.block.items;
+    let offset = items[..index]
+        .iter()
+        .map(|i| i.to_words().map(|w| w.len()))
+        .sum::<Result<usize>>()?;
+    let item_addr = block.addr + 4 * (1 + offset as u32);
+
+    Ok(match &items[index] {
+        BlockItem::LoadMap { absolute, entries } => entries
+            .iter()
+            .map(|e| LoadMapEntry {
+                storage_addr: if *absolute || e.storage_addr == 0 {
+                    e.storage_addr
+                } else {
+                    item_addr.wrapping_add(e.storage_addr)
+                },
+                ..*e
+            })
+            .collect(),
+        _ => vec![],
+    })
+}
+
+// hashes the stored contents of each load map entry, then the block words.
+// zero fill entries have no stored contents and are skipped
+fn hash_contents(
+    image: &[u8],
+    base: u32,
+    entries: &[LoadMapEntry],
+    block_words: &[u32],
+) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    for e in entries.iter().filter(|e| e.storage_addr != 0) {
+        let start = e.storage_addr.wrapping_sub(base) as usize;
+        let data = start
+            .checked_add(e.size as usize)
+            .and_then(|end| image.get(start..end))
+            .ok_or(Error::SealInvalid("LOAD_MAP entry outside of image"))?;
+        hasher.update(data);
+    }
+    hasher.update(words_to_bytes(block_words));
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{ExeCpu, ExeSecurity, ImageDef};
+
+    const BASE: u32 = 0x10000000;
+
+    // 0x40 bytes of code followed by an IMAGE_DEF block with a relative
+    // LOAD_MAP pointing back at the code
+    fn unsealed_image() -> Vec<u8> {
+        let mut image: Vec<u8> = (0..0x40).collect();
+        let block = Block {
+            items: vec![
+                BlockItem::ImageDef(ImageDef::exe(ExeCpu::Arm, ExeSecurity::Secure)),
+                BlockItem::LoadMap {
+                    absolute: false,
+                    entries: vec![LoadMapEntry {
+                        // the LOAD_MAP item sits 8 bytes into the block
+                        storage_addr: 0x48u32.wrapping_neg(),
+                        runtime_addr: BASE,
+                        size: 0x40,
+                    }],
+                },
+            ],
+            link: 0,
+        };
+        image.extend(block.to_bytes().unwrap());
+        image
+    }
+
+    fn test_key() -> SigningKey {
+        SigningKey::from_slice(&[0x11; 32]).unwrap()
+    }
+
+    #[test]
+    fn seal_round_trip() {
+        let key = test_key();
+        let sealed = seal_image(&unsealed_image(), BASE, &key).unwrap();
+
+        let status = verify_image(&sealed, BASE).unwrap();
+        assert_eq!(status.public_key, Some(public_key_bytes(&key)));
+
+        let block_loop = BlockLoop::from_image(&sealed, BASE).unwrap();
+        let (def_block, _) = block_loop.get_image_def().unwrap();
+        assert_eq!(def_block.addr, BASE + unsealed_image().len() as u32);
+        let entries = def_block.block.items.iter().find_map(|i| match i {
+            BlockItem::LoadMap { absolute, entries } => Some((*absolute, entries.clone())),
+            _ => None,
+        });
+        let expected = LoadMapEntry {
+            storage_addr: BASE,
+            runtime_addr: BASE,
+            size: 0x40,
+        };
+        assert_eq!(entries, Some((true, vec![expected])));
+    }
+
+    #[test]
+    fn seal_tampered() {
+        let mut sealed = seal_image(&unsealed_image(), BASE, &test_key()).unwrap();
+        sealed[5] ^= 0x01;
+        assert!(matches!(
+            verify_image(&sealed, BASE),
+            Err(Error::SealMismatch)
+        ));
+    }
+
+    // hash-only image in the layout picotool produces: an absolute LOAD_MAP
+    // storing runtime end addresses, including a zero fill entry, followed
+    // by HASH_DEF and HASH_VALUE
+    #[test]
+    fn verify_golden_hash() {
+        let hash = [
+            0x8C217DDB, 0x4A74C37E, 0x20A36219, 0x1DDD8652, 0xAB3DDBB5, 0x8DEE8CA7, 0x3E16E91B,
+            0xB37C4EEA,
+        ];
+        let mut words = vec![
+            0xFFFFDED3, // start marker
+            0x10210142, // IMAGE_DEF: secure ARM executable for RP2350
+            0x82000706, // absolute LOAD_MAP with 2 entries
+            0x10000000, 0x10000000, 0x10000020, // copy 0x20 bytes in place
+            0x00000000, 0x20000000, 0x20000100, // zero fill 0x100 bytes of SRAM
+            0x01000247, 11,         // HASH_DEF: SHA-256 over 11 block words
+            0x0000094B, // HASH_VALUE
+        ];
+        words.extend(hash);
+        words.extend([0x000013FF, 0, 0xAB123579]);
+
+        let mut image: Vec<u8> = (0..0x20).collect();
+        image.extend(words_to_bytes(&words));
+
+        let status = verify_image(&image, BASE).unwrap();
+        assert_eq!(status.hash[..], words_to_bytes(&hash)[..]);
+        assert_eq!(status.public_key, None);
+
+        image[0] ^= 0x01;
+        assert!(matches!(
+            verify_image(&image, BASE),
+            Err(Error::SealMismatch)
+        ));
+    }
+}
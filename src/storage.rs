@@ -0,0 +1,130 @@
+use crate::{
+    backend::UsbBackend, cmd::PicobootError, memory::MemoryRead, usb::PicobootConnection,
+    PICO_PAGE_SIZE, PICO_SECTOR_SIZE,
+};
+
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError,
+    NorFlashErrorKind, ReadNorFlash,
+};
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// A region of device flash as an `embedded-storage` NOR flash
+///
+/// Lets crates written against [`NorFlash`], such as on-flash file systems
+/// and key-value stores, read and populate the flash of a device in BOOTSEL
+/// mode. Offsets are relative to the start of the region.
+///
+/// Programming only ever clears bits, so a page may be written more than once
+/// between erases, see [`MultiwriteNorFlash`].
+#[derive(Debug)]
+pub struct FlashStorage<'a, T: UsbBackend> {
+    conn: &'a mut PicobootConnection<T>,
+    base: u32,
+    size: u32,
+}
+impl<'a, T: UsbBackend> FlashStorage<'a, T> {
+    /// Creates a NOR flash over a region of device flash.
+    ///
+    /// The device should already be in exclusive access mode and out of XIP
+    /// mode, see [`PicobootConnection::access_exclusive_eject`] and
+    /// [`PicobootConnection::exit_xip`].
+    ///
+    /// - `conn` - Connection to the device.
+    /// - `base` - Address of the start of the region. Must be on a multiple of [`PICO_SECTOR_SIZE`].
+    /// - `size` - Size of the region in bytes. Must be a multiple of [`PICO_SECTOR_SIZE`].
+    ///
+    /// # Errors:
+    /// - [`Error::EraseInvalidAddr`]
+    /// - [`Error::EraseInvalidSize`]
+    /// - [`Error::MemoryOutOfRange`]
+    pub fn new(conn: &'a mut PicobootConnection<T>, base: u32, size: u32) -> Result<Self> {
+        if base % PICO_SECTOR_SIZE != 0 {
+            return Err(Error::EraseInvalidAddr);
+        }
+        if size % PICO_SECTOR_SIZE != 0 {
+            return Err(Error::EraseInvalidSize);
+        }
+        if base.checked_add(size).is_none() {
+            return Err(Error::MemoryOutOfRange);
+        }
+
+        Ok(FlashStorage { conn, base, size })
+    }
+
+    /// Returns the address of the start of the region.
+    pub fn get_base(&self) -> u32 {
+        self.base
+    }
+}
+
+impl NorFlashError for PicobootError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Error::EraseInvalidAddr | Error::EraseInvalidSize | Error::WriteInvalidAddr => {
+                NorFlashErrorKind::NotAligned
+            }
+            Error::MemoryOutOfRange => NorFlashErrorKind::OutOfBounds,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+// converts a failed argument check, using `not_aligned` for misalignment
+fn check_error(kind: NorFlashErrorKind, not_aligned: Error) -> Error {
+    match kind {
+        NorFlashErrorKind::NotAligned => not_aligned,
+        _ => Error::MemoryOutOfRange,
+    }
+}
+
+impl<T: UsbBackend> ErrorType for FlashStorage<'_, T> {
+    type Error = PicobootError;
+}
+impl<T: UsbBackend> ReadNorFlash for FlashStorage<'_, T> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<()> {
+        check_read(self, offset, bytes.len())
+            .map_err(|k| check_error(k, Error::MemoryOutOfRange))?;
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        let data = self
+            .conn
+            .read_memory(self.base + offset, bytes.len() as u32)?;
+        bytes.copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.size as usize
+    }
+}
+impl<T: UsbBackend> NorFlash for FlashStorage<'_, T> {
+    const WRITE_SIZE: usize = PICO_PAGE_SIZE as usize;
+    const ERASE_SIZE: usize = PICO_SECTOR_SIZE as usize;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<()> {
+        check_erase(self, from, to).map_err(|k| check_error(k, Error::EraseInvalidAddr))?;
+        if from == to {
+            return Ok(());
+        }
+
+        self.conn.flash_erase(self.base + from, to - from)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<()> {
+        check_write(self, offset, bytes.len())
+            .map_err(|k| check_error(k, Error::WriteInvalidAddr))?;
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        self.conn.flash_write(self.base + offset, bytes)
+    }
+}
+impl<T: UsbBackend> MultiwriteNorFlash for FlashStorage<'_, T> {}
@@ -0,0 +1,220 @@
+use crate::{
+    backend::UsbBackend,
+    cmd::{PicobootError, TargetID},
+    usb::PicobootConnection,
+};
+
+// The RP2040 bootrom has no commands to talk to the flash chip directly, so
+// small Thumb functions are copied to RAM and called with EXEC instead. Each
+// stub takes its parameters from, and leaves its results in, the RAM at
+// STUB_PARAMS_ADDR. The stubs expect the SSI to be set up for serial
+// transfers by EXIT_XIP, and select the flash the same way the bootrom does,
+// by overriding the QSPI chip select pin.
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// RAM address stubs are copied to and called at
+pub const STUB_ADDR: u32 = 0x20000000;
+/// RAM address of the parameters and results of a stub
+pub const STUB_PARAMS_ADDR: u32 = 0x20000100;
+
+// Clocks the bytes following a length word at STUB_PARAMS_ADDR out to the
+// flash, replacing each with the byte clocked in.
+//
+//     push  {r4, r5, lr}
+//     ldr   r4, =0x4001800c    @ IO_QSPI GPIO_QSPI_SS_CTRL
+//     ldr   r5, =0x18000000    @ XIP_SSI
+//     ldr   r0, [r4]           @ drive chip select low
+//     movs  r1, #3
+//     lsls  r1, r1, #8
+//     bics  r0, r1
+//     movs  r1, #2
+//     lsls  r1, r1, #8
+//     orrs  r0, r1
+//     str   r0, [r4]
+//     ldr   r0, =0x20000100
+//     ldr   r1, [r0]           @ byte count
+//     adds  r0, #4
+// 1:  cmp   r1, #0
+//     beq   3f
+//     ldrb  r2, [r0]
+//     str   r2, [r5, #0x60]    @ DR0
+// 2:  ldr   r2, [r5, #0x28]    @ wait for SR.RFNE
+//     lsrs  r2, r2, #4
+//     bcc   2b
+//     ldr   r2, [r5, #0x60]
+//     strb  r2, [r0]
+//     adds  r0, #1
+//     subs  r1, #1
+//     b     1b
+// 3:  ldr   r0, [r4]           @ drive chip select high
+//     movs  r1, #3
+//     lsls  r1, r1, #8
+//     orrs  r0, r1
+//     str   r0, [r4]
+//     pop   {r4, r5, pc}
+const SPI_TRANSFER_STUB: [u8; 76] = [
+    0x30, 0xB5, 0x0F, 0x4C, 0x0F, 0x4D, 0x20, 0x68, 0x03, 0x21, 0x09, 0x02, //
+    0x88, 0x43, 0x02, 0x21, 0x09, 0x02, 0x08, 0x43, 0x20, 0x60, 0x0C, 0x48, //
+    0x01, 0x68, 0x04, 0x30, 0x00, 0x29, 0x09, 0xD0, 0x02, 0x78, 0x2A, 0x66, //
+    0xAA, 0x6A, 0x12, 0x09, 0xFC, 0xD3, 0x2A, 0x6E, 0x02, 0x70, 0x01, 0x30, //
+    0x01, 0x39, 0xF3, 0xE7, 0x20, 0x68, 0x03, 0x21, 0x09, 0x02, 0x08, 0x43, //
+    0x20, 0x60, 0x30, 0xBD, 0x0C, 0x80, 0x01, 0x40, 0x00, 0x00, 0x00, 0x18, //
+    0x00, 0x01, 0x00, 0x20, //
+];
+
+// Counts the 0xFF bytes at the start of a flash range, reading it with the
+// 03h command. Takes the flash address and byte count from the first two
+// words at STUB_PARAMS_ADDR, and leaves the count in the third.
+//
+//     push  {r4, r5, r6, lr}
+//     ldr   r4, =0x4001800c    @ IO_QSPI GPIO_QSPI_SS_CTRL
+//     ldr   r5, =0x18000000    @ XIP_SSI
+//     ldr   r6, =0x20000100
+//     ldr   r0, [r4]           @ drive chip select low
+//     movs  r1, #3
+//     lsls  r1, r1, #8
+//     bics  r0, r1
+//     movs  r1, #2
+//     lsls  r1, r1, #8
+//     orrs  r0, r1
+//     str   r0, [r4]
+//     ldr   r1, [r6]           @ 03h and the low 24 bits of the address
+//     lsls  r1, r1, #8
+//     lsrs  r1, r1, #8
+//     movs  r2, #3
+//     lsls  r2, r2, #24
+//     orrs  r1, r2
+//     movs  r3, #4
+// 1:  lsrs  r2, r1, #24
+//     bl    xfer
+//     lsls  r1, r1, #8
+//     subs  r3, #1
+//     bne   1b
+//     ldr   r1, [r6, #4]       @ byte count
+//     movs  r3, #0
+// 2:  cmp   r3, r1
+//     beq   3f
+//     movs  r2, #0
+//     bl    xfer
+//     cmp   r2, #0xff
+//     bne   3f
+//     adds  r3, #1
+//     b     2b
+// 3:  str   r3, [r6, #8]
+//     ldr   r0, [r4]           @ drive chip select high
+//     movs  r1, #3
+//     lsls  r1, r1, #8
+//     orrs  r0, r1
+//     str   r0, [r4]
+//     pop   {r4, r5, r6, pc}
+// xfer:                        @ sends r2, and returns the byte received in r2
+//     str   r2, [r5, #0x60]    @ DR0
+// 4:  ldr   r2, [r5, #0x28]    @ wait for SR.RFNE
+//     lsrs  r2, r2, #4
+//     bcc   4b
+//     ldr   r2, [r5, #0x60]
+//     bx    lr
+const BLANK_CHECK_STUB: [u8; 112] = [
+    0x70, 0xB5, 0x18, 0x4C, 0x18, 0x4D, 0x19, 0x4E, 0x20, 0x68, 0x03, 0x21, //
+    0x09, 0x02, 0x88, 0x43, 0x02, 0x21, 0x09, 0x02, 0x08, 0x43, 0x20, 0x60, //
+    0x31, 0x68, 0x09, 0x02, 0x09, 0x0A, 0x03, 0x22, 0x12, 0x06, 0x11, 0x43, //
+    0x04, 0x23, 0x0A, 0x0E, 0x00, 0xF0, 0x15, 0xF8, 0x09, 0x02, 0x01, 0x3B, //
+    0xF9, 0xD1, 0x71, 0x68, 0x00, 0x23, 0x8B, 0x42, 0x06, 0xD0, 0x00, 0x22, //
+    0x00, 0xF0, 0x0B, 0xF8, 0xFF, 0x2A, 0x01, 0xD1, 0x01, 0x33, 0xF6, 0xE7, //
+    0xB3, 0x60, 0x20, 0x68, 0x03, 0x21, 0x09, 0x02, 0x08, 0x43, 0x20, 0x60, //
+    0x70, 0xBD, 0x2A, 0x66, 0xAA, 0x6A, 0x12, 0x09, 0xFC, 0xD3, 0x2A, 0x6E, //
+    0x70, 0x47, 0x00, 0x00, 0x0C, 0x80, 0x01, 0x40, 0x00, 0x00, 0x00, 0x18, //
+    0x00, 0x01, 0x00, 0x20, //
+];
+
+impl<T: UsbBackend> PicobootConnection<T> {
+    // copies a stub and its parameters to RAM, calls it, and reads back the
+    // parameters as the stub left them
+    fn run_stub(&mut self, stub: &[u8], params: &[u8]) -> Result<Vec<u8>> {
+        if let TargetID::Rp2350 = self.get_device_type() {
+            return Err(Error::CmdNotAllowedForTarget);
+        }
+
+        self.exit_xip()?;
+        self.flash_write(STUB_ADDR, stub)?;
+        self.flash_write(STUB_PARAMS_ADDR, params)?;
+        self.exec(STUB_ADDR)?;
+        self.flash_read(STUB_PARAMS_ADDR, params.len() as u32)
+    }
+
+    /// Sends bytes to the flash chip in a single transfer, and returns the
+    /// bytes received while sending them. (Only for RP2040)
+    ///
+    /// Leaves the flash out of XIP mode, see [`Self::exit_xip`].
+    ///
+    /// - `tx` - Command and any address, dummy or data bytes to send.
+    ///
+    /// # Errors:
+    /// - [`Error::CmdNotAllowedForTarget`]
+    /// - [`Error::UsbReadBulkMismatch`]
+    /// - Any produced by [`Self::exec`], [`Self::flash_write`] or [`Self::flash_read`]
+    pub fn flash_spi_transfer(&mut self, tx: &[u8]) -> Result<Vec<u8>> {
+        let mut params = (tx.len() as u32).to_le_bytes().to_vec();
+        params.extend_from_slice(tx);
+
+        let rx = self.run_stub(&SPI_TRANSFER_STUB, &params)?;
+        if rx.len() != params.len() {
+            return Err(Error::UsbReadBulkMismatch);
+        }
+
+        Ok(rx[4..].to_vec())
+    }
+
+    // counts the blank bytes at the start of a range of flash on the device,
+    // without sending the range over the bus
+    pub(crate) fn flash_blank_count(&mut self, addr: u32, size: u32) -> Result<u32> {
+        let mut params = Vec::with_capacity(12);
+        params.extend_from_slice(&addr.to_le_bytes());
+        params.extend_from_slice(&size.to_le_bytes());
+        params.extend_from_slice(&0u32.to_le_bytes());
+
+        let res = self.run_stub(&BLANK_CHECK_STUB, &params)?;
+        match res.get(8..12) {
+            Some(&[a, b, c, d]) => Ok(u32::from_le_bytes([a, b, c, d])),
+            _ => Err(Error::UsbReadBulkMismatch),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the literal pool at the end of a stub
+    fn literals(stub: &[u8]) -> Vec<u32> {
+        stub[stub.len() - 12..]
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect()
+    }
+
+    #[test]
+    fn spi_transfer_stub() {
+        assert_eq!(
+            literals(&SPI_TRANSFER_STUB),
+            [0x4001800C, 0x18000000, STUB_PARAMS_ADDR]
+        );
+        // returns by popping pc
+        assert_eq!(SPI_TRANSFER_STUB[62..64], [0x30, 0xBD]);
+        // parameters do not overlap the stub
+        assert!(STUB_ADDR + SPI_TRANSFER_STUB.len() as u32 <= STUB_PARAMS_ADDR);
+    }
+
+    #[test]
+    fn blank_check_stub() {
+        assert_eq!(
+            literals(&BLANK_CHECK_STUB),
+            [0x4001800C, 0x18000000, STUB_PARAMS_ADDR]
+        );
+        assert_eq!(BLANK_CHECK_STUB[84..86], [0x70, 0xBD]);
+        assert_eq!(BLANK_CHECK_STUB[96..98], [0x70, 0x47]);
+        assert!(STUB_ADDR + BLANK_CHECK_STUB.len() as u32 <= STUB_PARAMS_ADDR);
+    }
+}
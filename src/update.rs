@@ -0,0 +1,351 @@
+use crate::{
+    backend::UsbBackend,
+    block::{BlockItem, BlockLoop, ImageType},
+    cmd::PicobootError,
+    info::BootInfo,
+    loader::{FlashReport, LoadOptions},
+    partition::{
+        Partition, PartitionLink, PartitionTable, PartitionTarget,
+        PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_ARM_NS,
+        PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_ARM_S, PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_RISCV,
+    },
+    reset::{BootselOptions, ResetConnection},
+    usb::{DeviceIdentity, PicobootConnection},
+    PICO_FLASH_START, UF2_RP2350_ARM_NS_FAMILY_ID, UF2_RP2350_ARM_S_FAMILY_ID,
+    UF2_RP2350_RISCV_FAMILY_ID,
+};
+
+use std::time::{Duration, Instant};
+
+// see https://datasheets.raspberrypi.com/rp2350/rp2350-datasheet.pdf
+// section 5.1.17 for details on flash update boots and try before you buy
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// Delay in milliseconds before the flash update reboot, giving the command
+/// time to be acknowledged.
+const FLASH_UPDATE_REBOOT_DELAY: u32 = 100;
+
+/// Image found in one slot of an A/B partition pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlotImage {
+    /// The image is an executable and could be booted.
+    pub bootable: bool,
+    /// The image is marked try-before-you-buy and has not been bought, so it
+    /// is only booted by a flash update boot.
+    pub buy_pending: bool,
+    /// Rollback version, major and minor version of the image.
+    pub version: (u16, u16, u16),
+}
+
+/// An A/B partition pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AbPair {
+    /// Index of the A partition.
+    pub a: u8,
+    /// Index of the B partition.
+    pub b: u8,
+}
+impl AbPair {
+    /// Finds the A/B pair a partition belongs to.
+    ///
+    /// - `table` - Partition table to search.
+    /// - `target` - Either partition of the pair.
+    ///
+    /// # Errors:
+    /// - [`Error::PartitionNotFound`]
+    /// - [`Error::PartitionNotAbPair`]
+    pub fn find(table: &PartitionTable, target: &PartitionTarget) -> Result<Self> {
+        let (index, partition) = table
+            .find_partition(target)
+            .ok_or(Error::PartitionNotFound)?;
+
+        let a = match partition.link {
+            PartitionLink::APartition(a) => a,
+            _ => index as u8,
+        };
+        let b = table
+            .partitions
+            .iter()
+            .position(|p| p.link == PartitionLink::APartition(a))
+            .ok_or(Error::PartitionNotAbPair)?;
+
+        Ok(AbPair { a, b: b as u8 })
+    }
+
+    /// Returns the slot the bootrom would boot normally.
+    ///
+    /// A bootable image that is not pending a buy is preferred; if both slots
+    /// have one, the higher version wins, and A wins ties. Returns `None` if
+    /// neither slot would boot.
+    ///
+    /// - `a` - Image in the A slot, if any.
+    /// - `b` - Image in the B slot, if any.
+    pub fn choose(&self, a: Option<SlotImage>, b: Option<SlotImage>) -> Option<u8> {
+        let usable = |s: Option<SlotImage>| s.filter(|s| s.bootable && !s.buy_pending);
+        match (usable(a), usable(b)) {
+            (Some(a), Some(b)) if b.version > a.version => Some(self.b),
+            (Some(_), _) => Some(self.a),
+            (None, Some(_)) => Some(self.b),
+            (None, None) => None,
+        }
+    }
+
+    /// Returns the other partition of the pair.
+    pub fn other(&self, partition: u8) -> u8 {
+        if partition == self.a {
+            self.b
+        } else {
+            self.a
+        }
+    }
+}
+
+/// Handle to a flash update started by [`PicobootConnection::start_flash_update`].
+#[derive(Debug, Clone)]
+pub struct FlashUpdate {
+    identity: DeviceIdentity,
+    stale_address: u8,
+    pair: AbPair,
+    partition: u8,
+    report: FlashReport,
+}
+impl FlashUpdate {
+    /// Waits for the device to return to BOOTSEL mode and connects to it.
+    ///
+    /// If the device comes back running an application that exposes the reset
+    /// interface, it is rebooted into BOOTSEL mode through it (see
+    /// [`ResetConnection`]). A try-before-you-buy image must have bought itself
+    /// by the time it enumerates, or the bootrom reverts it on this reboot.
+    ///
+    /// - `ctx` - USB context to connect with.
+    /// - `timeout` - Time to wait for the device to come back.
+    ///
+    /// # Errors:
+    /// - [`Error::UsbReconnectTimeout`]
+    /// - [`Error::UsbOpenFailure`]
+    /// - [`Error::UsbClaimInterfaceFailure`]
+    /// - Any produced by [`PicobootConnection::wait_for_device`] or
+    ///   [`ResetConnection::reboot_to_picoboot`]
+    pub fn reconnect<T: UsbBackend>(
+        &self,
+        ctx: T,
+        timeout: Duration,
+    ) -> Result<PicobootConnection<T>> {
+        let start = Instant::now();
+        loop {
+            // the device may not have left the bus yet, so skip it while it
+            // is still at its old address
+            let stale_address = Some(self.stale_address);
+            match PicobootConnection::wait_for_new_device(
+                ctx.clone(),
+                &self.identity,
+                stale_address,
+                Duration::ZERO,
+            ) {
+                Err(Error::UsbReconnectTimeout) => {}
+                res => return res,
+            }
+
+            if let Some(reset) = ResetConnection::find(ctx.clone(), &self.identity)? {
+                let remaining = timeout.saturating_sub(start.elapsed());
+                return reset.reboot_to_picoboot(BootselOptions::default(), remaining);
+            }
+
+            if start.elapsed() >= timeout {
+                return Err(Error::UsbReconnectTimeout);
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Returns the A/B pair being updated.
+    pub fn get_pair(&self) -> AbPair {
+        self.pair
+    }
+
+    /// Returns the partition the update was written to.
+    pub fn get_partition(&self) -> u8 {
+        self.partition
+    }
+
+    /// Returns what loading the update did to the device.
+    pub fn get_report(&self) -> &FlashReport {
+        &self.report
+    }
+
+    /// Returns the identity of the updated device.
+    pub fn get_identity(&self) -> &DeviceIdentity {
+        &self.identity
+    }
+}
+
+/// Outcome of a flash update, see [`PicobootConnection::get_flash_update_status`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlashUpdateStatus {
+    /// Image currently in the updated slot.
+    pub image: Option<SlotImage>,
+    /// The update is the image the bootrom would boot normally: it either did
+    /// not need buying, or it booted and was bought. Taken from the bootrom,
+    /// which downloads new UF2s to the other slot of the pair.
+    pub accepted: bool,
+    /// Diagnostics about the most recent boot.
+    pub boot_info: BootInfo,
+}
+
+impl<T: UsbBackend> PicobootConnection<T> {
+    /// Inspects the image in a partition.
+    ///
+    /// Returns `None` if the partition has no valid block loop with an
+    /// IMAGE_DEF.
+    ///
+    /// - `table` - Partition table the partition belongs to.
+    /// - `partition` - Index of the partition.
+    ///
+    /// # Errors:
+    /// - [`Error::PartitionNotFound`]
+    /// - Any produced by [`Self::flash_read`]
+    pub fn read_slot_image(
+        &mut self,
+        table: &PartitionTable,
+        partition: u8,
+    ) -> Result<Option<SlotImage>> {
+        let (_, p) = table
+            .find_partition(&PartitionTarget::Index(partition))
+            .ok_or(Error::PartitionNotFound)?;
+
+        let block_loop = match BlockLoop::find(self, PICO_FLASH_START + p.get_offset()) {
+            Ok(l) => l,
+            Err(Error::BlockNotFound)
+            | Err(Error::BlockInvalidItemSize)
+            | Err(Error::BlockInvalidLink) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let (block, def) = match block_loop.get_image_def() {
+            Some(d) => d,
+            None => return Ok(None),
+        };
+
+        let version = block
+            .block
+            .items
+            .iter()
+            .find_map(|item| match item {
+                BlockItem::Version {
+                    major,
+                    minor,
+                    rollback,
+                } => Some((rollback.as_ref().map_or(0, |r| r.0), *major, *minor)),
+                _ => None,
+            })
+            .unwrap_or_default();
+
+        Ok(Some(SlotImage {
+            bootable: def.image_type == ImageType::Exe,
+            buy_pending: def.try_before_you_buy,
+            version,
+        }))
+    }
+
+    /// Writes an image to the inactive slot of an A/B pair and reboots into
+    /// it in flash update mode.
+    ///
+    /// The inactive slot is the one the bootrom would not boot normally, see
+    /// [`AbPair::choose`]. A try-before-you-buy image must then be bought by
+    /// the running firmware, or the bootrom reverts to the other slot on the
+    /// next boot. Use [`FlashUpdate::reconnect`] and
+    /// [`Self::get_flash_update_status`] to check the outcome.
+    ///
+    /// - `target` - Either partition of the A/B pair.
+    /// - `image` - Image to load.
+    /// - `options` - See [`LoadOptions`].
+    ///
+    /// # Errors:
+    /// - Any produced by [`AbPair::find`], [`Self::load_partition`] or
+    ///   [`Self::reboot2_flash_update`]
+    pub fn start_flash_update(
+        mut self,
+        target: &PartitionTarget,
+        image: &[u8],
+        options: &LoadOptions,
+    ) -> Result<FlashUpdate> {
+        let table = self.get_partition_table()?;
+        let pair = AbPair::find(&table, target)?;
+
+        let a = self.read_slot_image(&table, pair.a)?;
+        let b = self.read_slot_image(&table, pair.b)?;
+        let partition = match pair.choose(a, b) {
+            Some(active) => pair.other(active),
+            None => pair.a,
+        };
+
+        let report = self.load_partition(&PartitionTarget::Index(partition), image, options)?;
+
+        let addr = PICO_FLASH_START + table.partitions[partition as usize].get_offset();
+        self.reboot2_flash_update(addr, FLASH_UPDATE_REBOOT_DELAY)?;
+
+        Ok(FlashUpdate {
+            identity: self.get_identity().clone(),
+            stale_address: self.get_usb_device().1.address,
+            pair,
+            partition,
+            report,
+        })
+    }
+
+    /// Checks whether a flash update booted and was accepted.
+    ///
+    /// - `update` - Update started by [`Self::start_flash_update`].
+    ///
+    /// # Errors:
+    /// - [`Error::PartitionNotFound`]
+    /// - Any produced by [`Self::get_partition_table`], [`Self::read_slot_image`],
+    ///   [`Self::get_uf2_target_partition`] or [`Self::get_boot_info`]
+    pub fn get_flash_update_status(&mut self, update: &FlashUpdate) -> Result<FlashUpdateStatus> {
+        let table = self.get_partition_table()?;
+        let pair = update.pair;
+        let partition = table
+            .partitions
+            .get(update.partition as usize)
+            .ok_or(Error::PartitionNotFound)?;
+
+        let image = self.read_slot_image(&table, update.partition)?;
+
+        // the bootrom downloads to the slot it would not boot
+        let accepted = match accepted_family(partition) {
+            Some(family_id) => {
+                self.get_uf2_target_partition(family_id)? == Some(pair.other(update.partition))
+            }
+            None => false,
+        };
+
+        Ok(FlashUpdateStatus {
+            image,
+            accepted,
+            boot_info: self.get_boot_info()?,
+        })
+    }
+}
+
+// returns a UF2 family a partition accepts, preferring the executable ones
+fn accepted_family(partition: &Partition) -> Option<u32> {
+    [
+        (
+            PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_ARM_S,
+            UF2_RP2350_ARM_S_FAMILY_ID,
+        ),
+        (
+            PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_RISCV,
+            UF2_RP2350_RISCV_FAMILY_ID,
+        ),
+        (
+            PARTITION_FLAGS_ACCEPTS_DEFAULT_FAMILY_ARM_NS,
+            UF2_RP2350_ARM_NS_FAMILY_ID,
+        ),
+    ]
+    .iter()
+    .find(|(flag, _)| partition.flags & flag != 0)
+    .map(|&(_, family_id)| family_id)
+    .or_else(|| partition.extra_families.first().copied())
+}
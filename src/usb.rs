@@ -1,10 +1,15 @@
 use crate::{
+    backend::{
+        sealed::{DeviceEntry, Handle},
+        UsbBackend,
+    },
     cmd::{PicobootCmd, PicobootError, PicobootStatusCmd, TargetID},
     PICOBOOT_PID_RP2040, PICOBOOT_PID_RP2350, PICOBOOT_VID, PICO_PAGE_SIZE, PICO_SECTOR_SIZE,
 };
 
 use bincode;
-use rusb::{Device, DeviceDescriptor, DeviceHandle, Direction, TransferType, UsbContext};
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
 
 // see https://github.com/raspberrypi/picotool/blob/master/main.cpp#L4173
 // for loading firmware over a connection
@@ -12,16 +17,83 @@ use rusb::{Device, DeviceDescriptor, DeviceHandle, Direction, TransferType, UsbC
 type Error = PicobootError;
 type Result<T> = ::std::result::Result<T, Error>;
 
+/// Largest number of bytes moved by a single flash read or write command.
+/// Larger transfers are split into several commands.
+pub const TRANSFER_CHUNK_SIZE: u32 = 4 * PICO_SECTOR_SIZE;
+
+/// Returns the timeout of a bulk transfer, allowing extra time on top of
+/// `base` for every kilobyte transferred.
+pub(crate) fn bulk_timeout(base: Duration, len: usize) -> Duration {
+    // full speed USB moves about a kilobyte per millisecond, leave plenty of
+    // margin for slow hubs and busy buses
+    base + Duration::from_millis(4 * (len as u64 / 1024))
+}
+
+/// Identity of a USB device that survives re-enumeration
+///
+/// A device is given a new address every time it re-enumerates on the bus, so
+/// it is instead identified by the physical port path it is plugged into, its
+/// VID/PID pair and, when available, its serial number string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    bus: u8,
+    ports: Vec<u8>,
+    vidpid: Option<(u16, u16)>,
+    serial: Option<String>,
+}
+impl DeviceIdentity {
+    pub(crate) fn from_port<D>(entry: &DeviceEntry<D>) -> Self {
+        DeviceIdentity {
+            bus: entry.bus,
+            ports: entry.ports.clone(),
+            vidpid: None,
+            serial: None,
+        }
+    }
+
+    fn from_device<D>(entry: &DeviceEntry<D>, handle: &impl Handle) -> Self {
+        DeviceIdentity {
+            bus: entry.bus,
+            ports: entry.ports.clone(),
+            vidpid: Some((entry.vendor_id, entry.product_id)),
+            serial: handle.read_serial_number_string(),
+        }
+    }
+
+    /// Returns the number of the bus the device is connected to.
+    pub fn get_bus_number(&self) -> u8 {
+        self.bus
+    }
+
+    /// Returns the chain of hub port numbers leading to the device.
+    pub fn get_port_numbers(&self) -> &[u8] {
+        &self.ports
+    }
+
+    /// Returns the VID/PID pair of the device, if known.
+    ///
+    /// Unknown for a running application, which may enumerate with a
+    /// different pair than its bootrom.
+    pub fn get_vidpid(&self) -> Option<(u16, u16)> {
+        self.vidpid
+    }
+
+    /// Returns the serial number string of the device, if it reported one.
+    pub fn get_serial_number(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+}
+
 /// A connection to a PICOBOOT device
 ///
 /// This structure contains shorthand functions for send commands with checks to
 /// ensure safety with use of PICOBOOT interface commands.
 #[derive(Debug)]
-pub struct PicobootConnection<T: UsbContext> {
-    _context: T,
-    _device: Device<T>,
-    _desc: DeviceDescriptor,
-    handle: DeviceHandle<T>,
+pub struct PicobootConnection<T: UsbBackend> {
+    context: T,
+    entry: DeviceEntry<T::Device>,
+    handle: T::Handle,
+    identity: DeviceIdentity,
 
     _cfg: u8,
     iface: u8,
@@ -33,31 +105,37 @@ pub struct PicobootConnection<T: UsbContext> {
     has_kernel_driver: bool,
     target_id: TargetID,
 }
-impl<T: UsbContext> Drop for PicobootConnection<T> {
+impl<T: UsbBackend> Drop for PicobootConnection<T> {
     fn drop(&mut self) {
-        self.handle
-            .release_interface(self.iface)
-            .expect("could not release interface");
+        // the device may have already left the bus (e.g. after a reboot), in
+        // which case there is nothing left to release
+        let _ = self.handle.release_interface(self.iface);
 
         if self.has_kernel_driver {
-            self.handle
-                .attach_kernel_driver(self.iface)
-                .expect("could not retach kernel driver")
+            let _ = self.handle.attach_kernel_driver(self.iface);
         }
     }
 }
-impl<T: UsbContext> PicobootConnection<T> {
+impl<T: UsbBackend> PicobootConnection<T> {
     /// Creates a new PICOBOOT connection
     ///
-    /// Takes a rusb context and a USB VID/PID pair tuple. The VID/PID pair
-    /// dictates how the connection determines the target. If `None` is
-    /// provided, the connection attempts both RP2040 and RP2350 VID/PID pairs.
-    /// If a VID/PID pair is provided, and if the pair belongs to the RP2040,
-    /// the target will be considered an RP2040. Otherwise, the target will be
-    /// considered an RP2350.
+    /// Takes a USB backend (see [`UsbBackend`]) and a USB VID/PID pair tuple.
+    /// The VID/PID pair dictates how the connection determines the target. If
+    /// `None` is provided, the connection attempts both RP2040 and RP2350
+    /// VID/PID pairs. If a VID/PID pair is provided, and if the pair belongs to
+    /// the RP2040, the target will be considered an RP2040. Otherwise, the
+    /// target will be considered an RP2350, which allows connecting to RP2350
+    /// devices with a white labeled VID/PID (see [`crate::WhiteLabel`]). The
+    /// guess is replaced by the chip family read from the bootrom once
+    /// [`Self::get_chip_info`] is called.
+    ///
+    /// If `None` is provided and neither default pair is found, any device
+    /// exposing both the BOOTSEL mass storage and PICOBOOT interfaces is
+    /// considered a white labeled RP2350.
     ///
     /// # Errors
     /// - [`Error::UsbDeviceNotFound`]
+    /// - [`Error::UsbGetDescriptorFailure`]
     /// - [`Error::UsbEndpointsNotFound`]
     /// - [`Error::UsbEndpointsUnexpected`]
     /// - [`Error::UsbDetachKernelDriverFailure`]
@@ -66,12 +144,7 @@ impl<T: UsbContext> PicobootConnection<T> {
     pub fn new(mut ctx: T, vidpid: impl Into<Option<(u16, u16)>>) -> Result<Self> {
         let (device, target_id) = match vidpid.into() {
             Some((vid, pid)) => {
-                // simple heuristic for determining target type
-                let target_id = if vid == PICOBOOT_VID && pid == PICOBOOT_PID_RP2040 {
-                    TargetID::Rp2040
-                } else {
-                    TargetID::Rp2350
-                };
+                let target_id = Self::guess_target(vid, pid);
 
                 if let Some(device) = Self::open_device(&mut ctx, vid, pid) {
                     (Some(device), Some(target_id))
@@ -83,95 +156,172 @@ impl<T: UsbContext> PicobootConnection<T> {
                 if let Some(device) = Self::open_device(&mut ctx, PICOBOOT_VID, PICOBOOT_PID_RP2040)
                 {
                     (Some(device), Some(TargetID::Rp2040))
+                } else if let Some(device) =
+                    Self::open_device(&mut ctx, PICOBOOT_VID, PICOBOOT_PID_RP2350)
+                {
+                    (Some(device), Some(TargetID::Rp2350))
+                } else if let Some(device) = Self::open_white_labeled_device(&mut ctx) {
+                    (Some(device), Some(TargetID::Rp2350))
                 } else {
-                    if let Some(device) =
-                        Self::open_device(&mut ctx, PICOBOOT_VID, PICOBOOT_PID_RP2350)
-                    {
-                        (Some(device), Some(TargetID::Rp2350))
-                    } else {
-                        (None, None)
-                    }
+                    (None, None)
                 }
             }
         };
 
         match device {
-            Some((device, desc, handle)) => {
-                let e1 = Self::get_endpoint(&device, 255, 0, 0, Direction::In, TransferType::Bulk);
-                let e2 = Self::get_endpoint(&device, 255, 0, 0, Direction::Out, TransferType::Bulk);
+            Some((entry, handle)) => Self::from_device(ctx, entry, handle, target_id.unwrap()),
+            None => Err(Error::UsbDeviceNotFound),
+        }
+    }
 
-                if e1.is_none() || e2.is_none() {
-                    return Err(Error::UsbEndpointsNotFound);
-                }
+    /// Waits for a device to appear in PICOBOOT mode and connects to it
+    ///
+    /// Polls the USB bus until a device matching `identity` exposes the
+    /// PICOBOOT interface, then opens it the same way as [`Self::new`],
+    /// including the VID/PID heuristic for determining the target.
+    ///
+    /// # Errors
+    /// - [`Error::UsbReconnectTimeout`]
+    /// - Any produced by [`Self::new`], except [`Error::UsbDeviceNotFound`]
+    pub fn wait_for_device(ctx: T, identity: &DeviceIdentity, timeout: Duration) -> Result<Self> {
+        Self::wait_for_new_device(ctx, identity, None, timeout)
+    }
 
-                let (_cfg, _iface, _setting, in_addr) = e1.unwrap();
-                let (cfg, iface, setting, out_addr) = e2.unwrap();
+    // same as wait_for_device, but skips the device while it is still at the
+    // address it had before rebooting
+    pub(crate) fn wait_for_new_device(
+        mut ctx: T,
+        identity: &DeviceIdentity,
+        stale_address: Option<u8>,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let (entry, handle) = Self::poll_device(&mut ctx, identity, stale_address, timeout)?;
+        let target_id = Self::guess_target(entry.vendor_id, entry.product_id);
+        Self::from_device(ctx, entry, handle, target_id)
+    }
 
-                if _cfg != cfg || _iface != iface || _setting != setting {
-                    return Err(Error::UsbEndpointsUnexpected);
-                }
+    /// Waits for the device to re-enumerate and returns a fresh connection
+    ///
+    /// After a reboot, the device drops off the bus and comes back with a new
+    /// address, leaving the existing connection unusable. This consumes the
+    /// connection, waits for the same device (see [`DeviceIdentity`]) to come
+    /// back in PICOBOOT mode under a new address, and connects to it.
+    ///
+    /// This only succeeds if the device reboots back into BOOTSEL mode, for
+    /// example after [`Self::reboot2_bootsel`].
+    ///
+    /// # Errors
+    /// - [`Error::UsbReconnectTimeout`]
+    /// - Any produced by [`Self::new`], except [`Error::UsbDeviceNotFound`]
+    pub fn reconnect(self, timeout: Duration) -> Result<Self> {
+        let mut ctx = self.context.clone();
+        let identity = self.identity.clone();
+        let target_id = self.target_id;
+        let stale_address = self.entry.address;
+        drop(self);
+
+        let (entry, handle) = Self::poll_device(&mut ctx, &identity, Some(stale_address), timeout)?;
+        Self::from_device(ctx, entry, handle, target_id)
+    }
 
-                let has_kernel_driver = match handle.kernel_driver_active(iface) {
-                    Ok(true) => {
-                        handle
-                            .detach_kernel_driver(iface)
-                            .map_err(|e| Error::UsbDetachKernelDriverFailure(e))?;
-                        true
-                    }
-                    _ => false,
-                };
-
-                if handle.set_active_configuration(cfg).is_err() {
-                    // println!("Warning: could not set USB active configuration");
-                }
+    /// Reboots the device and returns a fresh connection once it has
+    /// re-enumerated in PICOBOOT mode.
+    ///
+    /// - `reboot` - Issues the reboot on the current connection, e.g. `|c| c.reboot2_bootsel(100)`.
+    /// - `timeout` - Time to wait for the device to come back.
+    ///
+    /// # Errors
+    /// - Any produced by `reboot`
+    /// - Any produced by [`Self::reconnect`]
+    pub fn reboot_and_reconnect<F>(mut self, reboot: F, timeout: Duration) -> Result<Self>
+    where
+        F: FnOnce(&mut Self) -> Result<()>,
+    {
+        reboot(&mut self)?;
+        self.reconnect(timeout)
+    }
 
-                handle
-                    .claim_interface(iface)
-                    .map_err(|e| Error::UsbClaimInterfaceFailure(e))?;
-                handle
-                    .set_alternate_setting(iface, setting)
-                    .map_err(|e| Error::UsbSetAltSettingFailure(e))?;
-
-                Ok(PicobootConnection {
-                    _context: ctx,
-                    _device: device,
-                    _desc: desc,
-                    handle: handle,
-
-                    _cfg: cfg,
-                    iface: iface,
-                    _setting: setting,
-                    in_addr: in_addr,
-                    out_addr: out_addr,
-
-                    cmd_token: 1,
-                    has_kernel_driver: has_kernel_driver,
-                    target_id: target_id.unwrap(),
-                })
-            }
-            None => Err(Error::UsbDeviceNotFound),
+    // simple heuristic for determining target type
+    fn guess_target(vid: u16, pid: u16) -> TargetID {
+        if vid == PICOBOOT_VID && pid == PICOBOOT_PID_RP2040 {
+            TargetID::Rp2040
+        } else {
+            TargetID::Rp2350
         }
     }
 
-    fn open_device(
-        ctx: &mut T,
-        vid: u16,
-        pid: u16,
-    ) -> Option<(Device<T>, DeviceDescriptor, DeviceHandle<T>)> {
-        let devices = match ctx.devices() {
+    fn from_device(
+        ctx: T,
+        entry: DeviceEntry<T::Device>,
+        mut handle: T::Handle,
+        target_id: TargetID,
+    ) -> Result<Self> {
+        let e1 = handle
+            .find_bulk_endpoint(255, 0, 0, true)
+            .map_err(Error::UsbGetDescriptorFailure)?;
+        let e2 = handle
+            .find_bulk_endpoint(255, 0, 0, false)
+            .map_err(Error::UsbGetDescriptorFailure)?;
+
+        if e1.is_none() || e2.is_none() {
+            return Err(Error::UsbEndpointsNotFound);
+        }
+
+        let (_cfg, _iface, _setting, in_addr) = e1.unwrap();
+        let (cfg, iface, setting, out_addr) = e2.unwrap();
+
+        if _cfg != cfg || _iface != iface || _setting != setting {
+            return Err(Error::UsbEndpointsUnexpected);
+        }
+
+        let has_kernel_driver = handle.kernel_driver_active(iface);
+        if has_kernel_driver {
+            handle
+                .detach_kernel_driver(iface)
+                .map_err(Error::UsbDetachKernelDriverFailure)?;
+        }
+
+        if handle.set_active_configuration(cfg).is_err() {
+            // println!("Warning: could not set USB active configuration");
+        }
+
+        handle
+            .claim_interface(iface)
+            .map_err(Error::UsbClaimInterfaceFailure)?;
+        handle
+            .set_alternate_setting(iface, setting)
+            .map_err(Error::UsbSetAltSettingFailure)?;
+
+        let identity = DeviceIdentity::from_device(&entry, &handle);
+
+        Ok(PicobootConnection {
+            context: ctx,
+            entry,
+            handle,
+            identity,
+
+            _cfg: cfg,
+            iface,
+            _setting: setting,
+            in_addr,
+            out_addr,
+
+            cmd_token: 1,
+            has_kernel_driver,
+            target_id,
+        })
+    }
+
+    fn open_device(ctx: &mut T, vid: u16, pid: u16) -> Option<(DeviceEntry<T::Device>, T::Handle)> {
+        let devices = match ctx.list_devices() {
             Ok(d) => d,
             Err(_) => return None,
         };
 
-        for device in devices.iter() {
-            let device_desc = match device.device_descriptor() {
-                Ok(d) => d,
-                Err(_) => continue,
-            };
-
-            if device_desc.vendor_id() == vid && device_desc.product_id() == pid {
-                match device.open() {
-                    Ok(handle) => return Some((device, device_desc, handle)),
+        for entry in devices {
+            if entry.vendor_id == vid && entry.product_id == pid {
+                match ctx.open(&entry) {
+                    Ok(handle) => return Some((entry, handle)),
                     Err(e) => panic!("Device found but failed to open: {}", e),
                 }
             }
@@ -180,59 +330,105 @@ impl<T: UsbContext> PicobootConnection<T> {
         None
     }
 
-    fn get_endpoint(
-        device: &Device<T>,
-        class: u8,
-        subclass: u8,
-        protocol: u8,
-        direction: Direction,
-        transfer_type: TransferType,
-    ) -> Option<(u8, u8, u8, u8)> {
-        let desc = device.device_descriptor().unwrap();
-        for n in 0..desc.num_configurations() {
-            let config_desc = match device.config_descriptor(n) {
-                Ok(c) => c,
+    fn open_white_labeled_device(ctx: &mut T) -> Option<(DeviceEntry<T::Device>, T::Handle)> {
+        let devices = ctx.list_devices().ok()?;
+
+        for entry in devices {
+            if !Self::is_bootsel_device(&entry) {
+                continue;
+            }
+
+            if let Ok(handle) = ctx.open(&entry) {
+                return Some((entry, handle));
+            }
+        }
+
+        None
+    }
+
+    fn poll_device(
+        ctx: &mut T,
+        identity: &DeviceIdentity,
+        stale_address: Option<u8>,
+        timeout: Duration,
+    ) -> Result<(DeviceEntry<T::Device>, T::Handle)> {
+        let start = Instant::now();
+        loop {
+            if let Some(device) = Self::find_device(ctx, identity, stale_address) {
+                return Ok(device);
+            }
+            if start.elapsed() >= timeout {
+                return Err(Error::UsbReconnectTimeout);
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    fn find_device(
+        ctx: &mut T,
+        identity: &DeviceIdentity,
+        stale_address: Option<u8>,
+    ) -> Option<(DeviceEntry<T::Device>, T::Handle)> {
+        let devices = ctx.list_devices().ok()?;
+
+        for entry in devices {
+            if entry.bus != identity.bus
+                || Some(entry.address) == stale_address
+                || entry.ports != identity.ports
+            {
+                continue;
+            }
+
+            // only accept the device once it is back in PICOBOOT mode
+            if entry.find_interface(255, 0, 0).is_none() {
+                continue;
+            }
+
+            // some other device with a vendor interface may have been plugged
+            // into the same port, so the VID/PID pair has to match as well
+            let vidpid = (entry.vendor_id, entry.product_id);
+            let expected = match identity.vidpid {
+                Some(expected) => vidpid == expected,
+                None => {
+                    vidpid == (PICOBOOT_VID, PICOBOOT_PID_RP2040)
+                        || vidpid == (PICOBOOT_VID, PICOBOOT_PID_RP2350)
+                        || Self::is_bootsel_device(&entry)
+                }
+            };
+            if !expected {
+                continue;
+            }
+
+            // the device node may not be accessible yet right after enumeration,
+            // so failing to open is not fatal here
+            let handle = match ctx.open(&entry) {
+                Ok(h) => h,
                 Err(_) => continue,
             };
 
-            for iface in config_desc.interfaces() {
-                for iface_desc in iface.descriptors() {
-                    let iface_class = iface_desc.class_code();
-                    let iface_subclass = iface_desc.sub_class_code();
-                    let iface_protocol = iface_desc.protocol_code();
-                    if !(iface_class == class
-                        && iface_subclass == subclass
-                        && iface_protocol == protocol)
-                    {
-                        continue;
-                    }
-
-                    for endpoint_desc in iface_desc.endpoint_descriptors() {
-                        if endpoint_desc.direction() == direction
-                            && endpoint_desc.transfer_type() == transfer_type
-                        {
-                            return Some((
-                                config_desc.number(),
-                                iface_desc.interface_number(),
-                                iface_desc.setting_number(),
-                                endpoint_desc.address(),
-                            ));
-                        }
-                    }
-                }
+            if identity.serial.is_some() && handle.read_serial_number_string() != identity.serial {
+                continue;
             }
+
+            return Some((entry, handle));
         }
 
-        return None;
+        None
+    }
+
+    // BOOTSEL exposes a mass storage (SCSI, bulk-only) and a PICOBOOT
+    // interface, a vendor interface alone is too common to go by
+    fn is_bootsel_device(entry: &DeviceEntry<T::Device>) -> bool {
+        entry.find_interface(8, 6, 0x50).is_some() && entry.find_interface(255, 0, 0).is_some()
     }
 
     fn bulk_read(&mut self, buf_size: usize, check: bool) -> Result<Vec<u8>> {
         let mut buf: Vec<u8> = vec![0; buf_size]; // [0; SECTOR_SIZE];
-        let timeout = std::time::Duration::from_secs(3);
+        let timeout = bulk_timeout(Duration::from_secs(3), buf_size);
         let len = self
             .handle
             .read_bulk(self.in_addr, &mut buf, timeout)
-            .map_err(|e| Error::UsbReadBulkFailure(e))?;
+            .map_err(Error::UsbReadBulkFailure)?;
 
         if check && len != buf_size {
             return Err(Error::UsbReadBulkMismatch);
@@ -242,12 +438,12 @@ impl<T: UsbContext> PicobootConnection<T> {
         Ok(buf)
     }
 
-    fn bulk_write(&mut self, mut buf: &[u8], check: bool) -> Result<()> {
-        let timeout = std::time::Duration::from_secs(5);
+    fn bulk_write(&mut self, buf: &[u8], check: bool) -> Result<()> {
+        let timeout = bulk_timeout(Duration::from_secs(5), buf.len());
         let len = self
             .handle
-            .write_bulk(self.out_addr, &mut buf, timeout)
-            .map_err(|e| Error::UsbWriteBulkFailure(e))?;
+            .write_bulk(self.out_addr, buf, timeout)
+            .map_err(Error::UsbWriteBulkFailure)?;
 
         if check && len != buf.len() {
             return Err(Error::UsbWriteBulkMismatch);
@@ -269,11 +465,10 @@ impl<T: UsbContext> PicobootConnection<T> {
     /// - [`Error::UsbReadBulkFailure`]
     /// - [`Error::UsbReadBulkMismatch`]
     pub fn cmd(&mut self, cmd: PicobootCmd, buf: &[u8]) -> Result<Vec<u8>> {
-        let cmd = cmd.set_token(self.cmd_token);
-        self.cmd_token = self.cmd_token + 1;
+        let cmd = cmd.set_token(self.next_cmd_token());
 
         // write command
-        let cmdu8 = bincode::serialize(&cmd).map_err(|e| Error::CmdSerializeFailure(e))?;
+        let cmdu8 = bincode::serialize(&cmd).map_err(Error::CmdSerializeFailure)?;
         self.bulk_write(cmdu8.as_slice(), true)?;
         let _stat = self.get_command_status();
 
@@ -328,9 +523,8 @@ impl<T: UsbContext> PicobootConnection<T> {
     }
 
     fn set_exclusive_access(&mut self, exclusive: u8) -> Result<()> {
-        Ok(self
-            .cmd(PicobootCmd::exclusive_access(exclusive), &[0u8; 0])
-            .map(|_| ())?)
+        self.cmd(PicobootCmd::exclusive_access(exclusive), &[0u8; 0])
+            .map(|_| ())
     }
 
     /// Reboots the device with a specified program counter, stack pointer, and
@@ -343,9 +537,8 @@ impl<T: UsbContext> PicobootConnection<T> {
     /// # Errors:
     /// - Any produced by [`Self::cmd`]
     pub fn reboot(&mut self, pc: u32, sp: u32, delay: u32) -> Result<()> {
-        Ok(self
-            .cmd(PicobootCmd::reboot(pc, sp, delay), &[0u8; 0])
-            .map(|_| ())?)
+        self.cmd(PicobootCmd::reboot(pc, sp, delay), &[0u8; 0])
+            .map(|_| ())
     }
 
     /// Reboots the device with a delay in milliseconds. (Only for RP2350)
@@ -356,16 +549,53 @@ impl<T: UsbContext> PicobootConnection<T> {
     /// - [`Error::CmdNotAllowedForTarget`]
     /// - Any produced by [`Self::cmd`]
     pub fn reboot2_normal(&mut self, delay: u32) -> Result<()> {
-        match self.target_id {
-            TargetID::Rp2040 => {
-                return Err(Error::CmdNotAllowedForTarget);
-            }
-            _ => {}
+        if let TargetID::Rp2040 = self.target_id {
+            return Err(Error::CmdNotAllowedForTarget);
+        }
+
+        self.cmd(PicobootCmd::reboot2_normal(delay), &[0u8; 0])
+            .map(|_| ())
+    }
+
+    /// Reboots the device into BOOTSEL mode with a delay in milliseconds. (Only
+    /// for RP2350)
+    ///
+    /// The device will drop off the bus and re-enumerate, see
+    /// [`Self::reboot_and_reconnect`].
+    ///
+    /// - `delay` - Time in milliseconds to reboot the device after.
+    ///
+    /// # Errors:
+    /// - [`Error::CmdNotAllowedForTarget`]
+    /// - Any produced by [`Self::cmd`]
+    pub fn reboot2_bootsel(&mut self, delay: u32) -> Result<()> {
+        if let TargetID::Rp2040 = self.target_id {
+            return Err(Error::CmdNotAllowedForTarget);
         }
 
-        Ok(self
-            .cmd(PicobootCmd::reboot2_normal(delay), &[0u8; 0])
-            .map(|_| ())?)
+        self.cmd(PicobootCmd::reboot2_bootsel(delay), &[0u8; 0])
+            .map(|_| ())
+    }
+
+    /// Reboots the device in flash update mode with a delay in milliseconds.
+    /// (Only for RP2350)
+    ///
+    /// The bootrom prefers the image in the updated region on this boot, and
+    /// boots it even if it is marked try-before-you-buy.
+    ///
+    /// - `addr` - Start address of the updated flash region, e.g. of a partition.
+    /// - `delay` - Time in milliseconds to reboot the device after.
+    ///
+    /// # Errors:
+    /// - [`Error::CmdNotAllowedForTarget`]
+    /// - Any produced by [`Self::cmd`]
+    pub fn reboot2_flash_update(&mut self, addr: u32, delay: u32) -> Result<()> {
+        if let TargetID::Rp2040 = self.target_id {
+            return Err(Error::CmdNotAllowedForTarget);
+        }
+
+        self.cmd(PicobootCmd::reboot2_flash_update(addr, delay), &[0u8; 0])
+            .map(|_| ())
     }
 
     /// Erases the flash memory of the device.
@@ -385,38 +615,128 @@ impl<T: UsbContext> PicobootConnection<T> {
             return Err(Error::EraseInvalidSize);
         }
 
-        Ok(self
-            .cmd(PicobootCmd::flash_erase(addr, size), &[0u8; 0])
-            .map(|_| ())?)
+        self.cmd(PicobootCmd::flash_erase(addr, size), &[0u8; 0])
+            .map(|_| ())
     }
 
     /// Writes a buffer to the flash memory of the device.
     ///
+    /// Buffers larger than [`TRANSFER_CHUNK_SIZE`] are written with several
+    /// commands.
+    ///
     /// - `addr` - Address to start the write. Must be on a multiple of [`PICO_PAGE_SIZE`].
     /// - `buf` - Buffer of data to write to flash. Should be a multiple of [`PICO_PAGE_SIZE`]. If not, the remainder of the final page is zero-filled.
     ///
     /// # Errors:
     /// - [`Error::WriteInvalidAddr`]
+    /// - [`Error::MemoryOutOfRange`]
     /// - Any produced by [`Self::cmd`]
     pub fn flash_write(&mut self, addr: u32, buf: &[u8]) -> Result<()> {
         if addr % PICO_PAGE_SIZE != 0 {
             return Err(Error::WriteInvalidAddr);
         }
+        u32::try_from(buf.len())
+            .ok()
+            .and_then(|len| addr.checked_add(len))
+            .ok_or(Error::MemoryOutOfRange)?;
+
+        for (i, chunk) in buf.chunks(TRANSFER_CHUNK_SIZE as usize).enumerate() {
+            let chunk_addr = addr
+                .checked_add(i as u32 * TRANSFER_CHUNK_SIZE)
+                .ok_or(Error::MemoryOutOfRange)?;
+            self.cmd(
+                PicobootCmd::flash_write(chunk_addr, chunk.len() as u32),
+                chunk,
+            )?;
+        }
 
-        Ok(self
-            .cmd(PicobootCmd::flash_write(addr, buf.len() as u32), buf)
-            .map(|_| ())?)
+        Ok(())
     }
 
-    /// Writes a buffer to the flash memory of the device.
+    /// Writes the contents of a stream to the flash memory of the device.
+    ///
+    /// The stream is read and written [`TRANSFER_CHUNK_SIZE`] bytes at a time
+    /// until it ends, so it is never held in memory as a whole.
+    ///
+    /// Returns the number of bytes written.
+    ///
+    /// - `addr` - Address to start the write. Must be on a multiple of [`PICO_PAGE_SIZE`].
+    /// - `reader` - Stream of data to write to flash. If it does not end on a multiple of [`PICO_PAGE_SIZE`], the remainder of the final page is zero-filled.
+    ///
+    /// # Errors:
+    /// - [`Error::WriteInvalidAddr`]
+    /// - [`Error::MemoryOutOfRange`]
+    /// - [`Error::StreamIoFailure`]
+    /// - Any produced by [`Self::cmd`]
+    pub fn flash_write_from<R: Read>(&mut self, addr: u32, reader: &mut R) -> Result<u32> {
+        if addr % PICO_PAGE_SIZE != 0 {
+            return Err(Error::WriteInvalidAddr);
+        }
+
+        let mut buf = vec![0u8; TRANSFER_CHUNK_SIZE as usize];
+        let mut written = 0u32;
+        loop {
+            let len = read_chunk(reader, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+
+            let chunk_addr = addr.checked_add(written).ok_or(Error::MemoryOutOfRange)?;
+            self.flash_write(chunk_addr, &buf[..len])?;
+            written = written
+                .checked_add(len as u32)
+                .ok_or(Error::MemoryOutOfRange)?;
+            if len < buf.len() {
+                break;
+            }
+        }
+
+        Ok(written)
+    }
+
+    /// Reads from the flash memory of the device.
+    ///
+    /// Reads larger than [`TRANSFER_CHUNK_SIZE`] are made with several
+    /// commands.
     ///
     /// - `addr` - Address to start the read.
     /// - `size` - Number of bytes to read.
     ///
     /// # Errors:
+    /// - [`Error::MemoryOutOfRange`]
     /// - Any produced by [`Self::cmd`]
     pub fn flash_read(&mut self, addr: u32, size: u32) -> Result<Vec<u8>> {
-        self.cmd(PicobootCmd::flash_read(addr, size), &[0u8; 0])
+        let mut buf = Vec::with_capacity(size as usize);
+        self.flash_read_to(addr, size, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Reads from the flash memory of the device into a stream.
+    ///
+    /// The memory is read and written to the stream [`TRANSFER_CHUNK_SIZE`]
+    /// bytes at a time, so it is never held in memory as a whole.
+    ///
+    /// - `addr` - Address to start the read.
+    /// - `size` - Number of bytes to read.
+    /// - `writer` - Stream to write the data to.
+    ///
+    /// # Errors:
+    /// - [`Error::MemoryOutOfRange`]
+    /// - [`Error::StreamIoFailure`]
+    /// - Any produced by [`Self::cmd`]
+    pub fn flash_read_to<W: Write>(&mut self, addr: u32, size: u32, writer: &mut W) -> Result<()> {
+        addr.checked_add(size).ok_or(Error::MemoryOutOfRange)?;
+
+        let mut offset = 0;
+        while offset < size {
+            let len = std::cmp::min(size - offset, TRANSFER_CHUNK_SIZE);
+            let chunk_addr = addr.checked_add(offset).ok_or(Error::MemoryOutOfRange)?;
+            let data = self.cmd(PicobootCmd::flash_read(chunk_addr, len), &[0u8; 0])?;
+            writer.write_all(&data).map_err(Error::StreamIoFailure)?;
+            offset += len;
+        }
+
+        Ok(())
     }
 
     /// Enter Flash XIP (execute-in-place) mode.
@@ -424,7 +744,7 @@ impl<T: UsbContext> PicobootConnection<T> {
     /// # Errors:
     /// - Any produced by [`Self::cmd`]
     pub fn enter_xip(&mut self) -> Result<()> {
-        Ok(self.cmd(PicobootCmd::enter_xip(), &[0u8; 0]).map(|_| ())?)
+        self.cmd(PicobootCmd::enter_xip(), &[0u8; 0]).map(|_| ())
     }
 
     /// Exits Flash XIP (execute-in-place) mode.
@@ -432,7 +752,25 @@ impl<T: UsbContext> PicobootConnection<T> {
     /// # Errors:
     /// - Any produced by [`Self::cmd`]
     pub fn exit_xip(&mut self) -> Result<()> {
-        Ok(self.cmd(PicobootCmd::exit_xip(), &[0u8; 0]).map(|_| ())?)
+        self.cmd(PicobootCmd::exit_xip(), &[0u8; 0]).map(|_| ())
+    }
+
+    /// Calls a function in the RAM of the device. (Only for RP2040)
+    ///
+    /// The function runs inside the bootrom USB handler, and must return
+    /// before the command completes.
+    ///
+    /// - `addr` - Address of the Thumb function to call.
+    ///
+    /// # Errors:
+    /// - [`Error::CmdNotAllowedForTarget`]
+    /// - Any produced by [`Self::cmd`]
+    pub fn exec(&mut self, addr: u32) -> Result<()> {
+        if let TargetID::Rp2350 = self.target_id {
+            return Err(Error::CmdNotAllowedForTarget);
+        }
+
+        self.cmd(PicobootCmd::exec(addr), &[0u8; 0]).map(|_| ())
     }
 
     /// Resets PICOBOOT USB interface.
@@ -447,24 +785,21 @@ impl<T: UsbContext> PicobootConnection<T> {
     pub fn reset_interface(&mut self) -> Result<()> {
         self.handle
             .clear_halt(self.in_addr)
-            .map_err(|e| Error::UsbClearInAddrHalt(e))?;
+            .map_err(Error::UsbClearInAddrHalt)?;
         self.handle
             .clear_halt(self.out_addr)
-            .map_err(|e| Error::UsbClearOutAddrHalt(e))?;
+            .map_err(Error::UsbClearOutAddrHalt)?;
 
         let timeout = std::time::Duration::from_secs(1);
-        let mut buf = [0u8; 0];
+        let buf = [0u8; 0];
         let _res = self
             .handle
             .write_control(
-                0b01000001,
-                0b01000001,
-                0,
-                self.iface.into(),
-                &mut buf,
+                (0b01000001, 0b01000001, 0, self.iface.into()),
+                &buf,
                 timeout,
             )
-            .map_err(|e| Error::UsbResetInterfaceFailure(e))?;
+            .map_err(Error::UsbResetInterfaceFailure)?;
 
         Ok(())
     }
@@ -475,24 +810,112 @@ impl<T: UsbContext> PicobootConnection<T> {
         let _res = self
             .handle
             .read_control(
-                0b11000001,
-                0b01000010,
-                0,
-                self.iface.into(),
+                (0b11000001, 0b01000010, 0, self.iface.into()),
                 &mut buf,
                 timeout,
             )
-            .map_err(|e| Error::UsbGetCommandStatusFailure(e))?;
+            .map_err(Error::UsbGetCommandStatusFailure)?;
         let buf: PicobootStatusCmd =
-            bincode::deserialize(&buf).map_err(|e| Error::CmdDeserializeFailure(e))?;
+            bincode::deserialize(&buf).map_err(Error::CmdDeserializeFailure)?;
 
         Ok(buf)
     }
 
+    /// Reads rows of OTP memory. (Only for RP2350)
+    ///
+    /// ECC reads return 2 bytes of corrected data per row, raw reads return 4
+    /// bytes per row, of which the upper byte is zero.
+    ///
+    /// - `row` - First row to read.
+    /// - `row_count` - Number of rows to read.
+    /// - `ecc` - Read with error correction.
+    ///
+    /// # Errors:
+    /// - [`Error::CmdNotAllowedForTarget`]
+    /// - Any produced by [`Self::cmd`]
+    pub fn otp_read(&mut self, row: u16, row_count: u16, ecc: bool) -> Result<Vec<u8>> {
+        if let TargetID::Rp2040 = self.target_id {
+            return Err(Error::CmdNotAllowedForTarget);
+        }
+
+        self.cmd(PicobootCmd::otp_read(row, row_count, ecc), &[0u8; 0])
+    }
+
+    /// Writes rows of OTP memory. (Only for RP2350)
+    ///
+    /// OTP bits can only ever be set, and ECC rows can only be written once.
+    ///
+    /// - `row` - First row to write.
+    /// - `buf` - Data to write, 2 bytes per row for ECC writes or 4 bytes per
+    ///   row for raw writes.
+    /// - `ecc` - Write with error correction.
+    ///
+    /// # Errors:
+    /// - [`Error::CmdNotAllowedForTarget`]
+    /// - Any produced by [`Self::cmd`]
+    pub fn otp_write(&mut self, row: u16, buf: &[u8], ecc: bool) -> Result<()> {
+        if let TargetID::Rp2040 = self.target_id {
+            return Err(Error::CmdNotAllowedForTarget);
+        }
+
+        let row_count = buf.len() / if ecc { 2 } else { 4 };
+        self.cmd(PicobootCmd::otp_write(row, row_count as u16, ecc), buf)
+            .map(|_| ())
+    }
+
     /// Returns PICOBOOT device type.
     ///
-    /// Device type is determined by [`Self::new`].
+    /// Device type is guessed by [`Self::new`], and corrected by
+    /// [`Self::get_chip_info`].
     pub fn get_device_type(&self) -> TargetID {
         self.target_id
     }
+
+    pub(crate) fn set_device_type(&mut self, target_id: TargetID) {
+        self.target_id = target_id;
+    }
+
+    /// Returns the identity of the connected device.
+    ///
+    /// See [`Self::reconnect`].
+    pub fn get_identity(&self) -> &DeviceIdentity {
+        &self.identity
+    }
+
+    pub(crate) fn get_usb_device(&self) -> (&T::Handle, &DeviceEntry<T::Device>) {
+        (&self.handle, &self.entry)
+    }
+
+    #[cfg(feature = "libusb")]
+    pub(crate) fn get_context(&self) -> &T {
+        &self.context
+    }
+
+    #[cfg(feature = "libusb")]
+    // returns the interface number and the in and out endpoint addresses
+    pub(crate) fn get_endpoints(&self) -> (u8, u8, u8) {
+        (self.iface, self.in_addr, self.out_addr)
+    }
+
+    pub(crate) fn next_cmd_token(&mut self) -> u32 {
+        let token = self.cmd_token;
+        self.cmd_token += 1;
+        token
+    }
+}
+
+// fills `buf` from a stream, returning less than its length only at the end
+// of the stream
+fn read_chunk<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(Error::StreamIoFailure(e)),
+        }
+    }
+
+    Ok(len)
 }
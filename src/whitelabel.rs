@@ -0,0 +1,405 @@
+use crate::{
+    backend::UsbBackend,
+    cmd::PicobootError,
+    otp::{OtpPlan, OtpWrite},
+    usb::PicobootConnection,
+};
+
+// see https://datasheets.raspberrypi.com/rp2350/rp2350-datasheet.pdf
+// section 5.7 for details on white labeling
+
+type Error = PicobootError;
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// First row of the USB boot flags, stored with 3-way redundancy
+pub const OTP_USB_BOOT_FLAGS_ROW: u16 = 0x059;
+const OTP_USB_BOOT_FLAGS_COPIES: u16 = 3;
+const USB_BOOT_FLAGS_WHITE_LABEL_ADDR_VALID: u32 = 1 << 22;
+/// Row holding the row number of the white label structure
+pub const OTP_USB_WHITE_LABEL_ADDR_ROW: u16 = 0x05C;
+
+// number of entries in the white label structure, each has a valid flag in
+// the matching bit of the USB boot flags
+const WHITE_LABEL_ENTRIES: usize = 16;
+
+const INDEX_VID: usize = 0;
+const INDEX_PID: usize = 1;
+const INDEX_BCD_DEVICE: usize = 2;
+const INDEX_LANG_ID: usize = 3;
+const INDEX_MANUFACTURER: usize = 4;
+const INDEX_PRODUCT: usize = 5;
+const INDEX_SERIAL_NUMBER: usize = 6;
+const INDEX_ATTRIBUTES_MAX_POWER: usize = 7;
+const INDEX_VOLUME_LABEL: usize = 8;
+const INDEX_SCSI_VENDOR: usize = 9;
+const INDEX_SCSI_PRODUCT: usize = 10;
+const INDEX_SCSI_VERSION: usize = 11;
+const INDEX_REDIRECT_URL: usize = 12;
+const INDEX_REDIRECT_NAME: usize = 13;
+const INDEX_UF2_MODEL: usize = 14;
+const INDEX_UF2_BOARD_ID: usize = 15;
+
+const STRDEF_LEN_MASK: u16 = 0x7F;
+const STRDEF_UTF16: u16 = 1 << 7;
+const STRDEF_OFFSET_LSB: u16 = 8;
+
+// (index, maximum number of characters, ASCII only) of each string entry,
+// the bootrom copies the volume label and SCSI strings as single bytes
+const STRINGS: [(usize, usize, bool); 11] = [
+    (INDEX_MANUFACTURER, 30, false),
+    (INDEX_PRODUCT, 30, false),
+    (INDEX_SERIAL_NUMBER, 30, false),
+    (INDEX_VOLUME_LABEL, 11, true),
+    (INDEX_SCSI_VENDOR, 8, true),
+    (INDEX_SCSI_PRODUCT, 16, true),
+    (INDEX_SCSI_VERSION, 4, true),
+    (INDEX_REDIRECT_URL, 127, false),
+    (INDEX_REDIRECT_NAME, 127, false),
+    (INDEX_UF2_MODEL, 127, false),
+    (INDEX_UF2_BOARD_ID, 127, false),
+];
+
+/// USB white label configuration of the RP2350 bootrom
+///
+/// Every field left as `None` keeps the bootrom default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WhiteLabel {
+    /// USB vendor ID.
+    pub vid: Option<u16>,
+    /// USB product ID.
+    pub pid: Option<u16>,
+    /// USB device release number.
+    pub bcd_device: Option<u16>,
+    /// USB string descriptor language ID.
+    pub lang_id: Option<u16>,
+    /// USB manufacturer string, up to 30 characters.
+    pub manufacturer: Option<String>,
+    /// USB product string, up to 30 characters.
+    pub product: Option<String>,
+    /// USB serial number string, up to 30 characters.
+    pub serial_number: Option<String>,
+    /// USB configuration `bmAttributes` (high byte) and `bMaxPower` (low byte).
+    pub attributes_max_power: Option<u16>,
+    /// Mass storage volume label, up to 11 ASCII characters.
+    pub volume_label: Option<String>,
+    /// SCSI inquiry vendor, up to 8 ASCII characters.
+    pub scsi_vendor: Option<String>,
+    /// SCSI inquiry product, up to 16 ASCII characters.
+    pub scsi_product: Option<String>,
+    /// SCSI inquiry version, up to 4 ASCII characters.
+    pub scsi_version: Option<String>,
+    /// URL that INDEX.HTM redirects to.
+    pub redirect_url: Option<String>,
+    /// Name of the page INDEX.HTM redirects to.
+    pub redirect_name: Option<String>,
+    /// Model shown in INFO_UF2.TXT.
+    pub uf2_model: Option<String>,
+    /// Board ID shown in INFO_UF2.TXT.
+    pub uf2_board_id: Option<String>,
+}
+impl WhiteLabel {
+    fn values(&self) -> [Option<u16>; WHITE_LABEL_ENTRIES] {
+        let mut values = [None; WHITE_LABEL_ENTRIES];
+        values[INDEX_VID] = self.vid;
+        values[INDEX_PID] = self.pid;
+        values[INDEX_BCD_DEVICE] = self.bcd_device;
+        values[INDEX_LANG_ID] = self.lang_id;
+        values[INDEX_ATTRIBUTES_MAX_POWER] = self.attributes_max_power;
+        values
+    }
+
+    fn strings(&self) -> [Option<&String>; WHITE_LABEL_ENTRIES] {
+        let mut strings = [None; WHITE_LABEL_ENTRIES];
+        strings[INDEX_MANUFACTURER] = self.manufacturer.as_ref();
+        strings[INDEX_PRODUCT] = self.product.as_ref();
+        strings[INDEX_SERIAL_NUMBER] = self.serial_number.as_ref();
+        strings[INDEX_VOLUME_LABEL] = self.volume_label.as_ref();
+        strings[INDEX_SCSI_VENDOR] = self.scsi_vendor.as_ref();
+        strings[INDEX_SCSI_PRODUCT] = self.scsi_product.as_ref();
+        strings[INDEX_SCSI_VERSION] = self.scsi_version.as_ref();
+        strings[INDEX_REDIRECT_URL] = self.redirect_url.as_ref();
+        strings[INDEX_REDIRECT_NAME] = self.redirect_name.as_ref();
+        strings[INDEX_UF2_MODEL] = self.uf2_model.as_ref();
+        strings[INDEX_UF2_BOARD_ID] = self.uf2_board_id.as_ref();
+        strings
+    }
+
+    fn set_value(&mut self, index: usize, value: u16) {
+        match index {
+            INDEX_VID => self.vid = Some(value),
+            INDEX_PID => self.pid = Some(value),
+            INDEX_BCD_DEVICE => self.bcd_device = Some(value),
+            INDEX_LANG_ID => self.lang_id = Some(value),
+            INDEX_ATTRIBUTES_MAX_POWER => self.attributes_max_power = Some(value),
+            _ => {}
+        }
+    }
+
+    fn set_string(&mut self, index: usize, value: String) {
+        let field = match index {
+            INDEX_MANUFACTURER => &mut self.manufacturer,
+            INDEX_PRODUCT => &mut self.product,
+            INDEX_SERIAL_NUMBER => &mut self.serial_number,
+            INDEX_VOLUME_LABEL => &mut self.volume_label,
+            INDEX_SCSI_VENDOR => &mut self.scsi_vendor,
+            INDEX_SCSI_PRODUCT => &mut self.scsi_product,
+            INDEX_SCSI_VERSION => &mut self.scsi_version,
+            INDEX_REDIRECT_URL => &mut self.redirect_url,
+            INDEX_REDIRECT_NAME => &mut self.redirect_name,
+            INDEX_UF2_MODEL => &mut self.uf2_model,
+            INDEX_UF2_BOARD_ID => &mut self.uf2_board_id,
+            _ => return,
+        };
+        *field = Some(value);
+    }
+
+    /// Encodes the white label structure as ECC row values.
+    ///
+    /// Returns the valid flags for the USB boot flags, and the rows of the
+    /// structure followed by the string data it points to.
+    ///
+    /// # Errors:
+    /// - [`Error::OtpInvalidConfig`]
+    pub fn to_rows(&self) -> Result<(u32, Vec<u16>)> {
+        let mut flags = 0;
+        let mut rows = vec![0u16; WHITE_LABEL_ENTRIES];
+
+        for (i, value) in self.values().iter().enumerate() {
+            if let Some(value) = value {
+                rows[i] = *value;
+                flags |= 1 << i;
+            }
+        }
+
+        for (i, string) in self.strings().iter().enumerate() {
+            let string = match string {
+                Some(s) => s,
+                None => continue,
+            };
+            let (max_len, ascii_only) = STRINGS
+                .iter()
+                .find(|s| s.0 == i)
+                .map_or((127, false), |s| (s.1, s.2));
+            if ascii_only && !string.is_ascii() {
+                return Err(Error::OtpInvalidConfig("white label string must be ASCII"));
+            }
+
+            let offset = rows.len();
+            let (len, utf16) = if string.is_ascii() {
+                let bytes = string.as_bytes();
+                for pair in bytes.chunks(2) {
+                    rows.push(pair[0] as u16 | (*pair.get(1).unwrap_or(&0) as u16) << 8);
+                }
+                (bytes.len(), false)
+            } else {
+                let start = rows.len();
+                rows.extend(string.encode_utf16());
+                (rows.len() - start, true)
+            };
+
+            if len > max_len {
+                return Err(Error::OtpInvalidConfig("white label string too long"));
+            }
+            if offset > 0xFF {
+                return Err(Error::OtpInvalidConfig("white label strings too long"));
+            }
+
+            rows[i] = len as u16
+                | if utf16 { STRDEF_UTF16 } else { 0 }
+                | (offset as u16) << STRDEF_OFFSET_LSB;
+            flags |= 1 << i;
+        }
+
+        Ok((flags, rows))
+    }
+}
+
+impl<T: UsbBackend> PicobootConnection<T> {
+    /// Reads and decodes the white label configuration from OTP.
+    ///
+    /// Returns `None` if no white label structure is configured.
+    ///
+    /// # Errors:
+    /// - Any produced by [`Self::otp_read_redundant`]
+    /// - Any produced by [`Self::otp_read`]
+    pub fn read_white_label(&mut self) -> Result<Option<WhiteLabel>> {
+        let flags = self.otp_read_redundant(OTP_USB_BOOT_FLAGS_ROW, OTP_USB_BOOT_FLAGS_COPIES)?;
+        if flags & USB_BOOT_FLAGS_WHITE_LABEL_ADDR_VALID == 0 {
+            return Ok(None);
+        }
+
+        let addr = self.otp_read_ecc(OTP_USB_WHITE_LABEL_ADDR_ROW, 1)?;
+        let addr = *addr.first().ok_or(Error::UsbReadBulkMismatch)?;
+        let rows = self.otp_read_ecc(addr, WHITE_LABEL_ENTRIES as u16)?;
+        if rows.len() != WHITE_LABEL_ENTRIES {
+            return Err(Error::UsbReadBulkMismatch);
+        }
+
+        let mut white_label = WhiteLabel::default();
+        for (i, row) in rows.iter().enumerate() {
+            if flags & 1 << i == 0 {
+                continue;
+            }
+            if !STRINGS.iter().any(|s| s.0 == i) {
+                white_label.set_value(i, *row);
+                continue;
+            }
+
+            let len = (row & STRDEF_LEN_MASK) as usize;
+            let utf16 = row & STRDEF_UTF16 != 0;
+            let offset = row >> STRDEF_OFFSET_LSB;
+            let count = if utf16 { len } else { (len + 1) / 2 };
+            let data = self.otp_read_ecc(addr + offset, count as u16)?;
+
+            let string = if utf16 {
+                String::from_utf16_lossy(&data)
+            } else {
+                let bytes: Vec<u8> = data
+                    .iter()
+                    .flat_map(|r| r.to_le_bytes())
+                    .take(len)
+                    .collect();
+                String::from_utf8_lossy(&bytes).into_owned()
+            };
+            white_label.set_string(i, string);
+        }
+
+        Ok(Some(white_label))
+    }
+
+    /// Plans writing a white label configuration to OTP without making any
+    /// changes, see [`Self::apply_otp_plan`].
+    ///
+    /// The structure and its strings are written from `row` onwards, then the
+    /// structure address and the valid flags are set.
+    ///
+    /// - `white_label` - Configuration to write.
+    /// - `row` - First row of a free ECC area to hold the structure.
+    ///
+    /// # Errors:
+    /// - [`Error::OtpInvalidConfig`]
+    /// - Any produced by [`Self::plan_otp`]
+    pub fn plan_white_label(&mut self, white_label: &WhiteLabel, row: u16) -> Result<OtpPlan> {
+        let (flags, rows) = white_label.to_rows()?;
+
+        let mut writes: Vec<OtpWrite> = rows
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i >= WHITE_LABEL_ENTRIES || flags & 1 << i != 0)
+            .map(|(i, value)| OtpWrite::Ecc {
+                row: row + i as u16,
+                value: *value,
+            })
+            .collect();
+        writes.push(OtpWrite::Ecc {
+            row: OTP_USB_WHITE_LABEL_ADDR_ROW,
+            value: row,
+        });
+        for i in 0..OTP_USB_BOOT_FLAGS_COPIES {
+            writes.push(OtpWrite::RawBits {
+                row: OTP_USB_BOOT_FLAGS_ROW + i,
+                bits: flags | USB_BOOT_FLAGS_WHITE_LABEL_ADDR_VALID,
+            });
+        }
+
+        self.plan_otp(&writes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_rows_values() {
+        let white_label = WhiteLabel {
+            vid: Some(0x1234),
+            pid: Some(0x5678),
+            attributes_max_power: Some(0x80FA),
+            ..Default::default()
+        };
+        let (flags, rows) = white_label.to_rows().unwrap();
+
+        assert_eq!(
+            flags,
+            1 << INDEX_VID | 1 << INDEX_PID | 1 << INDEX_ATTRIBUTES_MAX_POWER
+        );
+        assert_eq!(rows.len(), WHITE_LABEL_ENTRIES);
+        assert_eq!(rows[INDEX_VID], 0x1234);
+        assert_eq!(rows[INDEX_PID], 0x5678);
+        assert_eq!(rows[INDEX_ATTRIBUTES_MAX_POWER], 0x80FA);
+        assert_eq!(rows[INDEX_BCD_DEVICE], 0);
+    }
+
+    #[test]
+    fn to_rows_strings() {
+        let white_label = WhiteLabel {
+            manufacturer: Some("ACME".to_owned()),
+            volume_label: Some("BOOT".to_owned()),
+            product: Some("Pico\u{e9}".to_owned()),
+            ..Default::default()
+        };
+        let (flags, rows) = white_label.to_rows().unwrap();
+
+        assert_eq!(
+            flags,
+            1 << INDEX_MANUFACTURER | 1 << INDEX_PRODUCT | 1 << INDEX_VOLUME_LABEL
+        );
+
+        // ASCII strings are packed two characters per row, little endian
+        let offset = WHITE_LABEL_ENTRIES as u16;
+        assert_eq!(rows[INDEX_MANUFACTURER], 4 | offset << STRDEF_OFFSET_LSB);
+        assert_eq!(rows[16..18], [0x4341, 0x454D]);
+
+        // other strings are stored as UTF-16, one character per row
+        let offset = offset + 2;
+        assert_eq!(
+            rows[INDEX_PRODUCT],
+            5 | STRDEF_UTF16 | offset << STRDEF_OFFSET_LSB
+        );
+        assert_eq!(rows[18..23], [0x50, 0x69, 0x63, 0x6F, 0xE9]);
+
+        let offset = offset + 5;
+        assert_eq!(rows[INDEX_VOLUME_LABEL], 4 | offset << STRDEF_OFFSET_LSB);
+        assert_eq!(rows[23..], [0x4F42, 0x544F]);
+    }
+
+    #[test]
+    fn to_rows_odd_length() {
+        let white_label = WhiteLabel {
+            scsi_version: Some("1.0".to_owned()),
+            ..Default::default()
+        };
+        let (_, rows) = white_label.to_rows().unwrap();
+
+        assert_eq!(rows[INDEX_SCSI_VERSION] & STRDEF_LEN_MASK, 3);
+        assert_eq!(rows[16..], [0x2E31, 0x0030]);
+    }
+
+    #[test]
+    fn to_rows_invalid() {
+        let too_long = WhiteLabel {
+            volume_label: Some("VOLUMELABEL1".to_owned()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            too_long.to_rows(),
+            Err(Error::OtpInvalidConfig(_))
+        ));
+
+        let not_ascii = WhiteLabel {
+            scsi_vendor: Some("\u{e9}".to_owned()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            not_ascii.to_rows(),
+            Err(Error::OtpInvalidConfig(_))
+        ));
+
+        let utf16 = WhiteLabel {
+            manufacturer: Some("\u{e9}".to_owned()),
+            ..Default::default()
+        };
+        assert!(utf16.to_rows().is_ok());
+    }
+}